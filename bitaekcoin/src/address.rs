@@ -0,0 +1,464 @@
+use std::fmt;
+
+use k256::{
+    ecdsa::VerifyingKey,
+    elliptic_curve::{group::prime::PrimeCurveAffine, sec1::ToEncodedPoint, PrimeField},
+    ProjectivePoint, Scalar,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    encode::DecodeError,
+    hash::{sha256, tagged_hash},
+    script::{Script, StandardScript},
+};
+
+const BASE58_ALPHABET: &[u8] = b"123456789ABCDEFGHJKLMNPQRSTUVWXYZabcdefghijkmnopqrstuvwxyz";
+const BECH32_CHARSET: &[u8] = b"qpzry9x8gf2tvdw0s3jn54khce6mua7l";
+const BECH32_CONST: u32 = 1;
+const BECH32M_CONST: u32 = 0x2bc830a3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Network {
+    Mainnet,
+    Testnet,
+    Regtest,
+}
+
+impl Network {
+    fn p2pkh_prefix(self) -> u8 {
+        match self {
+            Network::Mainnet => 0x00,
+            Network::Testnet | Network::Regtest => 0x6f,
+        }
+    }
+
+    fn p2sh_prefix(self) -> u8 {
+        match self {
+            Network::Mainnet => 0x05,
+            Network::Testnet | Network::Regtest => 0xc4,
+        }
+    }
+
+    fn hrp(self) -> &'static str {
+        match self {
+            Network::Mainnet => "bc",
+            Network::Testnet => "tb",
+            Network::Regtest => "bcrt",
+        }
+    }
+
+    // Coin type level of BIP44 derivation paths, shared by every test network
+    pub fn coin_type(self) -> u32 {
+        match self {
+            Network::Mainnet => 0,
+            Network::Testnet | Network::Regtest => 1,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Payload {
+    PubkeyHash([u8; 20]),
+    ScriptHash([u8; 20]),
+    // Witness version and program
+    WitnessProgram(u8, Vec<u8>),
+}
+
+// Base58Check for P2PKH and P2SH, Bech32 for segwit v0 and Bech32m for later versions
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Address {
+    pub network: Network,
+    pub payload: Payload,
+}
+
+impl Address {
+    // Outputs without an address, like P2PK and P2MS, give None
+    pub fn from_standard(script: &StandardScript, network: Network) -> Option<Self> {
+        let payload = match script {
+            StandardScript::P2PKH(pkh) => Payload::PubkeyHash(pkh.clone().try_into().ok()?),
+            StandardScript::P2SH(sh) => Payload::ScriptHash(sh.clone().try_into().ok()?),
+            StandardScript::P2WPKH(program) | StandardScript::P2WSH(program) => {
+                Payload::WitnessProgram(0, program.clone())
+            }
+            StandardScript::WitnessProgram(version, program) => {
+                Payload::WitnessProgram(*version, program.clone())
+            }
+            _ => return None,
+        };
+        Some(Self { network, payload })
+    }
+
+    pub fn from_script(script: &Script, network: Network) -> Option<Self> {
+        Self::from_standard(&script.to_standard()?, network)
+    }
+
+    pub fn to_standard(&self) -> StandardScript {
+        match &self.payload {
+            Payload::PubkeyHash(pkh) => StandardScript::P2PKH(pkh.to_vec()),
+            Payload::ScriptHash(sh) => StandardScript::P2SH(sh.to_vec()),
+            Payload::WitnessProgram(0, program) if program.len() == 20 => {
+                StandardScript::P2WPKH(program.clone())
+            }
+            Payload::WitnessProgram(0, program) => StandardScript::P2WSH(program.clone()),
+            Payload::WitnessProgram(version, program) => {
+                StandardScript::WitnessProgram(*version, program.clone())
+            }
+        }
+    }
+
+    pub fn script_pub_key(&self) -> Script {
+        self.to_standard().into_script()
+    }
+
+    // Fails on malformed addresses, wrong checksums and addresses of other networks
+    pub fn parse(address: &str, network: Network) -> Result<Self, DecodeError> {
+        let hrp = network.hrp();
+        let is_bech32 = address
+            .get(..hrp.len() + 1)
+            .is_some_and(|prefix| prefix.eq_ignore_ascii_case(&format!("{}1", hrp)));
+        let payload = if is_bech32 {
+            let (version, program) = decode_segwit(address, hrp)?;
+            Payload::WitnessProgram(version, program)
+        } else {
+            let bytes = base58check_decode(address)?;
+            let hash = bytes[1..].try_into().map_err(|_| DecodeError)?;
+            match bytes[0] {
+                prefix if prefix == network.p2pkh_prefix() => Payload::PubkeyHash(hash),
+                prefix if prefix == network.p2sh_prefix() => Payload::ScriptHash(hash),
+                _ => return Err(DecodeError),
+            }
+        };
+        Ok(Self { network, payload })
+    }
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let address = match &self.payload {
+            Payload::PubkeyHash(pkh) => {
+                base58check_encode(&[&[self.network.p2pkh_prefix()], &pkh[..]].concat())
+            }
+            Payload::ScriptHash(sh) => {
+                base58check_encode(&[&[self.network.p2sh_prefix()], &sh[..]].concat())
+            }
+            Payload::WitnessProgram(version, program) => {
+                encode_segwit(self.network.hrp(), *version, program)
+            }
+        };
+        f.write_str(&address)
+    }
+}
+
+fn checksum(bytes: &[u8]) -> [u8; 4] {
+    sha256(sha256(bytes.to_vec()).to_vec())[..4]
+        .try_into()
+        .unwrap()
+}
+
+// Output key of a taproot output spendable only with the key path (BIP86): the internal key
+// with an even y coordinate, tweaked by its own tagged hash
+pub fn taproot_output_key(internal_key: &VerifyingKey) -> Option<[u8; 32]> {
+    let point = internal_key.to_encoded_point(true);
+    let x: [u8; 32] = point.x()?.as_slice().try_into().ok()?;
+    let mut internal_key = ProjectivePoint::from(*internal_key.as_affine());
+    if point.as_bytes()[0] == 0x03 {
+        internal_key = -internal_key;
+    }
+    let tweak = tagged_hash("TapTweak", x.to_vec());
+    let tweak = Option::<Scalar>::from(Scalar::from_repr(tweak.into()))?;
+    let output_key = (internal_key + ProjectivePoint::GENERATOR * tweak).to_affine();
+    if bool::from(output_key.is_identity()) {
+        return None;
+    }
+    output_key
+        .to_encoded_point(true)
+        .x()?
+        .as_slice()
+        .try_into()
+        .ok()
+}
+
+pub fn base58check_encode(payload: &[u8]) -> String {
+    let mut bytes = payload.to_vec();
+    bytes.extend(checksum(payload));
+
+    // Base 58 digits in little endian
+    let mut digits: Vec<u8> = Vec::new();
+    for byte in &bytes {
+        let mut carry = *byte as u32;
+        for digit in &mut digits {
+            carry += (*digit as u32) << 8;
+            *digit = (carry % 58) as u8;
+            carry /= 58;
+        }
+        while carry > 0 {
+            digits.push((carry % 58) as u8);
+            carry /= 58;
+        }
+    }
+    // Leading zero bytes are written as ones
+    let zeros = bytes.iter().take_while(|byte| **byte == 0).count();
+    let mut encoded = "1".repeat(zeros);
+    for digit in digits.iter().rev() {
+        encoded.push(BASE58_ALPHABET[*digit as usize] as char);
+    }
+    encoded
+}
+
+pub fn base58check_decode(encoded: &str) -> Result<Vec<u8>, DecodeError> {
+    // Bytes in little endian
+    let mut bytes: Vec<u8> = Vec::new();
+    for c in encoded.bytes() {
+        let mut carry = BASE58_ALPHABET
+            .iter()
+            .position(|x| *x == c)
+            .ok_or(DecodeError)? as u32;
+        for byte in &mut bytes {
+            carry += *byte as u32 * 58;
+            *byte = carry as u8;
+            carry >>= 8;
+        }
+        while carry > 0 {
+            bytes.push(carry as u8);
+            carry >>= 8;
+        }
+    }
+    let zeros = encoded.bytes().take_while(|c| *c == b'1').count();
+    bytes.extend(vec![0; zeros]);
+    bytes.reverse();
+
+    if bytes.len() < 5 {
+        return Err(DecodeError);
+    }
+    let (payload, check) = bytes.split_at(bytes.len() - 4);
+    if checksum(payload) != check {
+        return Err(DecodeError);
+    }
+    Ok(payload.to_vec())
+}
+
+fn polymod(values: &[u8]) -> u32 {
+    const GENERATOR: [u32; 5] = [0x3b6a57b2, 0x26508e6d, 0x1ea119fa, 0x3d4233dd, 0x2a1462b3];
+    let mut checksum = 1;
+    for value in values {
+        let top = checksum >> 25;
+        checksum = (checksum & 0x1ffffff) << 5 ^ *value as u32;
+        for (i, generator) in GENERATOR.iter().enumerate() {
+            if (top >> i) & 1 == 1 {
+                checksum ^= generator;
+            }
+        }
+    }
+    checksum
+}
+
+fn expand_hrp(hrp: &str) -> Vec<u8> {
+    let mut values: Vec<u8> = hrp.bytes().map(|c| c >> 5).collect();
+    values.push(0);
+    values.extend(hrp.bytes().map(|c| c & 31));
+    values
+}
+
+// Regroups bits, padding the last group with zeros when `pad` is set
+fn convert_bits(data: &[u8], from: u32, to: u32, pad: bool) -> Result<Vec<u8>, DecodeError> {
+    let mut acc = 0u32;
+    let mut bits = 0;
+    let mut converted = Vec::new();
+    let max = (1 << to) - 1;
+    for value in data {
+        if (*value as u32) >> from != 0 {
+            return Err(DecodeError);
+        }
+        acc = acc << from | *value as u32;
+        bits += from;
+        while bits >= to {
+            bits -= to;
+            converted.push((acc >> bits & max) as u8);
+        }
+    }
+    if pad {
+        if bits > 0 {
+            converted.push((acc << (to - bits) & max) as u8);
+        }
+    } else if bits >= from || (acc << (to - bits)) & max != 0 {
+        return Err(DecodeError);
+    }
+    Ok(converted)
+}
+
+fn encode_segwit(hrp: &str, version: u8, program: &[u8]) -> String {
+    let mut data = vec![version];
+    data.extend(convert_bits(program, 8, 5, true).unwrap());
+    let constant = if version == 0 {
+        BECH32_CONST
+    } else {
+        BECH32M_CONST
+    };
+    let mut values = expand_hrp(hrp);
+    values.extend(&data);
+    values.extend([0; 6]);
+    let checksum = polymod(&values) ^ constant;
+
+    let mut encoded = format!("{}1", hrp);
+    for value in data {
+        encoded.push(BECH32_CHARSET[value as usize] as char);
+    }
+    for i in 0..6 {
+        encoded.push(BECH32_CHARSET[(checksum >> (5 * (5 - i)) & 31) as usize] as char);
+    }
+    encoded
+}
+
+fn decode_segwit(address: &str, hrp: &str) -> Result<(u8, Vec<u8>), DecodeError> {
+    // Either all lowercase or all uppercase
+    let lowercase = address.to_ascii_lowercase();
+    if address.len() > 90 || (address != lowercase && address != address.to_ascii_uppercase()) {
+        return Err(DecodeError);
+    }
+    let (address_hrp, data) = lowercase.rsplit_once('1').ok_or(DecodeError)?;
+    if address_hrp != hrp || data.len() < 7 {
+        return Err(DecodeError);
+    }
+    let values = data
+        .bytes()
+        .map(|c| BECH32_CHARSET.iter().position(|x| *x == c).map(|i| i as u8))
+        .collect::<Option<Vec<_>>>()
+        .ok_or(DecodeError)?;
+
+    let version = values[0];
+    let constant = if version == 0 {
+        BECH32_CONST
+    } else {
+        BECH32M_CONST
+    };
+    let mut checked = expand_hrp(hrp);
+    checked.extend(&values);
+    if polymod(&checked) != constant {
+        return Err(DecodeError);
+    }
+    let program = convert_bits(&values[1..values.len() - 6], 5, 8, false)?;
+    if version > 16
+        || !(2..=40).contains(&program.len())
+        || (version == 0 && program.len() != 20 && program.len() != 32)
+    {
+        return Err(DecodeError);
+    }
+    Ok((version, program))
+}
+
+#[cfg(test)]
+mod tests {
+    use hex_literal::hex;
+
+    use crate::encode::Encodable;
+
+    use super::*;
+
+    #[test]
+    fn test_base58check() {
+        let address = "1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNa";
+        let pkh = hex!("62e907b15cbf27d5425399ebf6f0fb50ebb88f18");
+        let decoded = Address::parse(address, Network::Mainnet).unwrap();
+        assert_eq!(decoded.payload, Payload::PubkeyHash(pkh));
+        assert_eq!(decoded.to_string(), address);
+
+        // Leading zero bytes survive the round trip
+        let payload = [0, 0, 1, 2, 3];
+        assert_eq!(
+            base58check_decode(&base58check_encode(&payload)).unwrap(),
+            payload
+        );
+
+        let p2sh = Address::from_standard(&StandardScript::P2SH(pkh.to_vec()), Network::Testnet)
+            .unwrap()
+            .to_string();
+        assert!(p2sh.starts_with('2'));
+        let decoded = Address::parse(&p2sh, Network::Regtest).unwrap();
+        assert_eq!(decoded.payload, Payload::ScriptHash(pkh));
+
+        assert!(Address::parse("1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNb", Network::Mainnet).is_err());
+        assert!(Address::parse(address, Network::Testnet).is_err());
+        assert!(Address::parse("1A1zP1eP5QGefi2DMPTfTL5SLmv7Divf0a", Network::Mainnet).is_err());
+    }
+
+    #[test]
+    fn test_bech32() {
+        let vectors = [
+            (
+                "BC1QW508D6QEJXTDG4Y5R3ZARVARY0C5XW7KV8F3T4",
+                Network::Mainnet,
+                hex!("0014751e76e8199196d454941c45d1b3a323f1433bd6").to_vec(),
+            ),
+            (
+                "tb1qrp33g0q5c5txsp9arysrx4k6zdkfs4nce4xj0gdcccefvpysxf3q0sl5k7",
+                Network::Testnet,
+                hex!("00201863143c14c5166804bd19203356da136c985678cd4d27a1b8c6329604903262")
+                    .to_vec(),
+            ),
+            (
+                "bc1p0xlxvlhemja6c4dqv22uapctqupfhlxm9h8z3k2e72q4k9hcz7vqzk5jj0",
+                Network::Mainnet,
+                hex!("512079be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798")
+                    .to_vec(),
+            ),
+            (
+                "BC1SW50QGDZ25J",
+                Network::Mainnet,
+                hex!("6002751e").to_vec(),
+            ),
+        ];
+        for (address, network, script) in vectors {
+            let decoded = Address::parse(address, network).unwrap();
+            assert_eq!(decoded.script_pub_key().encode(), script);
+            assert_eq!(decoded.to_string(), address.to_ascii_lowercase());
+            let script = decoded.script_pub_key();
+            assert_eq!(Address::from_script(&script, network).unwrap(), decoded);
+        }
+
+        let invalid = [
+            // Bech32 checksum for witness version 1
+            "bc1p0xlxvlhemja6c4dqv22uapctqupfhlxm9h8z3k2e72q4k9hcz7vqh2y7hd",
+            // Bech32m checksum for witness version 0
+            "bc1qw508d6qejxtdg4y5r3zarvary0c5xw7kemeawh",
+            // Invalid program length for witness version 0
+            "BC1QR508D6QEJXTDG4Y5R3ZARVARYV98GJ9P",
+            // Mixed case
+            "bc1qW508D6QEJXTDG4Y5R3ZARVARY0C5XW7KV8F3T4",
+            // Wrong character
+            "bc1qw508d6qejxtdg4y5r3zarvary0c5xw7kv8f3t5",
+        ];
+        for address in invalid {
+            assert!(Address::parse(address, Network::Mainnet).is_err());
+        }
+        let script =
+            StandardScript::P2WPKH(hex!("751e76e8199196d454941c45d1b3a323f1433bd6").to_vec());
+        let address = Address::from_standard(&script, Network::Regtest)
+            .unwrap()
+            .to_string();
+        assert!(address.starts_with("bcrt1q"));
+        assert!(Address::parse(&address, Network::Regtest).is_ok());
+        assert!(Address::parse(&address, Network::Mainnet).is_err());
+    }
+
+    #[test]
+    fn test_taproot_output_key() {
+        // Test vector of BIP86 at m/86'/0'/0'/0/0
+        let internal_key = VerifyingKey::from_sec1_bytes(&hex!(
+            "03cc8a4bc64d897bddc5fbc2f670f7a8ba0b386779106cf1223c6fc5d7cd6fc115"
+        ))
+        .unwrap();
+        let output_key = taproot_output_key(&internal_key).unwrap();
+        assert_eq!(
+            output_key,
+            hex!("a60869f0dbcf1dc659c9cecbaf8050135ea9e8cdc487053f1dc6880949dc684c")
+        );
+        let script = StandardScript::WitnessProgram(1, output_key.to_vec());
+        assert_eq!(
+            Address::from_standard(&script, Network::Mainnet)
+                .unwrap()
+                .to_string(),
+            "bc1p5cyxnuxmeuwuvkwfem96lqzszd02n6xdcjrs20cac6yqjjwudpxqkedrcr"
+        );
+    }
+}
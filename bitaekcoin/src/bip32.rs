@@ -0,0 +1,360 @@
+use std::{fmt, str::FromStr};
+
+use hmac::{Hmac, Mac};
+use k256::{
+    ecdsa::{SigningKey, VerifyingKey},
+    elliptic_curve::{group::prime::PrimeCurveAffine, PrimeField},
+    ProjectivePoint, Scalar,
+};
+use sha2::Sha512;
+
+use crate::{
+    address::{base58check_decode, base58check_encode, Network},
+    encode::DecodeError,
+    hash::hash160,
+};
+
+// Child numbers from this one on are hardened
+pub const HARDENED: u32 = 1 << 31;
+
+const XPRV: u32 = 0x0488ade4;
+const XPUB: u32 = 0x0488b21e;
+const TPRV: u32 = 0x04358394;
+const TPUB: u32 = 0x043587cf;
+
+// Child numbers from the master key, written like m/0'/1/2h
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DerivationPath(pub Vec<u32>);
+
+impl FromStr for DerivationPath {
+    type Err = DecodeError;
+
+    fn from_str(path: &str) -> Result<Self, Self::Err> {
+        let mut parts = path.split('/');
+        if parts.next() != Some("m") {
+            return Err(DecodeError);
+        }
+        let mut children = Vec::new();
+        for part in parts {
+            let (number, hardened) = match part.strip_suffix(['\'', 'h']) {
+                Some(number) => (number, HARDENED),
+                None => (part, 0),
+            };
+            let number: u32 = number.parse().map_err(|_| DecodeError)?;
+            if number >= HARDENED {
+                return Err(DecodeError);
+            }
+            children.push(number | hardened);
+        }
+        Ok(Self(children))
+    }
+}
+
+impl fmt::Display for DerivationPath {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "m")?;
+        for child in &self.0 {
+            match child >= &HARDENED {
+                true => write!(f, "/{}'", child - HARDENED)?,
+                false => write!(f, "/{}", child)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ExtendedPrivateKey {
+    pub network: Network,
+    pub depth: u8,
+    pub parent_fingerprint: [u8; 4],
+    pub child_number: u32,
+    pub chain_code: [u8; 32],
+    pub private_key: SigningKey,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExtendedPublicKey {
+    pub network: Network,
+    pub depth: u8,
+    pub parent_fingerprint: [u8; 4],
+    pub child_number: u32,
+    pub chain_code: [u8; 32],
+    pub public_key: VerifyingKey,
+}
+
+impl ExtendedPrivateKey {
+    // None for the rare seeds that don't give a valid key
+    pub fn new_master(seed: &[u8], network: Network) -> Option<Self> {
+        let (key, chain_code) = hmac_sha512(b"Bitcoin seed", seed);
+        Some(Self {
+            network,
+            depth: 0,
+            parent_fingerprint: [0; 4],
+            child_number: 0,
+            chain_code,
+            private_key: SigningKey::from_bytes(&key.into()).ok()?,
+        })
+    }
+
+    // None for the rare indexes that don't give a valid key, the next index should be used
+    pub fn derive_child(&self, index: u32) -> Option<Self> {
+        let mut data = if index >= HARDENED {
+            let mut data = vec![0];
+            data.extend(self.private_key.to_bytes());
+            data
+        } else {
+            self.public_key().public_key.to_sec1_bytes().to_vec()
+        };
+        data.extend(index.to_be_bytes());
+        let (tweak, chain_code) = hmac_sha512(&self.chain_code, &data);
+        let tweak = Option::<Scalar>::from(Scalar::from_repr(tweak.into()))?;
+        let key = tweak + self.private_key.as_nonzero_scalar().as_ref();
+        Some(Self {
+            network: self.network,
+            depth: self.depth.checked_add(1)?,
+            parent_fingerprint: self.fingerprint(),
+            child_number: index,
+            chain_code,
+            private_key: SigningKey::from_bytes(&key.to_bytes()).ok()?,
+        })
+    }
+
+    pub fn derive_path(&self, path: &DerivationPath) -> Option<Self> {
+        path.0
+            .iter()
+            .try_fold(self.clone(), |key, index| key.derive_child(*index))
+    }
+
+    pub fn public_key(&self) -> ExtendedPublicKey {
+        ExtendedPublicKey {
+            network: self.network,
+            depth: self.depth,
+            parent_fingerprint: self.parent_fingerprint,
+            child_number: self.child_number,
+            chain_code: self.chain_code,
+            public_key: *self.private_key.verifying_key(),
+        }
+    }
+
+    pub fn fingerprint(&self) -> [u8; 4] {
+        self.public_key().fingerprint()
+    }
+}
+
+impl ExtendedPublicKey {
+    // Hardened children can't be derived from a public key
+    pub fn derive_child(&self, index: u32) -> Option<Self> {
+        if index >= HARDENED {
+            return None;
+        }
+        let mut data = self.public_key.to_sec1_bytes().to_vec();
+        data.extend(index.to_be_bytes());
+        let (tweak, chain_code) = hmac_sha512(&self.chain_code, &data);
+        let tweak = Option::<Scalar>::from(Scalar::from_repr(tweak.into()))?;
+        let point = ProjectivePoint::GENERATOR * tweak + self.public_key.as_affine();
+        let point = point.to_affine();
+        if bool::from(point.is_identity()) {
+            return None;
+        }
+        Some(Self {
+            network: self.network,
+            depth: self.depth.checked_add(1)?,
+            parent_fingerprint: self.fingerprint(),
+            child_number: index,
+            chain_code,
+            public_key: VerifyingKey::from_affine(point).ok()?,
+        })
+    }
+
+    pub fn derive_path(&self, path: &DerivationPath) -> Option<Self> {
+        path.0
+            .iter()
+            .try_fold(self.clone(), |key, index| key.derive_child(*index))
+    }
+
+    // First 4 bytes of the key identifier, the hash160 of the public key
+    pub fn fingerprint(&self) -> [u8; 4] {
+        hash160(self.public_key.to_sec1_bytes().to_vec())[..4]
+            .try_into()
+            .unwrap()
+    }
+}
+
+impl fmt::Display for ExtendedPrivateKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let version = match self.network {
+            Network::Mainnet => XPRV,
+            Network::Testnet | Network::Regtest => TPRV,
+        };
+        let mut key = vec![0];
+        key.extend(self.private_key.to_bytes());
+        let bytes = serialize(
+            version,
+            self.depth,
+            self.parent_fingerprint,
+            self.child_number,
+            self.chain_code,
+            &key,
+        );
+        f.write_str(&base58check_encode(&bytes))
+    }
+}
+
+impl fmt::Display for ExtendedPublicKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let version = match self.network {
+            Network::Mainnet => XPUB,
+            Network::Testnet | Network::Regtest => TPUB,
+        };
+        let bytes = serialize(
+            version,
+            self.depth,
+            self.parent_fingerprint,
+            self.child_number,
+            self.chain_code,
+            &self.public_key.to_sec1_bytes(),
+        );
+        f.write_str(&base58check_encode(&bytes))
+    }
+}
+
+// Testnet versions are parsed as testnet keys, they are shared with regtest
+impl FromStr for ExtendedPrivateKey {
+    type Err = DecodeError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (version, depth, parent_fingerprint, child_number, chain_code, key) = deserialize(s)?;
+        let network = match version {
+            XPRV => Network::Mainnet,
+            TPRV => Network::Testnet,
+            _ => return Err(DecodeError),
+        };
+        if key[0] != 0 {
+            return Err(DecodeError);
+        }
+        Ok(Self {
+            network,
+            depth,
+            parent_fingerprint,
+            child_number,
+            chain_code,
+            private_key: SigningKey::from_slice(&key[1..]).map_err(|_| DecodeError)?,
+        })
+    }
+}
+
+impl FromStr for ExtendedPublicKey {
+    type Err = DecodeError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (version, depth, parent_fingerprint, child_number, chain_code, key) = deserialize(s)?;
+        let network = match version {
+            XPUB => Network::Mainnet,
+            TPUB => Network::Testnet,
+            _ => return Err(DecodeError),
+        };
+        Ok(Self {
+            network,
+            depth,
+            parent_fingerprint,
+            child_number,
+            chain_code,
+            public_key: VerifyingKey::from_sec1_bytes(&key).map_err(|_| DecodeError)?,
+        })
+    }
+}
+
+fn hmac_sha512(key: &[u8], data: &[u8]) -> ([u8; 32], [u8; 32]) {
+    let mut mac = Hmac::<Sha512>::new_from_slice(key).unwrap();
+    mac.update(data);
+    let output = mac.finalize().into_bytes();
+    (
+        output[..32].try_into().unwrap(),
+        output[32..].try_into().unwrap(),
+    )
+}
+
+fn serialize(
+    version: u32,
+    depth: u8,
+    parent_fingerprint: [u8; 4],
+    child_number: u32,
+    chain_code: [u8; 32],
+    key: &[u8],
+) -> Vec<u8> {
+    let mut bytes = version.to_be_bytes().to_vec();
+    bytes.push(depth);
+    bytes.extend(parent_fingerprint);
+    bytes.extend(child_number.to_be_bytes());
+    bytes.extend(chain_code);
+    bytes.extend(key);
+    bytes
+}
+
+type Serialized = (u32, u8, [u8; 4], u32, [u8; 32], [u8; 33]);
+
+fn deserialize(s: &str) -> Result<Serialized, DecodeError> {
+    let bytes = base58check_decode(s)?;
+    if bytes.len() != 78 {
+        return Err(DecodeError);
+    }
+    Ok((
+        u32::from_be_bytes(bytes[0..4].try_into().unwrap()),
+        bytes[4],
+        bytes[5..9].try_into().unwrap(),
+        u32::from_be_bytes(bytes[9..13].try_into().unwrap()),
+        bytes[13..45].try_into().unwrap(),
+        bytes[45..78].try_into().unwrap(),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use hex_literal::hex;
+
+    use super::*;
+
+    #[test]
+    fn test_derivation() {
+        // Test vector 1 of BIP32
+        let seed = hex!("000102030405060708090a0b0c0d0e0f");
+        let master = ExtendedPrivateKey::new_master(&seed, Network::Mainnet).unwrap();
+        assert_eq!(master.to_string(), "xprv9s21ZrQH143K3QTDL4LXw2F7HEK3wJUD2nW2nRk4stbPy6cq3jPPqjiChkVvvNKmPGJxWUtg6LnF5kejMRNNU3TGtRBeJgk33yuGBxrMPHi");
+        assert_eq!(master.public_key().to_string(), "xpub661MyMwAqRbcFtXgS5sYJABqqG9YLmC4Q1Rdap9gSE8NqtwybGhePY2gZ29ESFjqJoCu1Rupje8YtGqsefD265TMg7usUDFdp6W1EGMcet8");
+
+        let path: DerivationPath = "m/0'/1".parse().unwrap();
+        assert_eq!(path.to_string(), "m/0'/1");
+        let child = master.derive_path(&path).unwrap();
+        assert_eq!(child.to_string(), "xprv9wTYmMFdV23N2TdNG573QoEsfRrWKQgWeibmLntzniatZvR9BmLnvSxqu53Kw1UmYPxLgboyZQaXwTCg8MSY3H2EU4pWcQDnRnrVA1xe8fs");
+        assert_eq!(child.public_key().to_string(), "xpub6ASuArnXKPbfEwhqN6e3mwBcDTgzisQN1wXN9BJcM47sSikHjJf3UFHKkNAWbWMiGj7Wf5uMash7SyYq527Hqck2AxYysAA7xmALppuCkwQ");
+        assert_eq!(child.depth, 2);
+
+        // Non-hardened children can be derived from the public key alone
+        let hardened = master.derive_child(HARDENED).unwrap().public_key();
+        assert_eq!(hardened.derive_child(1).unwrap(), child.public_key());
+        assert!(hardened.derive_child(HARDENED).is_none());
+        assert_eq!(child.parent_fingerprint, hardened.fingerprint());
+    }
+
+    #[test]
+    fn test_serialization() {
+        let master = ExtendedPrivateKey::new_master(&[1; 32], Network::Regtest).unwrap();
+        let xprv = master.to_string();
+        assert!(xprv.starts_with("tprv"));
+        let parsed: ExtendedPrivateKey = xprv.parse().unwrap();
+        assert_eq!(parsed.private_key, master.private_key);
+        assert_eq!(parsed.chain_code, master.chain_code);
+
+        let xpub = master.public_key().to_string();
+        assert!(xpub.starts_with("tpub"));
+        let parsed: ExtendedPublicKey = xpub.parse().unwrap();
+        assert_eq!(parsed.public_key, master.public_key().public_key);
+        assert!(xpub.parse::<ExtendedPrivateKey>().is_err());
+
+        assert!("m/0'/x".parse::<DerivationPath>().is_err());
+        assert!("0/1".parse::<DerivationPath>().is_err());
+        let path: DerivationPath = "m/0h/2".parse().unwrap();
+        assert_eq!(path, DerivationPath(vec![HARDENED, 2]));
+    }
+}
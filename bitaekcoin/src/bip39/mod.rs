@@ -0,0 +1,150 @@
+use std::{fmt, str::FromStr};
+
+use sha2::Sha512;
+use unicode_normalization::UnicodeNormalization;
+
+use crate::{encode::DecodeError, hash::sha256};
+
+const ENGLISH: &str = include_str!("english.txt");
+const ROUNDS: u32 = 2048;
+
+fn wordlist() -> Vec<&'static str> {
+    ENGLISH.lines().collect()
+}
+
+// Words encoding some entropy and its checksum, a wallet seed is derived from them
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Mnemonic {
+    pub words: Vec<&'static str>,
+}
+
+impl Mnemonic {
+    // 16 to 32 bytes in steps of 4 give 12 to 24 words
+    pub fn from_entropy(entropy: &[u8]) -> Result<Self, DecodeError> {
+        if !(16..=32).contains(&entropy.len()) || !entropy.len().is_multiple_of(4) {
+            return Err(DecodeError);
+        }
+        // One checksum bit per 4 bytes of entropy
+        let mut bytes = entropy.to_vec();
+        bytes.push(sha256(entropy.to_vec())[0]);
+        let bits = entropy.len() * 8 + entropy.len() / 4;
+
+        let wordlist = wordlist();
+        let words = (0..bits / 11)
+            .map(|i| {
+                let index = (0..11).fold(0, |index, j| {
+                    let bit = i * 11 + j;
+                    index << 1 | (bytes[bit / 8] >> (7 - bit % 8) & 1) as usize
+                });
+                wordlist[index]
+            })
+            .collect();
+        Ok(Self { words })
+    }
+
+    pub fn entropy(&self) -> Vec<u8> {
+        self.checked_entropy().unwrap()
+    }
+
+    // PBKDF2 of the words with the passphrase as salt
+    pub fn to_seed(&self, passphrase: &str) -> [u8; 64] {
+        let mnemonic: String = self.to_string().nfkd().collect();
+        let salt: String = format!("mnemonic{}", passphrase).nfkd().collect();
+        let mut seed = [0; 64];
+        pbkdf2::pbkdf2_hmac::<Sha512>(mnemonic.as_bytes(), salt.as_bytes(), ROUNDS, &mut seed);
+        seed
+    }
+
+    fn checked_entropy(&self) -> Result<Vec<u8>, DecodeError> {
+        let wordlist = wordlist();
+        let mut bits = Vec::new();
+        for word in &self.words {
+            let index = wordlist.binary_search(word).map_err(|_| DecodeError)?;
+            bits.extend((0..11).rev().map(|i| (index >> i) & 1 == 1));
+        }
+        if ![12, 15, 18, 21, 24].contains(&self.words.len()) {
+            return Err(DecodeError);
+        }
+        let entropy: Vec<u8> = bits[..bits.len() / 33 * 32]
+            .chunks(8)
+            .map(|byte| byte.iter().fold(0, |acc, bit| acc << 1 | *bit as u8))
+            .collect();
+        if Self::from_entropy(&entropy)?.words != self.words {
+            return Err(DecodeError);
+        }
+        Ok(entropy)
+    }
+}
+
+// Fails on unknown words, word counts other than 12 to 24 and wrong checksums
+impl FromStr for Mnemonic {
+    type Err = DecodeError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let wordlist = wordlist();
+        let words = s
+            .split_whitespace()
+            .map(|word| {
+                let word = word.to_lowercase();
+                wordlist.iter().find(|w| ***w == word).copied()
+            })
+            .collect::<Option<Vec<_>>>()
+            .ok_or(DecodeError)?;
+        let mnemonic = Self { words };
+        mnemonic.checked_entropy()?;
+        Ok(mnemonic)
+    }
+}
+
+impl fmt::Display for Mnemonic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.words.join(" "))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use hex_literal::hex;
+
+    use super::*;
+
+    #[test]
+    fn test_mnemonic() {
+        // Test vectors of the reference implementation, all with the passphrase "TREZOR"
+        let vectors = [
+            (
+                hex!("00000000000000000000000000000000").to_vec(),
+                "abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon about",
+                hex!("c55257c360c07c72029aebc1b53c05ed0362ada38ead3e3e9efa3708e53495531f09a6987599d18264c1e1c92f2cf141630c7a3c4ab7c81b2f001698e7463b04"),
+            ),
+            (
+                hex!("7f7f7f7f7f7f7f7f7f7f7f7f7f7f7f7f").to_vec(),
+                "legal winner thank year wave sausage worth useful legal winner thank yellow",
+                hex!("2e8905819b8723fe2c1d161860e5ee1830318dbf49a83bd451cfb8440c28bd6fa457fe1296106559a3c80937a1c1069be3a3a5bd381ee6260e8d9739fce1f607"),
+            ),
+            (
+                hex!("ffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff").to_vec(),
+                "zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo vote",
+                hex!("dd48c104698c30cfe2b6142103248622fb7bb0ff692eebb00089b32d22484e1613912f0a5b694407be899ffd31ed3992c456cdf60f5d4564b8ba3f05a69890ad"),
+            ),
+        ];
+        for (entropy, words, seed) in vectors {
+            let mnemonic = Mnemonic::from_entropy(&entropy).unwrap();
+            assert_eq!(mnemonic.to_string(), words);
+            assert_eq!(mnemonic.to_seed("TREZOR"), seed);
+            let parsed: Mnemonic = words.parse().unwrap();
+            assert_eq!(parsed.entropy(), entropy);
+        }
+
+        // Wrong checksum, unknown word and wrong word count
+        let invalid = [
+            "abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon",
+            "abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon bitaekcoin",
+            "abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon about",
+        ];
+        for words in invalid {
+            assert!(words.parse::<Mnemonic>().is_err());
+        }
+        assert!(Mnemonic::from_entropy(&[0; 15]).is_err());
+    }
+}
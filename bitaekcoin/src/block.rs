@@ -1,13 +1,21 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
 
 use primitive_types::U256;
 use serde::{Deserialize, Serialize};
 
 use crate::{
+    encode::{Decodable, DecodeError, Encodable},
     hash::{merkle_root, sha256},
     transaction::{Transaction, TxID, TxOut},
 };
 
+// Number of blocks a coinbase output has to wait before it can be spent
+pub const COINBASE_MATURITY: u64 = 100;
+// Number of previous blocks whose timestamps make up the median time past
+pub const MEDIAN_TIME_SPAN: usize = 11;
+// How far a block timestamp may be ahead of the network-adjusted time
+pub const MAX_FUTURE_BLOCK_TIME: u32 = 2 * 60 * 60;
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Block {
     pub header: BlockHeader,
@@ -27,27 +35,24 @@ impl Block {
             return false;
         }
 
-        // Validate transactions
-        for i in 1..self.transactions.len() {
-            let tx = &self.transactions[i];
-            for i in 0..tx.inputs.len() {
-                let sum_in: u64 = tx.outputs.iter().map(|tx_out| tx_out.amount).sum();
-                let mut sum_out = 0;
-                match outpoints.get(&(tx.inputs[i].txid, tx.inputs[i].output_index)) {
-                    Some(tx_out) => {
-                        sum_out += tx_out.amount;
-                        if !tx.validate(
-                            i,
-                            &tx.inputs[i].script_sig,
-                            &tx_out.script_pub_key,
-                            tx_out.amount,
-                        ) {
-                            return false;
-                        }
-                    }
-                    None => return false,
+        // Validate Coinbase, which comes first and only there
+        match self.transactions.split_first() {
+            Some((coinbase, txs)) if coinbase.is_coinbase() => {
+                if txs.iter().any(|tx| tx.is_coinbase()) {
+                    return false;
                 }
-                if sum_in < sum_out {
+            }
+            _ => return false,
+        }
+
+        // Validate transactions, whose outputs can't spend more than their inputs
+        for tx in self.transactions.iter().skip(1) {
+            if tx.fee(outpoints).is_none() {
+                return false;
+            }
+            for (i, tx_in) in tx.inputs.iter().enumerate() {
+                let tx_out = &outpoints[&(tx_in.txid, tx_in.output_index)];
+                if !tx.validate(i, &tx_in.script_sig, &tx_out.script_pub_key, tx_out.amount) {
                     return false;
                 }
             }
@@ -70,41 +75,99 @@ pub struct BlockHeader {
 
 impl BlockHeader {
     pub fn hash(&self) -> [u8; 32] {
-        let bytes = {
-            let mut bytes: [u8; 80] = [0; 80];
-            bytes[0..4].copy_from_slice(&self.version.to_le_bytes());
+        let mut hash = sha256(sha256(self.encode()).to_vec());
+        hash.reverse();
+        hash
+    }
 
-            let mut prev_block_hash_le = self.prev_block_hash;
-            prev_block_hash_le.reverse();
-            bytes[4..36].copy_from_slice(&prev_block_hash_le);
+    // None if the exponent of the compact bits is out of range
+    pub fn target(&self) -> Option<U256> {
+        let bytes = self.bits.to_be_bytes();
+        let index = bytes[0];
+        if !(3..=32).contains(&index) {
+            return None;
+        }
+        let coef = U256::from_big_endian(&bytes[1..4]);
+        Some(coef << (8 * (index - 3)))
+    }
 
-            let mut merkle_root_le = self.merkle_root;
-            merkle_root_le.reverse();
-            bytes[36..68].copy_from_slice(&merkle_root_le);
+    // Expected number of hashes to find a block at this target, 2^256 / (target + 1). Zero for
+    // an invalid target.
+    pub fn work(&self) -> U256 {
+        match self.target() {
+            Some(target) => (!target / (target + 1)) + 1,
+            None => U256::zero(),
+        }
+    }
 
-            bytes[68..72].copy_from_slice(&self.timestamp.to_le_bytes());
-            bytes[72..76].copy_from_slice(&self.bits.to_le_bytes());
-            bytes[76..80].copy_from_slice(&self.nonce.to_le_bytes());
+    pub fn validate(&self) -> bool {
+        let hash = self.hash();
+        let hash_u256 = U256::from_big_endian(&hash);
+        self.target().is_some_and(|target| hash_u256 <= target)
+    }
 
-            bytes
-        };
+    pub fn validate_timestamp(&self, median_time_past: u32, adjusted_time: u32) -> bool {
+        self.timestamp > median_time_past
+            && self.timestamp <= adjusted_time.saturating_add(MAX_FUTURE_BLOCK_TIME)
+    }
+}
 
-        let mut hash = sha256(sha256(bytes.to_vec()).to_vec());
-        hash.reverse();
-        hash
+// Median of the given timestamps of the latest blocks, at most the last MEDIAN_TIME_SPAN are used
+pub fn median_time_past(timestamps: &[u32]) -> u32 {
+    let start = timestamps.len().saturating_sub(MEDIAN_TIME_SPAN);
+    let mut timestamps = timestamps[start..].to_vec();
+    if timestamps.is_empty() {
+        return 0;
     }
+    timestamps.sort();
+    timestamps[timestamps.len() / 2]
+}
 
-    pub fn validate(&self) -> bool {
-        let target = {
-            let bytes = self.bits.to_be_bytes();
-            let index = bytes[0];
-            let coef = U256::from_big_endian(&bytes[1..4]);
-            coef << (8 * (index - 3))
-        };
+impl Encodable for Block {
+    fn encode(&self) -> Vec<u8> {
+        let mut bytes = self.header.encode();
+        bytes.append(&mut self.transactions.encode());
+        bytes
+    }
+}
 
-        let hash = self.hash();
-        let hash_u256 = U256::from_big_endian(&hash);
-        hash_u256 <= target
+// The height is not encoded, decoded blocks have to get it from elsewhere
+impl Decodable for Block {
+    fn decode(bytes: &mut VecDeque<u8>) -> Result<Self, DecodeError> {
+        let header = BlockHeader::decode(bytes)?;
+        let transactions = Vec::decode(bytes)?;
+        Ok(Block {
+            header,
+            transactions,
+        })
+    }
+}
+
+// The height is not part of the 80 byte header
+impl Encodable for BlockHeader {
+    fn encode(&self) -> Vec<u8> {
+        let mut bytes = vec![];
+        bytes.append(&mut self.version.encode());
+        bytes.append(&mut self.prev_block_hash.encode());
+        bytes.append(&mut self.merkle_root.encode());
+        bytes.append(&mut self.timestamp.encode());
+        bytes.append(&mut self.bits.encode());
+        bytes.append(&mut self.nonce.encode());
+        bytes
+    }
+}
+
+impl Decodable for BlockHeader {
+    fn decode(bytes: &mut VecDeque<u8>) -> Result<Self, DecodeError> {
+        Ok(BlockHeader {
+            height: 0,
+            version: u32::decode(bytes)?,
+            prev_block_hash: <[u8; 32]>::decode(bytes)?,
+            merkle_root: <[u8; 32]>::decode(bytes)?,
+            timestamp: u32::decode(bytes)?,
+            bits: u32::decode(bytes)?,
+            nonce: u32::decode(bytes)?,
+        })
     }
 }
 
@@ -130,4 +193,57 @@ mod tests {
         };
         assert!(block_header.validate());
     }
+
+    #[test]
+    pub fn test_work() {
+        let mut header = BlockHeader {
+            height: 0,
+            version: 1,
+            prev_block_hash: [0; 32],
+            merkle_root: [0; 32],
+            timestamp: 0,
+            bits: 0x1d00ffff,
+            nonce: 0,
+        };
+        // Difficulty 1 is 2^32 + 2^16 + 1 hashes
+        assert_eq!(header.work(), U256::from(0x100010001u64));
+        header.bits = 0x207fffff;
+        assert_eq!(header.work(), U256::from(2));
+        // Exponents out of range
+        for bits in [0x0200ffff, 0x2100ffff] {
+            header.bits = bits;
+            assert_eq!(header.target(), None);
+            assert_eq!(header.work(), U256::zero());
+            assert!(!header.validate());
+        }
+    }
+
+    #[test]
+    pub fn test_median_time_past() {
+        assert_eq!(median_time_past(&[]), 0);
+        assert_eq!(median_time_past(&[5, 1, 3]), 3);
+        assert_eq!(median_time_past(&[1, 2, 3, 4]), 3);
+        // Only the last MEDIAN_TIME_SPAN timestamps count
+        let timestamps: Vec<u32> = (0..20).collect();
+        assert_eq!(median_time_past(&timestamps), 14);
+    }
+
+    #[test]
+    pub fn test_validate_timestamp() {
+        let mut header = BlockHeader {
+            height: 1,
+            version: 1,
+            prev_block_hash: [0; 32],
+            merkle_root: [0; 32],
+            timestamp: 100,
+            bits: 0x207fffff,
+            nonce: 0,
+        };
+        assert!(header.validate_timestamp(99, 100));
+        assert!(!header.validate_timestamp(100, 100));
+        header.timestamp = 100 + MAX_FUTURE_BLOCK_TIME;
+        assert!(header.validate_timestamp(99, 100));
+        header.timestamp += 1;
+        assert!(!header.validate_timestamp(99, 100));
+    }
 }
@@ -0,0 +1,77 @@
+use chacha20poly1305::{aead::Aead, ChaCha20Poly1305, KeyInit};
+
+use crate::encode::DecodeError;
+
+// Memory-hard key derivation of RFC 7914, costing 128 * r * 2^log_n bytes of memory. None for
+// parameters scrypt doesn't accept.
+pub fn scrypt(
+    password: &[u8],
+    salt: &[u8],
+    log_n: u8,
+    r: u32,
+    p: u32,
+    len: usize,
+) -> Option<Vec<u8>> {
+    let params = scrypt::Params::new(log_n, r, p, len).ok()?;
+    let mut key = vec![0; len];
+    scrypt::scrypt(password, salt, &params, &mut key).ok()?;
+    Some(key)
+}
+
+// Encrypts with ChaCha20-Poly1305, prepending the nonce to the ciphertext and its tag
+pub fn encrypt(key: &[u8; 32], nonce: [u8; 12], plaintext: &[u8]) -> Vec<u8> {
+    let cipher = ChaCha20Poly1305::new(key.into());
+    let mut data = nonce.to_vec();
+    data.extend(cipher.encrypt(&nonce.into(), plaintext).unwrap());
+    data
+}
+
+// Fails if the data was not encrypted with `key` or was modified
+pub fn decrypt(key: &[u8; 32], data: &[u8]) -> Result<Vec<u8>, DecodeError> {
+    if data.len() < 12 {
+        return Err(DecodeError);
+    }
+    let (nonce, ciphertext) = data.split_at(12);
+    let cipher = ChaCha20Poly1305::new(key.into());
+    cipher
+        .decrypt(nonce.into(), ciphertext)
+        .map_err(|_| DecodeError)
+}
+
+#[cfg(test)]
+mod tests {
+    use hex_literal::hex;
+
+    use super::*;
+
+    #[test]
+    fn test_scrypt() {
+        // Test vectors of RFC 7914
+        assert_eq!(
+            scrypt(b"", b"", 4, 1, 1, 64).unwrap(),
+            hex!("77d6576238657b203b19ca42c18a0497f16b4844e3074ae8dfdffa3fede21442fcd0069ded0948f8326a753a0fc81f17e8d3e0fb2e0d3628cf35e20c38d18906")
+        );
+        assert_eq!(
+            scrypt(b"password", b"NaCl", 10, 8, 16, 64).unwrap(),
+            hex!("fdbabe1c9d3472007856e7190d01e9fe7c6ad7cbc8237830e77376634b3731622eaf30d92e22a3886ff109279d9830dac727afb94a83ee6d8360cbdfa2cc0640")
+        );
+    }
+
+    #[test]
+    fn test_encrypt() {
+        let key = [7; 32];
+        let plaintext = [1; 45];
+        let data = encrypt(&key, [2; 12], &plaintext);
+        assert_eq!(data.len(), 12 + 45 + 16);
+        assert_eq!(decrypt(&key, &data).unwrap(), plaintext);
+        // A different nonce gives a different ciphertext
+        assert_ne!(encrypt(&key, [3; 12], &plaintext)[12..57], data[12..57]);
+
+        assert!(decrypt(&[8; 32], &data).is_err());
+        let mut modified = data.clone();
+        modified[20] ^= 1;
+        assert!(decrypt(&key, &modified).is_err());
+        assert!(decrypt(&key, &data[..40]).is_err());
+        assert!(decrypt(&key, &data[..8]).is_err());
+    }
+}
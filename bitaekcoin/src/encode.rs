@@ -12,6 +12,7 @@ pub trait Decodable: Sized {
     fn decode(bytes: &mut VecDeque<u8>) -> Result<Self, DecodeError>;
 }
 
+#[derive(Debug)]
 pub struct DecodeError;
 
 macro_rules! impl_int_encodable {
@@ -24,11 +25,27 @@ macro_rules! impl_int_encodable {
     };
 }
 
+macro_rules! impl_int_decodable {
+    ($ty:ident) => {
+        impl Decodable for $ty {
+            fn decode(bytes: &mut VecDeque<u8>) -> Result<Self, DecodeError> {
+                let value = pop_front(bytes, std::mem::size_of::<$ty>(), DecodeError)?;
+                Ok($ty::from_le_bytes(value.try_into().unwrap()))
+            }
+        }
+    };
+}
+
 impl_int_encodable!(u8);
 impl_int_encodable!(u16);
 impl_int_encodable!(u32);
 impl_int_encodable!(u64);
 
+impl_int_decodable!(u8);
+impl_int_decodable!(u16);
+impl_int_decodable!(u32);
+impl_int_decodable!(u64);
+
 impl Encodable for [u8; 32] {
     fn encode(&self) -> Vec<u8> {
         let mut bytes = *self;
@@ -37,6 +54,14 @@ impl Encodable for [u8; 32] {
     }
 }
 
+impl Decodable for [u8; 32] {
+    fn decode(bytes: &mut VecDeque<u8>) -> Result<Self, DecodeError> {
+        let mut value = pop_front(bytes, 32, DecodeError)?;
+        value.reverse();
+        Ok(value.try_into().unwrap())
+    }
+}
+
 impl<T: Encodable> Encodable for Vec<T> {
     fn encode(&self) -> Vec<u8> {
         let len = VarInt(self.len() as u64);
@@ -48,7 +73,29 @@ impl<T: Encodable> Encodable for Vec<T> {
     }
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+impl<T: Decodable> Decodable for Vec<T> {
+    fn decode(bytes: &mut VecDeque<u8>) -> Result<Self, DecodeError> {
+        let len = VarInt::decode(bytes)?.0;
+        let mut items = Vec::new();
+        for _ in 0..len {
+            items.push(T::decode(bytes)?);
+        }
+        Ok(items)
+    }
+}
+
+// Decodes a value that has to span all of `bytes`
+pub fn decode_all<T: Decodable>(bytes: Vec<u8>) -> Result<T, DecodeError> {
+    let mut bytes = VecDeque::from(bytes);
+    let value = T::decode(&mut bytes)?;
+    if bytes.is_empty() {
+        Ok(value)
+    } else {
+        Err(DecodeError)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct VarInt(pub u64);
 
 impl Encodable for VarInt {
@@ -43,6 +43,18 @@ pub fn ripemd160(bytes: Vec<u8>) -> [u8; 20] {
     hasher.finalize().into()
 }
 
+pub fn hash160(bytes: Vec<u8>) -> [u8; 20] {
+    ripemd160(sha256(bytes).to_vec())
+}
+
+// Hash of `bytes` in the domain of `tag`, used by taproot
+pub fn tagged_hash(tag: &str, bytes: Vec<u8>) -> [u8; 32] {
+    let tag = sha256(tag.as_bytes().to_vec());
+    let mut data = [tag, tag].concat();
+    data.extend(bytes);
+    sha256(data)
+}
+
 #[derive(Debug, Clone, Copy)]
 pub enum SigHash {
     All,
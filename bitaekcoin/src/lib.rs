@@ -1,6 +1,11 @@
+pub mod address;
+pub mod bip32;
+pub mod bip39;
 pub mod block;
+pub mod crypto;
 pub mod encode;
 pub mod hash;
 pub mod script;
 pub mod transaction;
 pub mod utils;
+pub mod wif;
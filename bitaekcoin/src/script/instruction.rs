@@ -1,6 +1,11 @@
+use std::collections::VecDeque;
+
 use serde::{Deserialize, Serialize};
 
-use crate::encode::Encodable;
+use crate::{
+    encode::{Decodable, DecodeError, Encodable},
+    utils::pop_front,
+};
 
 pub const OP_0: u8 = 0x00;
 pub const OP_PUSHDATA1: u8 = 0x4c;
@@ -17,7 +22,7 @@ pub const OP_CODESEPARATOR: u8 = 0xab;
 pub const OP_CHECKSIG: u8 = 0xac;
 pub const OP_CHECKMULTISIG: u8 = 0xae;
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub enum Instruction {
     Opcode(u8),
     PushBytes(PushBytes),
@@ -56,7 +61,39 @@ impl Encodable for Instruction {
     }
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+impl Decodable for Instruction {
+    fn decode(bytes: &mut VecDeque<u8>) -> Result<Self, DecodeError> {
+        let opcode = pop_front(bytes, 1, DecodeError)?[0];
+        let instruction = match opcode {
+            OP_0 => Instruction::PushBytes(PushBytes::Empty),
+            0x01..=0x4b => {
+                let value = pop_front(bytes, opcode as usize, DecodeError)?;
+                Instruction::PushBytes(PushBytes::Bytes(opcode, value))
+            }
+            OP_PUSHDATA1 => {
+                let n = pop_front(bytes, 1, DecodeError)?[0];
+                let value = pop_front(bytes, n as usize, DecodeError)?;
+                Instruction::PushBytes(PushBytes::Data1(n, value))
+            }
+            OP_PUSHDATA2 => {
+                let n = u16::from_le_bytes(pop_front(bytes, 2, DecodeError)?.try_into().unwrap());
+                let value = pop_front(bytes, n as usize, DecodeError)?;
+                Instruction::PushBytes(PushBytes::Data2(n, value))
+            }
+            OP_PUSHDATA4 => {
+                let n = u32::from_le_bytes(pop_front(bytes, 4, DecodeError)?.try_into().unwrap());
+                let value = pop_front(bytes, n as usize, DecodeError)?;
+                Instruction::PushBytes(PushBytes::Data4(n, value))
+            }
+            OP_1NEGATE => Instruction::PushBytes(PushBytes::Negate1),
+            0x51..=0x60 => Instruction::PushBytes(PushBytes::OneToSixteen(opcode - 0x50)),
+            _ => Instruction::Opcode(opcode),
+        };
+        Ok(instruction)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub enum PushBytes {
     Empty,
     Bytes(u8, Vec<u8>),
@@ -92,6 +129,15 @@ impl PushBytes {
     }
 }
 
+impl Decodable for PushBytes {
+    fn decode(bytes: &mut VecDeque<u8>) -> Result<Self, DecodeError> {
+        match Instruction::decode(bytes)? {
+            Instruction::PushBytes(pb) => Ok(pb),
+            Instruction::Opcode(_) => Err(DecodeError),
+        }
+    }
+}
+
 impl Encodable for PushBytes {
     fn encode(&self) -> Vec<u8> {
         match self {
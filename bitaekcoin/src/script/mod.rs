@@ -3,7 +3,7 @@ use std::collections::VecDeque;
 use crate::{
     encode::{Decodable, DecodeError, Encodable},
     hash::SigHash,
-    utils::{pop_front, signature_sighash},
+    utils::signature_sighash,
 };
 
 pub mod instruction;
@@ -12,7 +12,7 @@ use instruction::*;
 use k256::ecdsa::Signature;
 use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub struct Script(pub Vec<Instruction>);
 
 impl Script {
@@ -52,6 +52,12 @@ impl Script {
                         }
                         _ => None,
                     }
+                } else if let (
+                    Instruction::PushBytes(PushBytes::OneToSixteen(version)),
+                    Instruction::PushBytes(PushBytes::Bytes(2..=40, program)),
+                ) = (&instructions[0], &instructions[1])
+                {
+                    Some(StandardScript::WitnessProgram(*version, program.clone()))
                 } else if let Instruction::PushBytes(pb) = &instructions[0] {
                     if instructions[1].opcode() == OP_CHECKSIG {
                         Some(StandardScript::P2PK(pb.bytes()))
@@ -200,35 +206,7 @@ impl Decodable for Script {
     fn decode(bytes: &mut VecDeque<u8>) -> Result<Self, DecodeError> {
         let mut instructions = Vec::new();
         while !bytes.is_empty() {
-            let opcode = bytes.pop_front().unwrap();
-            let instruction = match opcode {
-                OP_0 => Instruction::PushBytes(PushBytes::Empty),
-                0x01..=0x4b => {
-                    let value = pop_front(bytes, opcode as usize, DecodeError)?;
-                    Instruction::PushBytes(PushBytes::Bytes(opcode, value))
-                }
-                OP_PUSHDATA1 => {
-                    let n = pop_front(bytes, 1, DecodeError)?[0];
-                    let value = pop_front(bytes, n as usize, DecodeError)?;
-                    Instruction::PushBytes(PushBytes::Data1(n, value))
-                }
-                OP_PUSHDATA2 => {
-                    let n =
-                        u16::from_le_bytes(pop_front(bytes, 2, DecodeError)?.try_into().unwrap());
-                    let value = pop_front(bytes, n as usize, DecodeError)?;
-                    Instruction::PushBytes(PushBytes::Data2(n, value))
-                }
-                OP_PUSHDATA4 => {
-                    let n =
-                        u32::from_le_bytes(pop_front(bytes, 4, DecodeError)?.try_into().unwrap());
-                    let value = pop_front(bytes, n as usize, DecodeError)?;
-                    Instruction::PushBytes(PushBytes::Data4(n, value))
-                }
-                OP_1NEGATE => Instruction::PushBytes(PushBytes::Negate1),
-                0x51..=0x60 => Instruction::PushBytes(PushBytes::OneToSixteen(opcode - 0x50)),
-                _ => Instruction::Opcode(opcode),
-            };
-            instructions.push(instruction);
+            instructions.push(Instruction::decode(bytes)?);
         }
         Ok(Script(instructions))
     }
@@ -245,13 +223,14 @@ pub enum StandardScriptType {
 
 #[derive(Debug, Clone)]
 pub enum StandardScript {
-    P2PK(Vec<u8>),              // public key
-    P2PKH(Vec<u8>),             // public key hash
-    P2MS(u8, u8, Vec<Vec<u8>>), // m-of-n with n public keys
-    P2SH(Vec<u8>),              // script hash
-    NullData(Vec<u8>),          // data
-    P2WPKH(Vec<u8>),            // public key hash
-    P2WSH(Vec<u8>),             // script hash
+    P2PK(Vec<u8>),               // public key
+    P2PKH(Vec<u8>),              // public key hash
+    P2MS(u8, u8, Vec<Vec<u8>>),  // m-of-n with n public keys
+    P2SH(Vec<u8>),               // script hash
+    NullData(Vec<u8>),           // data
+    P2WPKH(Vec<u8>),             // public key hash
+    P2WSH(Vec<u8>),              // script hash
+    WitnessProgram(u8, Vec<u8>), // witness version 1 to 16 and program
 }
 
 impl StandardScript {
@@ -294,6 +273,10 @@ impl StandardScript {
                 Instruction::PushBytes(PushBytes::Empty),
                 Instruction::PushBytes(PushBytes::from_bytes(sh)),
             ],
+            StandardScript::WitnessProgram(version, program) => vec![
+                Instruction::PushBytes(PushBytes::OneToSixteen(version)),
+                Instruction::PushBytes(PushBytes::from_bytes(program)),
+            ],
         };
         Script(instructions)
     }
@@ -1,20 +1,23 @@
-use std::collections::VecDeque;
+use std::collections::{HashMap, VecDeque};
 
 use k256::ecdsa::{signature::hazmat::PrehashVerifier, VerifyingKey};
 use serde::{Deserialize, Serialize};
 
 use crate::{
-    encode::{Decodable, Encodable, VarInt},
+    encode::{Decodable, DecodeError, Encodable, VarInt},
     hash::{ripemd160, sha256, SigHash},
     script::{
         instruction::{Instruction, PushBytes},
         Script, StandardScript, StandardScriptType, UnlockingStandardScript,
     },
-    utils::signature_sighash,
+    utils::{pop_front, signature_sighash},
 };
 
 pub type TxID = [u8; 32];
 
+// Lock times below this are block heights, otherwise unix timestamps
+pub const LOCKTIME_THRESHOLD: u32 = 500_000_000;
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Transaction {
     pub version: u32,
@@ -51,6 +54,52 @@ impl Transaction {
         self.flag.is_some()
     }
 
+    // Witness data counts a quarter of other data (BIP141)
+    pub fn weight(&self) -> usize {
+        self.encode_without_witness().len() * 3 + self.encode().len()
+    }
+
+    // Size in virtual bytes, which fee rates are given in
+    pub fn vsize(&self) -> usize {
+        self.weight().div_ceil(4)
+    }
+
+    // Amount of the spent outputs left over by the outputs. None if a spent output is unknown
+    // or the outputs spend more than the inputs.
+    pub fn fee(&self, outpoints: &HashMap<(TxID, u32), TxOut>) -> Option<u64> {
+        let sum_in = self.inputs.iter().try_fold(0u64, |sum, tx_in| {
+            sum.checked_add(outpoints.get(&(tx_in.txid, tx_in.output_index))?.amount)
+        })?;
+        let sum_out = self
+            .outputs
+            .iter()
+            .try_fold(0u64, |sum, tx_out| sum.checked_add(tx_out.amount))?;
+        sum_in.checked_sub(sum_out)
+    }
+
+    // `time` is the median time past of the previous block (BIP113)
+    pub fn is_final(&self, height: u64, time: u32) -> bool {
+        if self.lock_time == 0 {
+            return true;
+        }
+        let lock_time = self.lock_time as u64;
+        let limit = if self.lock_time < LOCKTIME_THRESHOLD {
+            height
+        } else {
+            time as u64
+        };
+        if lock_time < limit {
+            return true;
+        }
+        self.inputs.iter().all(|tx_in| tx_in.sequence == 0xffffffff)
+    }
+
+    pub fn is_coinbase(&self) -> bool {
+        self.inputs.len() == 1
+            && self.inputs[0].txid == [0; 32]
+            && self.inputs[0].output_index == 0xffffffff
+    }
+
     pub fn hash_prevouts(&self, sighash: SigHash) -> [u8; 32] {
         match sighash {
             SigHash::AnyoneCanPay => [0; 32],
@@ -116,7 +165,6 @@ impl Transaction {
                             }
                         }
                         _ => false,
-                        
                     }
                 }
                 StandardScript::P2PKH(pkh) => {
@@ -280,8 +328,11 @@ impl Transaction {
                         false
                     }
                 }
+                // Spending rules of later witness versions are not implemented
+                StandardScript::WitnessProgram(_, _) => false,
             },
-            None => unimplemented!("NON STANDARD SCRIPT"),
+            // Non-standard outputs can't be spent
+            None => false,
         }
     }
 }
@@ -306,6 +357,36 @@ impl Encodable for Transaction {
     }
 }
 
+impl Decodable for Transaction {
+    fn decode(bytes: &mut VecDeque<u8>) -> Result<Self, DecodeError> {
+        let version = u32::decode(bytes)?;
+        // A zero byte can't start the inputs of a valid transaction, so it is the segwit marker
+        let flag = if bytes.front() == Some(&0) {
+            bytes.pop_front();
+            Some(u8::decode(bytes)?)
+        } else {
+            None
+        };
+        let inputs = Vec::<TxIn>::decode(bytes)?;
+        let outputs = Vec::<TxOut>::decode(bytes)?;
+        let mut witnesses = Vec::new();
+        if flag.is_some() {
+            for _ in 0..inputs.len() {
+                witnesses.push(Witness::decode(bytes)?);
+            }
+        }
+        let lock_time = u32::decode(bytes)?;
+        Ok(Transaction {
+            version,
+            flag,
+            inputs,
+            outputs,
+            witnesses,
+            lock_time,
+        })
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct TxIn {
     pub txid: TxID,
@@ -327,7 +408,23 @@ impl Encodable for TxIn {
     }
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+impl Decodable for TxIn {
+    fn decode(bytes: &mut VecDeque<u8>) -> Result<Self, DecodeError> {
+        let txid = TxID::decode(bytes)?;
+        let output_index = u32::decode(bytes)?;
+        let (script_size, script_sig) = decode_script(bytes)?;
+        let sequence = u32::decode(bytes)?;
+        Ok(TxIn {
+            txid,
+            output_index,
+            script_size,
+            script_sig,
+            sequence,
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct TxOut {
     pub amount: u64,
     pub script_size: VarInt,
@@ -344,6 +441,25 @@ impl Encodable for TxOut {
     }
 }
 
+impl Decodable for TxOut {
+    fn decode(bytes: &mut VecDeque<u8>) -> Result<Self, DecodeError> {
+        let amount = u64::decode(bytes)?;
+        let (script_size, script_pub_key) = decode_script(bytes)?;
+        Ok(TxOut {
+            amount,
+            script_size,
+            script_pub_key,
+        })
+    }
+}
+
+fn decode_script(bytes: &mut VecDeque<u8>) -> Result<(VarInt, Script), DecodeError> {
+    let script_size = VarInt::decode(bytes)?;
+    let script_bytes = pop_front(bytes, script_size.0 as usize, DecodeError)?;
+    let script = Script::decode(&mut VecDeque::from(script_bytes))?;
+    Ok((script_size, script))
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Witness(pub Vec<PushBytes>);
 
@@ -375,3 +491,9 @@ impl Encodable for Witness {
         self.0.encode()
     }
 }
+
+impl Decodable for Witness {
+    fn decode(bytes: &mut VecDeque<u8>) -> Result<Self, DecodeError> {
+        Ok(Witness(Vec::decode(bytes)?))
+    }
+}
@@ -16,9 +16,26 @@ pub fn pop_front<E>(bytes: &mut VecDeque<u8>, mut n: usize, err: E) -> Result<Ve
     Ok(ret)
 }
 
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+pub fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
 pub fn signature_sighash(mut bytes: Vec<u8>) -> Option<(Signature, SigHash)> {
     if let Some(byte) = bytes.pop() {
-        let signature = Signature::from_der(&bytes);
+        // k256 only verifies low-S signatures, while the ones of early blocks such as block
+        // 100000 may be high-S. Both are valid consensus-wise, so S is normalized first.
+        let signature = Signature::from_der(&bytes)
+            .map(|signature| signature.normalize_s().unwrap_or(signature));
         let sighash = SigHash::from_byte(byte);
         match (signature, sighash) {
             (Ok(signature), Some(sighash)) => Some((signature, sighash)),
@@ -28,3 +45,27 @@ pub fn signature_sighash(mut bytes: Vec<u8>) -> Option<(Signature, SigHash)> {
         None
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use hex_literal::hex;
+
+    use super::*;
+
+    #[test]
+    fn test_hex() {
+        assert_eq!(to_hex(&[0x00, 0xab, 0x10]), "00ab10");
+        assert_eq!(from_hex("00AB10"), Some(vec![0x00, 0xab, 0x10]));
+        assert_eq!(from_hex("0"), None);
+        assert_eq!(from_hex("zz"), None);
+    }
+
+    #[test]
+    fn test_high_s_signature() {
+        // Input of e9a66845e05d5abc0ad04ec80f774a7e585c6e8db975962d069a522137b80c1d in block 100000
+        let bytes = hex!("3046022100bb1ad26df930a51cce110cf44f7a48c3c561fd977500b1ae5d6b6fd13d0b3f4a022100c5b42951acedff14abba2736fd574bdb465f3e6f8da12e2c5303954aca7f78f301").to_vec();
+        let high_s = Signature::from_der(&bytes[..bytes.len() - 1]).unwrap();
+        let (signature, _) = signature_sighash(bytes).unwrap();
+        assert_eq!(Some(signature), high_s.normalize_s());
+    }
+}
@@ -0,0 +1,77 @@
+use std::fmt;
+
+use k256::ecdsa::SigningKey;
+
+use crate::{
+    address::{base58check_decode, base58check_encode, Network},
+    encode::DecodeError,
+};
+
+// Marks keys whose public key is used in compressed form
+const COMPRESSED: u8 = 0x01;
+
+// Private key in the wallet import format
+#[derive(Debug, Clone)]
+pub struct PrivateKey {
+    pub network: Network,
+    pub key: SigningKey,
+}
+
+impl PrivateKey {
+    fn prefix(network: Network) -> u8 {
+        match network {
+            Network::Mainnet => 0x80,
+            Network::Testnet | Network::Regtest => 0xef,
+        }
+    }
+
+    // Fails on keys of other networks and keys of uncompressed public keys, which scripts
+    // don't use here
+    pub fn parse(wif: &str, network: Network) -> Result<Self, DecodeError> {
+        let bytes = base58check_decode(wif)?;
+        if bytes.len() != 34 || bytes[0] != Self::prefix(network) || bytes[33] != COMPRESSED {
+            return Err(DecodeError);
+        }
+        let key = SigningKey::from_slice(&bytes[1..33]).map_err(|_| DecodeError)?;
+        Ok(Self { network, key })
+    }
+}
+
+impl fmt::Display for PrivateKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut bytes = vec![Self::prefix(self.network)];
+        bytes.extend(self.key.to_bytes());
+        bytes.push(COMPRESSED);
+        f.write_str(&base58check_encode(&bytes))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use hex_literal::hex;
+
+    use super::*;
+
+    #[test]
+    fn test_wif() {
+        let wif = "KwdMAjGmerYanjeui5SHS7JkmpZvVipYvB2LJGU1ZxJwYvP98617";
+        let key = PrivateKey::parse(wif, Network::Mainnet).unwrap();
+        assert_eq!(
+            key.key.to_bytes().as_slice(),
+            hex!("0c28fca386c7a227600b2fe50b7cae11ec86d3bf1fbe471be89827e19d72aa1d")
+        );
+        assert_eq!(key.to_string(), wif);
+
+        let testnet = PrivateKey {
+            network: Network::Regtest,
+            key: key.key,
+        }
+        .to_string();
+        assert!(testnet.starts_with('c'));
+        assert!(PrivateKey::parse(&testnet, Network::Testnet).is_ok());
+        assert!(PrivateKey::parse(&testnet, Network::Mainnet).is_err());
+        // Uncompressed
+        let uncompressed = "5HueCGU8rMjxEXxiPuD5BDku4MkFqeZyd4dZ1jvhTVqvbTLvyTJ";
+        assert!(PrivateKey::parse(uncompressed, Network::Mainnet).is_err());
+    }
+}
@@ -2,7 +2,7 @@ use std::collections::HashMap;
 
 use bitaekcoin::{
     block::{Block, BlockHeader},
-    encode::VarInt,
+    encode::{decode_all, Encodable, VarInt},
     script::{
         instruction::{Instruction, PushBytes},
         Script, StandardScript,
@@ -190,3 +190,15 @@ fn test() {
     let outpoints = prev_outpoints();
     assert!(block.validate(&outpoints));
 }
+
+#[test]
+fn test_encode_decode() {
+    let block = block();
+    let bytes = block.encode();
+    let decoded: Block = decode_all(bytes.clone()).unwrap();
+    assert_eq!(decoded.header.hash(), block.header.hash());
+    assert_eq!(decoded.encode(), bytes);
+    for (tx, decoded_tx) in block.transactions.iter().zip(&decoded.transactions) {
+        assert_eq!(decoded_tx.txid(), tx.txid());
+    }
+}
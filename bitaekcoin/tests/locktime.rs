@@ -0,0 +1,39 @@
+use bitaekcoin::{
+    encode::VarInt,
+    script::Script,
+    transaction::{Transaction, TxIn, LOCKTIME_THRESHOLD},
+};
+
+fn tx(lock_time: u32, sequence: u32) -> Transaction {
+    Transaction {
+        version: 1,
+        flag: None,
+        inputs: vec![TxIn {
+            txid: [1; 32],
+            output_index: 0,
+            script_size: VarInt(0),
+            script_sig: Script(vec![]),
+            sequence,
+        }],
+        outputs: vec![],
+        witnesses: vec![],
+        lock_time,
+    }
+}
+
+#[test]
+fn test_height_lock() {
+    assert!(tx(0, 0).is_final(0, 0));
+    assert!(!tx(10, 0).is_final(10, u32::MAX));
+    assert!(tx(10, 0).is_final(11, 0));
+    // Final sequences disable the lock time
+    assert!(tx(10, 0xffffffff).is_final(0, 0));
+}
+
+#[test]
+fn test_time_lock() {
+    let lock_time = LOCKTIME_THRESHOLD + 1000;
+    // Compared against the median time past, not the height
+    assert!(!tx(lock_time, 0).is_final(u64::MAX, lock_time));
+    assert!(tx(lock_time, 0).is_final(0, lock_time + 1));
+}
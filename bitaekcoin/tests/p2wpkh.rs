@@ -1,5 +1,7 @@
+use std::collections::HashMap;
+
 use bitaekcoin::{
-    encode::VarInt,
+    encode::{decode_all, Encodable, VarInt},
     script::{instruction::PushBytes, Script, StandardScript},
     transaction::{Transaction, TxIn, TxOut, Witness},
 };
@@ -71,3 +73,39 @@ fn test() {
     let locking_script = locking_script();
     assert!(tx.validate(0, &tx.inputs[0].script_sig, &locking_script, 194300));
 }
+
+#[test]
+fn test_encode_decode() {
+    let tx = tx();
+    let decoded: Transaction = decode_all(tx.encode()).unwrap();
+    assert_eq!(decoded.flag, Some(1));
+    assert_eq!(decoded.txid(), tx.txid());
+    assert_eq!(decoded.wtxid(), tx.wtxid());
+}
+
+#[test]
+fn test_weight_and_fee() {
+    let tx = tx();
+    assert_eq!(tx.encode_without_witness().len(), 159);
+    assert_eq!(tx.weight(), 746);
+    assert_eq!(tx.vsize(), 187);
+
+    let mut outpoints = HashMap::new();
+    assert_eq!(tx.fee(&outpoints), None);
+    let tx_out = TxOut {
+        amount: 194300,
+        script_size: VarInt(22),
+        script_pub_key: locking_script(),
+    };
+    outpoints.insert((tx.inputs[0].txid, 0), tx_out.clone());
+    assert_eq!(tx.fee(&outpoints), Some(31240));
+    // Outputs spending more than the input
+    outpoints.insert(
+        (tx.inputs[0].txid, 0),
+        TxOut {
+            amount: 100000,
+            ..tx_out
+        },
+    );
+    assert_eq!(tx.fee(&outpoints), None);
+}
@@ -1,40 +1,322 @@
-use std::collections::HashMap;
-
-use k256::{
-    ecdsa::{Signature, SigningKey, VerifyingKey},
-    elliptic_curve::generic_array::GenericArray,
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+    str::FromStr,
 };
+
+use k256::ecdsa::{Signature, SigningKey, VerifyingKey};
 use serde::{Deserialize, Serialize};
 
 use bitaekcoin::{
+    address::{taproot_output_key, Address},
+    bip32::{DerivationPath, ExtendedPrivateKey, ExtendedPublicKey, HARDENED},
     encode::{Encodable, VarInt},
-    hash::SigHash,
-    script::{Script, StandardScript, UnlockingStandardScript},
-    transaction::{Transaction, TxID, TxIn, TxOut},
+    hash::{hash160, SigHash},
+    script::{
+        instruction::{Instruction, PushBytes},
+        Script, StandardScript, UnlockingStandardScript,
+    },
+    transaction::{Transaction, TxID, Witness},
 };
 
+use crate::{tx_builder::TxBuilder, utxo::Utxo, NETWORK};
+
+// Chains of addresses below the key of an account, for receiving and for change
+pub const EXTERNAL: u32 = 0;
+pub const INTERNAL: u32 = 1;
+
+// Restoring a wallet stops looking for used addresses after this many unused ones in a row
+pub const DEFAULT_GAP_LIMIT: usize = 20;
+
+// Purpose level of a derivation path, which decides the output script of every address of
+// an account
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Purpose {
+    // BIP44, P2PKH
+    Legacy,
+    // BIP49, P2SH-P2WPKH
+    NestedSegwit,
+    // BIP84, P2WPKH
+    NativeSegwit,
+    // BIP86, P2TR spendable with the key path only
+    Taproot,
+}
+
+impl Purpose {
+    pub const ALL: [Purpose; 4] = [
+        Purpose::Legacy,
+        Purpose::NestedSegwit,
+        Purpose::NativeSegwit,
+        Purpose::Taproot,
+    ];
+
+    pub fn number(self) -> u32 {
+        match self {
+            Purpose::Legacy => 44,
+            Purpose::NestedSegwit => 49,
+            Purpose::NativeSegwit => 84,
+            Purpose::Taproot => 86,
+        }
+    }
+
+    // None for the negligible chance of a taproot output key at infinity
+    pub fn script(self, public_key: &VerifyingKey) -> Option<Script> {
+        let pubkey_hash = hash160(public_key.to_sec1_bytes().to_vec()).to_vec();
+        let script = match self {
+            Purpose::Legacy => StandardScript::P2PKH(pubkey_hash),
+            Purpose::NestedSegwit => {
+                let redeem_script = StandardScript::P2WPKH(pubkey_hash).into_script();
+                StandardScript::P2SH(hash160(redeem_script.encode()).to_vec())
+            }
+            Purpose::NativeSegwit => StandardScript::P2WPKH(pubkey_hash),
+            Purpose::Taproot => {
+                StandardScript::WitnessProgram(1, taproot_output_key(public_key)?.to_vec())
+            }
+        };
+        Some(script.into_script())
+    }
+}
+
+// Named after the address types of Bitcoin Core
+impl FromStr for Purpose {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "legacy" => Ok(Purpose::Legacy),
+            "p2sh-segwit" => Ok(Purpose::NestedSegwit),
+            "bech32" => Ok(Purpose::NativeSegwit),
+            "bech32m" => Ok(Purpose::Taproot),
+            _ => Err(format!("Unknown address type {}", s)),
+        }
+    }
+}
+
+impl fmt::Display for Purpose {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Purpose::Legacy => "legacy",
+            Purpose::NestedSegwit => "p2sh-segwit",
+            Purpose::NativeSegwit => "bech32",
+            Purpose::Taproot => "bech32m",
+        })
+    }
+}
+
+// m/purpose'/coin_type'/account', the chains and addresses of the account are below it
+pub fn account_path(purpose: Purpose, account: u32) -> DerivationPath {
+    DerivationPath(vec![
+        purpose.number() | HARDENED,
+        NETWORK.coin_type() | HARDENED,
+        account | HARDENED,
+    ])
+}
+
+// Key of a single address of an account
+#[derive(Debug, Clone)]
+pub struct Key {
+    // None for watched scripts
+    pub public_key: Option<VerifyingKey>,
+    // None for watch-only keys and scripts and while the wallet is locked
+    pub private_key: Option<SigningKey>,
+    // Derivation path from the wallet seed, None for keys that weren't derived
+    pub path: Option<DerivationPath>,
+    // Output scripts owned by the key
+    pub scripts: Vec<Script>,
+    // Private key of an encrypted wallet, for keys that weren't derived
+    pub encrypted_key: Option<Vec<u8>>,
+}
+
+impl Key {
+    // Keys that weren't derived are spendable as P2PK, P2PKH, P2WPKH and P2SH-P2WPKH
+    pub fn new(private_key: Vec<u8>) -> Result<Self, String> {
+        // Shorter keys would be padded
+        let private_key = match SigningKey::from_slice(&private_key) {
+            Ok(key) if private_key.len() == 32 => key,
+            _ => return Err("Invalid private key in wallet".to_owned()),
+        };
+        Ok(Self {
+            private_key: Some(private_key.clone()),
+            ..Self::watch(VerifyingKey::from(&private_key))
+        })
+    }
+
+    // Watches the same scripts as a key that wasn't derived
+    pub fn watch(public_key: VerifyingKey) -> Self {
+        let pubkey = public_key.to_sec1_bytes().to_vec();
+        let pubkey_hash = hash160(pubkey.clone()).to_vec();
+        let p2wpkh = StandardScript::P2WPKH(pubkey_hash.clone()).into_script();
+        let scripts = vec![
+            StandardScript::P2PK(pubkey).into_script(),
+            StandardScript::P2PKH(pubkey_hash).into_script(),
+            StandardScript::P2SH(hash160(p2wpkh.encode()).to_vec()).into_script(),
+            p2wpkh,
+        ];
+        Self {
+            public_key: Some(public_key),
+            private_key: None,
+            path: None,
+            scripts,
+            encrypted_key: None,
+        }
+    }
+
+    pub fn watch_script(script: Script) -> Self {
+        Self {
+            public_key: None,
+            private_key: None,
+            path: None,
+            scripts: vec![script],
+            encrypted_key: None,
+        }
+    }
+
+    // Derived and encrypted keys sign once the wallet is unlocked
+    pub fn is_watch_only(&self) -> bool {
+        self.private_key.is_none() && self.path.is_none() && self.encrypted_key.is_none()
+    }
+
+    // EXTERNAL or INTERNAL for derived keys
+    pub fn chain(&self) -> Option<u32> {
+        self.path.as_ref().and_then(|path| path.0.get(3).copied())
+    }
+
+    // Sets the script sig or witness of input `i` spending an output locked by `script`
+    pub(crate) fn sign_input(
+        &self,
+        tx: &mut Transaction,
+        i: usize,
+        script: &Script,
+        amount: u64,
+    ) -> Result<(), String> {
+        let pubkey = match (&self.public_key, &self.private_key) {
+            (Some(public_key), Some(_)) => public_key.to_sec1_bytes().to_vec(),
+            _ if !self.is_watch_only() => return Err("Wallet is locked".to_owned()),
+            _ => return Err("Watch-only keys can't sign".to_owned()),
+        };
+        match script.to_standard() {
+            Some(StandardScript::P2PK(_)) => {
+                let hash = SigHash::All.hash(tx, i, script, amount);
+                let signature = self.sign(hash.to_vec());
+                tx.inputs[i].script_sig =
+                    UnlockingStandardScript::P2PK(signature, SigHash::All).into_script();
+            }
+            Some(StandardScript::P2PKH(_)) => {
+                let hash = SigHash::All.hash(tx, i, script, amount);
+                let signature = self.sign(hash.to_vec());
+                tx.inputs[i].script_sig =
+                    UnlockingStandardScript::P2PKH(signature, SigHash::All, pubkey).into_script();
+            }
+            Some(StandardScript::P2WPKH(_)) => {
+                let hash = SigHash::All.hash(tx, i, script, amount);
+                tx.witnesses[i] = self.witness(hash, pubkey);
+            }
+            // Only nested P2WPKH is spendable by an account
+            Some(StandardScript::P2SH(_)) => {
+                let redeem_script = StandardScript::P2WPKH(hash160(pubkey.clone()).to_vec());
+                let redeem_script = redeem_script.into_script();
+                let hash = SigHash::All.hash(tx, i, &redeem_script, amount);
+                tx.witnesses[i] = self.witness(hash, pubkey);
+                tx.inputs[i].script_sig = Script(vec![Instruction::PushBytes(
+                    PushBytes::from_bytes(redeem_script.encode()),
+                )]);
+            }
+            // Including taproot outputs, whose spending rules aren't implemented
+            _ => return Err("Can't sign output of unsupported script type".to_owned()),
+        }
+        tx.inputs[i].script_size = VarInt(tx.inputs[i].script_sig.encode().len() as u64);
+        Ok(())
+    }
+
+    fn witness(&self, hash: [u8; 32], pubkey: Vec<u8>) -> Witness {
+        let mut signature = self.sign(hash.to_vec()).to_der().as_bytes().to_vec();
+        signature.push(SigHash::All.to_byte());
+        Witness(vec![
+            PushBytes::from_bytes(signature),
+            PushBytes::from_bytes(pubkey),
+        ])
+    }
+
+    fn sign(&self, msg: Vec<u8>) -> Signature {
+        let private_key = self.private_key.as_ref().expect("Watch-only key");
+        private_key.sign_prehash_recoverable(&msg).unwrap().0
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct Account {
     pub index: usize,
-    pub public_key: VerifyingKey,
-    pub private_key: SigningKey,
-    pub balance: u64,
-    pub utxos: HashMap<(TxID, u32), TxOut>,
+    // None for accounts of a single key that wasn't derived, like the mining account
+    pub purpose: Option<Purpose>,
+    pub path: Option<DerivationPath>,
+    // None for accounts that weren't derived, the private key also while the wallet is locked
+    pub account_key: Option<ExtendedPrivateKey>,
+    pub xpub: Option<ExtendedPublicKey>,
+    // Addresses of both chains in the order they were derived
+    pub keys: Vec<Key>,
+    pub utxos: HashMap<(TxID, u32), Utxo>,
+    // Outputs of mempool transactions paying the account that no other one spends
+    pub pending_utxos: HashMap<(TxID, u32), Utxo>,
+    // Confirmed outputs spent by mempool transactions
+    pub pending_spends: HashSet<(TxID, u32)>,
+    // Mempool transactions funded by the account, whose outputs can be spent before they
+    // confirm
+    pub pending_txids: HashSet<TxID>,
 }
 
 impl Account {
-    pub fn new(index: usize, private_key: Vec<u8>) -> Self {
-        let private_key = SigningKey::from_bytes(&GenericArray::from_iter(private_key)).unwrap();
-        let public_key = VerifyingKey::from(&private_key);
+    pub fn new(index: usize, private_key: Vec<u8>) -> Result<Self, String> {
+        Ok(Self::from_key(index, Key::new(private_key)?))
+    }
+
+    // Account of a single key that wasn't derived
+    pub fn from_key(index: usize, key: Key) -> Self {
         Self {
             index,
-            public_key,
-            private_key,
-            balance: 0,
+            purpose: None,
+            path: None,
+            account_key: None,
+            xpub: None,
+            keys: vec![key],
             utxos: HashMap::new(),
+            pending_utxos: HashMap::new(),
+            pending_spends: HashSet::new(),
+            pending_txids: HashSet::new(),
         }
     }
 
+    // An account at `path` without any addresses yet, see `account_path`
+    pub fn derive(
+        index: usize,
+        master_key: &ExtendedPrivateKey,
+        path: DerivationPath,
+    ) -> Option<Self> {
+        let account_key = master_key.derive_path(&path)?;
+        Some(Self {
+            account_key: Some(account_key.clone()),
+            ..Self::watch(index, account_key.public_key(), path)?
+        })
+    }
+
+    // A derived account of a locked wallet, whose addresses have public keys only
+    pub fn watch(index: usize, xpub: ExtendedPublicKey, path: DerivationPath) -> Option<Self> {
+        let purpose = Purpose::ALL
+            .into_iter()
+            .find(|purpose| path.0.first() == Some(&(purpose.number() | HARDENED)))?;
+        Some(Self {
+            index,
+            purpose: Some(purpose),
+            path: Some(path),
+            account_key: None,
+            xpub: Some(xpub),
+            keys: vec![],
+            utxos: HashMap::new(),
+            pending_utxos: HashMap::new(),
+            pending_spends: HashSet::new(),
+            pending_txids: HashSet::new(),
+        })
+    }
+
     pub fn random(index: usize) -> Self {
         Account::new(
             index,
@@ -42,138 +324,361 @@ impl Account {
                 .to_bytes()
                 .to_vec(),
         )
+        .unwrap()
     }
 
-    pub fn transfer(&self, to: &VerifyingKey, amount: u64) -> Result<Transaction, String> {
-        if amount > self.balance {
-            return Err("Transfer amount is larger than balance".to_owned());
-        }
-        let mut sum = 0;
-        let mut inputs = Vec::new();
-        let mut data_for_sign = Vec::new();
-        for ((txid, output_index), tx_out) in &self.utxos {
-            inputs.push(TxIn {
-                txid: *txid,
-                output_index: *output_index,
-                script_size: VarInt(0),
-                script_sig: Script(Vec::new()),
-                sequence: 0xffffffff,
-            });
-            data_for_sign.push((tx_out.script_pub_key.clone(), tx_out.amount));
-
-            sum += tx_out.amount;
-            if sum >= amount {
-                break;
+    // Key of address `index` of a chain, None for accounts that weren't derived
+    pub fn child_key(&self, chain: u32, index: u32) -> Option<Key> {
+        let (public_key, private_key) = match &self.account_key {
+            Some(account_key) => {
+                let key = account_key.derive_child(chain)?.derive_child(index)?;
+                (*key.private_key.verifying_key(), Some(key.private_key))
+            }
+            None => {
+                let xpub = self.xpub.as_ref()?.derive_child(chain)?;
+                (xpub.derive_child(index)?.public_key, None)
+            }
+        };
+        Some(Key {
+            public_key: Some(public_key),
+            private_key,
+            path: Some(DerivationPath(
+                [self.path.as_ref()?.0.clone(), vec![chain, index]].concat(),
+            )),
+            scripts: vec![self.purpose?.script(&public_key)?],
+            encrypted_key: None,
+        })
+    }
+
+    // Number of addresses derived on a chain
+    pub fn chain_len(&self, chain: u32) -> u32 {
+        self.keys
+            .iter()
+            .filter(|key| key.chain() == Some(chain))
+            .count() as u32
+    }
+
+    // Derives the next address of a chain
+    pub fn derive_key(&mut self, chain: u32) -> Option<Key> {
+        let key = self.child_key(chain, self.chain_len(chain))?;
+        self.keys.push(key.clone());
+        Some(key)
+    }
+
+    // Watch-only accounts track their outputs but can't spend them
+    pub fn is_watch_only(&self) -> bool {
+        self.keys.iter().all(Key::is_watch_only)
+    }
+
+    pub fn scripts(&self) -> impl Iterator<Item = &Script> {
+        self.keys.iter().flat_map(|key| &key.scripts)
+    }
+
+    // Latest address of the external chain, or the only key of an account that wasn't derived
+    pub fn receive_key(&self) -> &Key {
+        self.keys
+            .iter()
+            .rev()
+            .find(|key| key.chain() != Some(INTERNAL))
+            .expect("Account without keys")
+    }
+
+    pub fn change_key(&self) -> &Key {
+        self.keys
+            .iter()
+            .rev()
+            .find(|key| key.chain() != Some(EXTERNAL))
+            .expect("Account without keys")
+    }
+
+    // Tracks the pending transactions of the mempool in order, which would be included at
+    // `height`
+    pub fn apply_mempool(&mut self, transactions: &[Transaction], height: u64) {
+        let scripts: HashSet<_> = self.scripts().cloned().collect();
+        for tx in transactions {
+            let txid = tx.txid();
+            for tx_in in &tx.inputs {
+                let outpoint = (tx_in.txid, tx_in.output_index);
+                if self.utxos.contains_key(&outpoint) {
+                    self.pending_spends.insert(outpoint);
+                    self.pending_txids.insert(txid);
+                }
+                if self.pending_utxos.remove(&outpoint).is_some() {
+                    self.pending_txids.insert(txid);
+                }
+            }
+            for (i, tx_out) in tx.outputs.iter().enumerate() {
+                if scripts.contains(&tx_out.script_pub_key) {
+                    let utxo = Utxo::new(tx_out.clone(), height, false);
+                    self.pending_utxos.insert((txid, i as u32), utxo);
+                }
             }
         }
-        let mut outputs = Vec::new();
-        let locking_script = StandardScript::P2PK(to.to_sec1_bytes().to_vec()).into_script();
-        outputs.push(TxOut {
-            amount,
-            script_size: VarInt(locking_script.encode().len() as u64),
-            script_pub_key: locking_script,
+    }
+
+    // Outputs a transaction included at `height` can spend, including the pending change of
+    // transactions funded by the account
+    pub fn spendable_utxos(&self, height: u64) -> impl Iterator<Item = (&(TxID, u32), &Utxo)> {
+        let confirmed = self.utxos.iter().filter(move |(outpoint, utxo)| {
+            utxo.is_mature(height) && !self.pending_spends.contains(outpoint)
         });
-        if sum > amount {
-            let locking_script =
-                StandardScript::P2PK(self.public_key.to_sec1_bytes().to_vec()).into_script();
-            outputs.push(TxOut {
-                amount: sum - amount,
-                script_size: VarInt(locking_script.encode().len() as u64),
-                script_pub_key: locking_script,
-            });
-        }
+        let pending = self
+            .pending_utxos
+            .iter()
+            .filter(|((txid, _), _)| self.pending_txids.contains(txid));
+        confirmed.chain(pending)
+    }
 
-        let mut tx = Transaction {
-            version: 1,
-            flag: None,
-            inputs,
-            outputs,
-            witnesses: Vec::new(),
-            lock_time: 0,
-        };
+    pub fn spendable_balance(&self, height: u64) -> u64 {
+        self.spendable_utxos(height)
+            .map(|(_, utxo)| utxo.tx_out.amount)
+            .sum()
+    }
 
-        let mut signatures = Vec::new();
-        for (i, (script, amount)) in data_for_sign.into_iter().enumerate() {
-            let hash = SigHash::All.hash(&tx, i, &script, amount);
-            let signature = self.sign(hash.to_vec());
-            signatures.push(signature);
-            tx.inputs[i].script_sig =
-                UnlockingStandardScript::P2PK(signature, SigHash::All).into_script();
-            tx.inputs[i].script_size = VarInt(tx.inputs[i].script_sig.encode().len() as u64);
-        }
+    // Sum of confirmed outputs spendable by a transaction included at `height` that pending
+    // transactions don't spend
+    pub fn balance(&self, height: u64) -> u64 {
+        self.utxos
+            .iter()
+            .filter(|(outpoint, utxo)| {
+                utxo.is_mature(height) && !self.pending_spends.contains(outpoint)
+            })
+            .map(|(_, utxo)| utxo.tx_out.amount)
+            .sum()
+    }
+
+    // Sum of outputs of pending transactions, received or change
+    pub fn pending_balance(&self) -> u64 {
+        self.pending_utxos
+            .values()
+            .map(|utxo| utxo.tx_out.amount)
+            .sum()
+    }
+
+    // Sum of coinbase outputs that are not yet spendable at `height`
+    pub fn immature_balance(&self, height: u64) -> u64 {
+        self.utxos
+            .values()
+            .filter(|utxo| !utxo.is_mature(height))
+            .map(|utxo| utxo.tx_out.amount)
+            .sum()
+    }
 
-        Ok(tx)
+    // Pays to the receiving address of another account
+    pub fn transfer(&self, to: &Account, amount: u64, height: u64) -> Result<Transaction, String> {
+        self.transfer_to_script(to.receive_key().scripts[0].clone(), amount, height)
     }
 
-    pub fn sign(&self, msg: Vec<u8>) -> Signature {
-        self.private_key.sign_prehash_recoverable(&msg).unwrap().0
+    pub fn transfer_to_script(
+        &self,
+        locking_script: Script,
+        amount: u64,
+        height: u64,
+    ) -> Result<Transaction, String> {
+        TxBuilder::new(self)
+            .add_recipient(locking_script, amount)
+            .build(height)
     }
 }
 
 #[cfg(test)]
 mod tests {
 
+    use bitaekcoin::{bip39::Mnemonic, block::COINBASE_MATURITY, transaction::TxOut};
+
     use crate::PRIVATE_KEY;
 
     use super::*;
 
     #[test]
     fn test_miner_account() {
-        let account = Account::new(0, PRIVATE_KEY.to_vec());
+        let key = &Account::new(0, PRIVATE_KEY.to_vec()).unwrap().keys[0];
         assert!(
-            account.public_key.to_sec1_bytes().to_vec()
-                == account.private_key.verifying_key().to_sec1_bytes().to_vec()
+            key.public_key.unwrap().to_sec1_bytes().to_vec()
+                == key
+                    .private_key
+                    .as_ref()
+                    .unwrap()
+                    .verifying_key()
+                    .to_sec1_bytes()
+                    .to_vec()
         )
     }
 
     #[test]
     fn test_transfer() {
-        let mut sender = Account::new(0, PRIVATE_KEY.to_vec());
+        let mut sender = Account::new(0, PRIVATE_KEY.to_vec()).unwrap();
         let receiver = Account::random(1);
 
         let locking_script =
-            StandardScript::P2PK(sender.public_key.to_sec1_bytes().to_vec()).into_script();
+            StandardScript::P2PK(sender.keys[0].public_key.unwrap().to_sec1_bytes().to_vec())
+                .into_script();
         let utxos = {
             HashMap::from_iter([(
                 ([0; 32], 0),
-                TxOut {
-                    amount: 1,
-                    script_size: VarInt(locking_script.encode().len() as u64),
-                    script_pub_key: locking_script.clone(),
-                },
+                Utxo::new(
+                    TxOut {
+                        amount: 1,
+                        script_size: VarInt(locking_script.encode().len() as u64),
+                        script_pub_key: locking_script.clone(),
+                    },
+                    0,
+                    false,
+                ),
             )])
         };
-        sender.balance = 1;
         sender.utxos = utxos;
-        let tx = sender.transfer(&receiver.public_key, 1).unwrap();
+        let tx = sender.transfer(&receiver, 1, 1).unwrap();
         assert!(tx.validate(0, &tx.inputs[0].script_sig, &locking_script, 1));
     }
+
+    #[test]
+    fn test_immature_transfer() {
+        let mut sender = Account::new(0, PRIVATE_KEY.to_vec()).unwrap();
+        let receiver = Account::random(1);
+
+        let locking_script =
+            StandardScript::P2PK(sender.keys[0].public_key.unwrap().to_sec1_bytes().to_vec())
+                .into_script();
+        sender.utxos.insert(
+            ([0; 32], 0),
+            Utxo::new(
+                TxOut {
+                    amount: 1,
+                    script_size: VarInt(locking_script.encode().len() as u64),
+                    script_pub_key: locking_script,
+                },
+                0,
+                true,
+            ),
+        );
+        assert_eq!(sender.balance(1), 0);
+        assert_eq!(sender.immature_balance(1), 1);
+        assert!(sender.transfer(&receiver, 1, 1).is_err());
+
+        assert_eq!(sender.balance(COINBASE_MATURITY), 1);
+        assert_eq!(sender.immature_balance(COINBASE_MATURITY), 0);
+        assert!(sender.transfer(&receiver, 1, COINBASE_MATURITY).is_ok());
+    }
+
+    #[test]
+    fn test_account_addresses() {
+        let account = Account::random(0);
+        let json = AccountJson::new(account.clone(), 0);
+        // Every script but P2PK has an address
+        assert_eq!(json.addresses.len(), 3);
+        for (address, script) in json.addresses.iter().zip(&account.keys[0].scripts[1..]) {
+            let address = Address::parse(address, NETWORK).unwrap();
+            assert_eq!(address.script_pub_key(), *script);
+        }
+        assert!(json.addresses[2].starts_with("bcrt1q"));
+    }
+
+    #[test]
+    fn test_derived_account() {
+        let mnemonic: Mnemonic = "abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon about".parse().unwrap();
+        let master_key = ExtendedPrivateKey::new_master(&mnemonic.to_seed(""), NETWORK).unwrap();
+        let path = account_path(Purpose::NestedSegwit, 0);
+        let mut account = Account::derive(1, &master_key, path).unwrap();
+        account.derive_key(EXTERNAL).unwrap();
+        account.derive_key(INTERNAL).unwrap();
+        // Test vector of BIP49, whose testnet addresses are those of regtest
+        let json = AccountJson::new(account.clone(), 0);
+        assert_eq!(json.addresses[0], "2Mww8dCYPUpKHofjgcXcBCEGmniw9CoaiD2");
+        assert_eq!(json.path.unwrap(), "m/49'/1'/0'");
+
+        account.derive_key(EXTERNAL).unwrap();
+        let receive_path = account.receive_key().path.clone().unwrap();
+        assert_eq!(receive_path.to_string(), "m/49'/1'/0'/0/1");
+        let change_path = account.change_key().path.clone().unwrap();
+        assert_eq!(change_path.to_string(), "m/49'/1'/0'/1/0");
+        assert_eq!(account.chain_len(EXTERNAL), 2);
+
+        for (purpose, prefix) in [
+            (Purpose::Legacy, "m"),
+            (Purpose::NativeSegwit, "bcrt1q"),
+            (Purpose::Taproot, "bcrt1p"),
+        ] {
+            let mut account = Account::derive(1, &master_key, account_path(purpose, 0)).unwrap();
+            let key = account.derive_key(EXTERNAL).unwrap();
+            let address = Address::from_script(&key.scripts[0], NETWORK).unwrap();
+            assert!(address.to_string().starts_with(prefix));
+        }
+        assert!(Account::derive(1, &master_key, "m/0'/0'".parse().unwrap()).is_none());
+    }
+
+    #[test]
+    fn test_transfer_from_scripts() {
+        let mut sender = Account::random(0);
+        let receiver = Account::random(1);
+        for (i, script) in sender.keys[0].scripts.clone().into_iter().enumerate() {
+            let tx_out = TxOut {
+                amount: 1,
+                script_size: VarInt(script.encode().len() as u64),
+                script_pub_key: script,
+            };
+            sender
+                .utxos
+                .insert(([i as u8; 32], 0), Utxo::new(tx_out, 0, false));
+        }
+
+        let tx = sender.transfer(&receiver, 4, 1).unwrap();
+        assert!(tx.is_segwit());
+        assert_eq!(tx.inputs.len(), 4);
+        for (i, tx_in) in tx.inputs.iter().enumerate() {
+            let utxo = &sender.utxos[&(tx_in.txid, tx_in.output_index)];
+            let script = &utxo.tx_out.script_pub_key;
+            assert!(tx.validate(i, &tx_in.script_sig, script, 1));
+        }
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct AccountJson {
     pub index: usize,
-    pub public_key: Vec<u8>,
+    // Key of the receiving address
+    pub public_key: Option<Vec<u8>>,
+    pub path: Option<String>,
+    pub xpub: Option<String>,
+    pub watch_only: bool,
+    pub addresses: Vec<String>,
     pub balance: u64,
+    pub pending_balance: u64,
+    pub immature_balance: u64,
     pub utxos: Vec<TxOutJson>,
+    pub pending_utxos: Vec<TxOutJson>,
 }
 
-impl From<Account> for AccountJson {
-    fn from(value: Account) -> Self {
+impl AccountJson {
+    // Balances are reported as seen by a transaction included at `height`
+    pub fn new(account: Account, height: u64) -> Self {
         Self {
-            index: value.index,
-            public_key: value.public_key.to_sec1_bytes().to_vec(),
-            balance: value.balance,
-            utxos: value
+            index: account.index,
+            public_key: account
+                .receive_key()
+                .public_key
+                .map(|public_key| public_key.to_sec1_bytes().to_vec()),
+            path: account.path.as_ref().map(|path| path.to_string()),
+            xpub: account.xpub.as_ref().map(|xpub| xpub.to_string()),
+            watch_only: account.is_watch_only(),
+            addresses: account
+                .scripts()
+                .filter_map(|script| Address::from_script(script, NETWORK))
+                .map(|address| address.to_string())
+                .collect(),
+            balance: account.balance(height),
+            pending_balance: account.pending_balance(),
+            immature_balance: account.immature_balance(height),
+            utxos: account
                 .utxos
                 .iter()
-                .map(|((txid, output_index), tx_out)| TxOutJson {
-                    txid: *txid,
-                    output_index: *output_index,
-                    amount: tx_out.amount,
-                    script_size: tx_out.script_size.clone(),
-                    script_pub_key: tx_out.script_pub_key.clone(),
-                })
+                .filter(|(outpoint, _)| !account.pending_spends.contains(outpoint))
+                .map(|(outpoint, utxo)| TxOutJson::new(*outpoint, utxo))
+                .collect(),
+            pending_utxos: account
+                .pending_utxos
+                .iter()
+                .map(|(outpoint, utxo)| TxOutJson::new(*outpoint, utxo))
                 .collect(),
         }
     }
@@ -186,4 +691,32 @@ pub struct TxOutJson {
     pub amount: u64,
     pub script_size: VarInt,
     pub script_pub_key: Script,
+    pub address: Option<String>,
+    pub height: u64,
+    pub is_coinbase: bool,
+}
+
+impl TxOutJson {
+    pub fn new((txid, output_index): (TxID, u32), utxo: &Utxo) -> Self {
+        Self {
+            txid,
+            output_index,
+            amount: utxo.tx_out.amount,
+            script_size: utxo.tx_out.script_size.clone(),
+            script_pub_key: utxo.tx_out.script_pub_key.clone(),
+            address: Address::from_script(&utxo.tx_out.script_pub_key, NETWORK)
+                .map(|address| address.to_string()),
+            height: utxo.height,
+            is_coinbase: utxo.is_coinbase,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WalletInfoJson {
+    pub fingerprint: String,
+    pub gap_limit: usize,
+    pub encrypted: bool,
+    // Unix time the wallet locks at, None while it is locked or if it isn't encrypted
+    pub unlocked_until: Option<u32>,
 }
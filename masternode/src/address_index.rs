@@ -0,0 +1,183 @@
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+
+use bitaekcoin::{
+    block::Block,
+    script::Script,
+    transaction::{Transaction, TxID, TxOut},
+};
+
+use crate::undo::BlockUndo;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ScriptEventKind {
+    Funding,
+    Spending,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ScriptEvent {
+    pub kind: ScriptEventKind,
+    pub txid: TxID,
+    // Output index of a funding event, input index of a spending event
+    pub index: u32,
+    pub amount: u64,
+    // None while the transaction is in the mempool
+    pub height: Option<u64>,
+}
+
+// Funding and spending events of every script in the active chain, oldest first
+#[derive(Debug, Clone, Default)]
+pub struct AddressIndex {
+    pub events: HashMap<Script, Vec<ScriptEvent>>,
+}
+
+impl AddressIndex {
+    // `undo` holds the outputs spent by the block
+    pub fn connect_block(&mut self, block: &Block, undo: &BlockUndo) {
+        let height = Some(block.header.height);
+        let mut spent_utxos = undo.spent_utxos.iter();
+        for tx in &block.transactions {
+            let txid = tx.txid();
+            if !tx.is_coinbase() {
+                for i in 0..tx.inputs.len() {
+                    let tx_out = &spent_utxos.next().unwrap().tx_out;
+                    let event = spending(txid, i, tx_out, height);
+                    self.push(&tx_out.script_pub_key, event);
+                }
+            }
+            for (i, tx_out) in tx.outputs.iter().enumerate() {
+                self.push(&tx_out.script_pub_key, funding(txid, i, tx_out, height));
+            }
+        }
+    }
+
+    pub fn disconnect_block(&mut self, block: &Block, undo: &BlockUndo) {
+        let height = Some(block.header.height);
+        let spent_scripts = undo.spent_utxos.iter().map(|utxo| &utxo.tx_out);
+        let created_scripts = block.transactions.iter().flat_map(|tx| &tx.outputs);
+        for tx_out in spent_scripts.chain(created_scripts) {
+            let script = &tx_out.script_pub_key;
+            // Events of the tip are the latest of each script
+            if let Some(events) = self.events.get_mut(script) {
+                while events.last().is_some_and(|event| event.height == height) {
+                    events.pop();
+                }
+                if events.is_empty() {
+                    self.events.remove(script);
+                }
+            }
+        }
+    }
+
+    pub fn history(&self, script: &Script) -> Vec<ScriptEvent> {
+        self.events.get(script).cloned().unwrap_or_default()
+    }
+
+    fn push(&mut self, script: &Script, event: ScriptEvent) {
+        self.events.entry(script.clone()).or_default().push(event);
+    }
+}
+
+// Events of `script` in unconfirmed transactions, `prevout` looks up the confirmed outputs
+// they spend. Transactions may spend outputs of earlier transactions in the list.
+pub fn mempool_events(
+    script: &Script,
+    transactions: &[Transaction],
+    prevout: impl Fn(&(TxID, u32)) -> Option<TxOut>,
+) -> Vec<ScriptEvent> {
+    let mut events = Vec::new();
+    let mut created: HashMap<(TxID, u32), TxOut> = HashMap::new();
+    for tx in transactions {
+        let txid = tx.txid();
+        for (i, tx_in) in tx.inputs.iter().enumerate() {
+            let outpoint = (tx_in.txid, tx_in.output_index);
+            let tx_out = created
+                .get(&outpoint)
+                .cloned()
+                .or_else(|| prevout(&outpoint));
+            if let Some(tx_out) = tx_out.filter(|tx_out| tx_out.script_pub_key == *script) {
+                events.push(spending(txid, i, &tx_out, None));
+            }
+        }
+        for (i, tx_out) in tx.outputs.iter().enumerate() {
+            if tx_out.script_pub_key == *script {
+                events.push(funding(txid, i, tx_out, None));
+            }
+            created.insert((txid, i as u32), tx_out.clone());
+        }
+    }
+    events
+}
+
+fn funding(txid: TxID, i: usize, tx_out: &TxOut, height: Option<u64>) -> ScriptEvent {
+    ScriptEvent {
+        kind: ScriptEventKind::Funding,
+        txid,
+        index: i as u32,
+        amount: tx_out.amount,
+        height,
+    }
+}
+
+fn spending(txid: TxID, i: usize, tx_out: &TxOut, height: Option<u64>) -> ScriptEvent {
+    ScriptEvent {
+        kind: ScriptEventKind::Spending,
+        txid,
+        index: i as u32,
+        amount: tx_out.amount,
+        height,
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ScriptBalanceJson {
+    pub confirmed: u64,
+    // Net change by mempool transactions
+    pub unconfirmed: i64,
+}
+
+#[cfg(test)]
+mod tests {
+    use bitaekcoin::encode::VarInt;
+
+    use crate::{account::Account, utxo::Utxo};
+
+    use super::*;
+
+    #[test]
+    fn test_mempool_events() {
+        let mut sender = Account::random(0);
+        let mut receiver = Account::random(1);
+        let tx_out = TxOut {
+            amount: 5,
+            script_size: VarInt(0),
+            script_pub_key: sender.keys[0].scripts[0].clone(),
+        };
+        sender
+            .utxos
+            .insert(([0; 32], 0), Utxo::new(tx_out.clone(), 0, false));
+
+        // The second transaction spends the output of the first one
+        let tx1 = sender.transfer(&receiver, 5, 1).unwrap();
+        let utxo = Utxo::new(tx1.outputs[0].clone(), 0, false);
+        receiver.utxos.insert((tx1.txid(), 0), utxo);
+        let tx2 = receiver.transfer(&sender, 5, 1).unwrap();
+        let transactions = [tx1.clone(), tx2.clone()];
+        let prevout = |outpoint: &(TxID, u32)| (*outpoint == ([0; 32], 0)).then(|| tx_out.clone());
+
+        let events = mempool_events(&receiver.keys[0].scripts[0], &transactions, prevout);
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0].kind, ScriptEventKind::Funding);
+        assert_eq!(events[0].txid, tx1.txid());
+        assert_eq!(events[1].kind, ScriptEventKind::Spending);
+        assert_eq!(events[1].txid, tx2.txid());
+        assert!(events.iter().all(|event| event.height.is_none()));
+
+        let events = mempool_events(&sender.keys[0].scripts[0], &transactions, prevout);
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0].kind, ScriptEventKind::Spending);
+        assert_eq!(events[1].kind, ScriptEventKind::Funding);
+    }
+}
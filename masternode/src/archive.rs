@@ -0,0 +1,98 @@
+use std::collections::VecDeque;
+
+use serde::{Deserialize, Serialize};
+
+use bitaekcoin::{
+    bip32::ExtendedPrivateKey,
+    block::Block,
+    encode::{Decodable, DecodeError, Encodable},
+    utils::to_hex,
+};
+
+use crate::{
+    storage::{SeedRecord, WalletRecord},
+    NETWORK,
+};
+
+// Blocks of the active chain and the wallet, enough to rebuild a node by connecting the
+// blocks again and restoring the wallet
+#[derive(Debug, Clone)]
+pub struct ChainArchive {
+    // Starting with the genesis block
+    pub blocks: Vec<Block>,
+    // The keystore of an encrypted wallet, whose seed stays encrypted
+    pub seed: SeedRecord,
+    // Records of the accounts that weren't derived from the seed, the mining account included.
+    // Of every account of an encrypted wallet, as its seed can't derive them on import.
+    pub records: Vec<WalletRecord>,
+}
+
+// The part of the archive in the format of the wallet files
+#[derive(Serialize, Deserialize)]
+struct ArchivedWallet {
+    seed: SeedRecord,
+    records: Vec<WalletRecord>,
+}
+
+impl ChainArchive {
+    pub fn info(&self) -> ChainArchiveJson {
+        let fingerprint = match &self.seed {
+            SeedRecord::Plain(seed) => ExtendedPrivateKey::new_master(seed, NETWORK)
+                .map(|key| key.fingerprint())
+                .unwrap_or_default(),
+            SeedRecord::Encrypted(keystore) => keystore.fingerprint,
+        };
+        ChainArchiveJson {
+            height: (self.blocks.len() as u64).saturating_sub(1),
+            block_hash: to_hex(
+                &self
+                    .blocks
+                    .last()
+                    .map(|block| block.header.hash())
+                    .unwrap_or([0; 32]),
+            ),
+            fingerprint: to_hex(&fingerprint),
+        }
+    }
+}
+
+impl Encodable for ChainArchive {
+    fn encode(&self) -> Vec<u8> {
+        let mut bytes = self.blocks.encode();
+        let wallet = ArchivedWallet {
+            seed: self.seed.clone(),
+            records: self.records.clone(),
+        };
+        bytes.extend(serde_json::to_vec(&wallet).unwrap().encode());
+        bytes
+    }
+}
+
+impl Decodable for ChainArchive {
+    fn decode(bytes: &mut VecDeque<u8>) -> Result<Self, DecodeError> {
+        let mut blocks = Vec::<Block>::decode(bytes)?;
+        for (height, block) in blocks.iter_mut().enumerate() {
+            block.header.height = height as u64;
+        }
+        let wallet: ArchivedWallet =
+            serde_json::from_slice(&Vec::<u8>::decode(bytes)?).map_err(|_| DecodeError)?;
+        if let SeedRecord::Plain(seed) = &wallet.seed {
+            if ExtendedPrivateKey::new_master(seed, NETWORK).is_none() {
+                return Err(DecodeError);
+            }
+        }
+        Ok(Self {
+            blocks,
+            seed: wallet.seed,
+            records: wallet.records,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChainArchiveJson {
+    pub height: u64,
+    pub block_hash: String,
+    // Of the wallet seed
+    pub fingerprint: String,
+}
@@ -0,0 +1,21 @@
+use primitive_types::U256;
+
+use bitaekcoin::block::BlockHeader;
+
+#[derive(Debug, Clone)]
+pub struct BlockIndex {
+    pub header: BlockHeader,
+    // Total work of the chain ending at this block
+    pub chain_work: U256,
+    // Order of arrival, the earlier block wins between chains with equal work
+    pub sequence: u64,
+    pub invalid: bool,
+    // Whether the block and its undo data are stored, only the header is known otherwise
+    pub have_data: bool,
+}
+
+impl BlockIndex {
+    pub fn height(&self) -> u64 {
+        self.header.height
+    }
+}
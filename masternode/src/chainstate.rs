@@ -0,0 +1,119 @@
+use std::collections::HashMap;
+
+use bitaekcoin::{
+    block::Block,
+    script::Script,
+    transaction::{Transaction, TxID, TxOut},
+};
+
+use crate::{
+    account::{Account, Key, Purpose, WalletInfoJson},
+    address_index::ScriptEvent,
+    archive::ChainArchive,
+    history::WalletTx,
+    prune::PruneInfoJson,
+    snapshot::UtxoSnapshot,
+    tx_index::TxLocation,
+    utxo::Utxo,
+};
+
+// Chain and wallet state the node and the RPC server work against
+pub trait ChainState: Send + Sync {
+    fn block(&self, hash: &[u8; 32]) -> Option<Block>;
+
+    // Block of the active chain at `height`
+    fn block_at(&self, height: u64) -> Option<Block>;
+
+    // Blocks of the active chain in ascending order
+    fn blocks(&self) -> Vec<Block>;
+
+    fn latest_block(&self) -> Option<Block>;
+
+    fn latest_block_hash(&self) -> [u8; 32];
+
+    fn next_height(&self) -> u64;
+
+    fn median_time_past(&self) -> u32;
+
+    fn utxo(&self, outpoint: &(TxID, u32)) -> Option<Utxo>;
+
+    // Unspent outputs locked by `script`
+    fn utxos_by_script(&self, script: &Script) -> Vec<((TxID, u32), Utxo)>;
+
+    // Confirmed transaction by txid or wtxid, fails if transactions are not indexed
+    fn transaction(&self, id: &TxID) -> Result<Option<(Transaction, TxLocation)>, String>;
+
+    // Confirmed funding and spending events of `script`, oldest first. Fails if scripts are
+    // not indexed.
+    fn script_history(&self, script: &Script) -> Result<Vec<ScriptEvent>, String>;
+
+    // UTXO set as of the block at `height` of the active chain
+    fn utxo_snapshot(&self, height: u64) -> Result<UtxoSnapshot, String>;
+
+    // Re-reads the blocks of the active chain down to `depth` blocks below the tip, or all
+    // of them if `depth` is 0, and checks them at the given level:
+    // 0: block hashes and proof of work
+    // 1: merkle roots and that the blocks match the ones in memory
+    // 2: undo data matches the one in memory
+    // 3: undo data rolls the UTXO set back consistently
+    // 4: UTXO set rebuilt from genesis matches the current one, regardless of `depth`
+    fn verify_chain(&self, level: u32, depth: u64) -> Result<(), String>;
+
+    // Blocks of the active chain and the wallet, fails if blocks are pruned
+    fn chain_archive(&self) -> Result<ChainArchive, String>;
+
+    // Whether blocks are pruned and from which height of the active chain they are stored
+    fn prune_info(&self) -> PruneInfoJson;
+
+    // Checks that a transaction accepted now could be included in the next block after the
+    // pending transactions that created the `pending` outputs
+    fn validate_transaction(
+        &self,
+        tx: &Transaction,
+        pending: &HashMap<(TxID, u32), TxOut>,
+    ) -> Result<(), String>;
+
+    // Connects a block, reorganizing if it completes a chain with more work. Returns the
+    // transactions of disconnected blocks.
+    fn process_block(&mut self, block: Block) -> Result<Vec<Transaction>, String>;
+
+    // Marks a block and its descendants invalid and switches to the best chain without them.
+    // Returns the transactions of disconnected blocks.
+    fn invalidate_block(&mut self, hash: &[u8; 32]) -> Result<Vec<Transaction>, String>;
+
+    // Clears the invalid marks of a block, its ancestors and its descendants and switches to
+    // the best chain. Returns the transactions of disconnected blocks.
+    fn reconsider_block(&mut self, hash: &[u8; 32]) -> Result<Vec<Transaction>, String>;
+
+    // Fingerprint of the wallet seed and the gap limit of restoring it
+    fn wallet_info(&self) -> WalletInfoJson;
+
+    fn accounts(&self) -> Vec<Account>;
+
+    // Confirmed transactions of an account in the active chain, oldest first. Transactions of
+    // pruned blocks are left out.
+    fn wallet_history(&self, index: usize) -> Result<Vec<WalletTx>, String>;
+
+    fn create_account(&mut self, purpose: Purpose) -> Result<Account, String>;
+
+    // Derives the next address of the external or internal chain of an account
+    fn new_address(&mut self, index: usize, chain: u32) -> Result<Script, String>;
+
+    // Adds an account of a single key, which picks up the outputs the key already owns
+    fn import_key(&mut self, key: Key) -> Result<Account, String>;
+
+    // Replaces the derived accounts with the used ones of the wallet with this seed
+    fn restore_wallet(&mut self, seed: Vec<u8>) -> Result<Vec<Account>, String>;
+
+    // Encrypts the seed and the keys that weren't derived with a passphrase and locks the
+    // wallet
+    fn encrypt_wallet(&mut self, passphrase: &str) -> Result<(), String>;
+
+    // Decrypts the secrets of an encrypted wallet, `until` is only reported
+    fn unlock_wallet(&mut self, passphrase: &str, until: u32) -> Result<(), String>;
+
+    // Forgets the decrypted secrets, accounts can't sign or be created until unlocked again
+    fn lock_wallet(&mut self) -> Result<(), String>;
+
+    fn change_passphrase(&mut self, old: &str, new: &str) -> Result<(), String>;
+}
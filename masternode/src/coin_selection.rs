@@ -0,0 +1,405 @@
+use std::cmp::Reverse;
+
+use bitaekcoin::{
+    script::{Script, StandardScript},
+    transaction::TxID,
+};
+use rand::{seq::SliceRandom, Rng};
+
+use crate::{tx_builder::DUST_LIMIT, utxo::Utxo};
+
+// Branches searched by Branch-and-Bound before giving up, as in Bitcoin Core
+pub const BNB_MAX_TRIES: usize = 100000;
+const KNAPSACK_ITERATIONS: usize = 1000;
+
+// An output that can be spent, valued net of the fee of the input spending it
+#[derive(Debug, Clone)]
+pub struct Coin {
+    pub outpoint: (TxID, u32),
+    pub utxo: Utxo,
+    pub input_vsize: u64,
+    pub effective_value: u64,
+}
+
+impl Coin {
+    // None if the account can't sign the script of the output
+    pub fn new(outpoint: (TxID, u32), utxo: Utxo, fee_rate: u64) -> Option<Self> {
+        let input_vsize = input_vsize(&utxo.tx_out.script_pub_key)?;
+        let effective_value = input_vsize
+            .checked_mul(fee_rate)
+            .map_or(0, |fee| utxo.tx_out.amount.saturating_sub(fee));
+        Some(Self {
+            outpoint,
+            utxo,
+            input_vsize,
+            effective_value,
+        })
+    }
+}
+
+// Virtual size of an input with a 72 byte signature, the largest DER encoding
+pub fn input_vsize(script: &Script) -> Option<u64> {
+    match script.to_standard()? {
+        StandardScript::P2PK(_) => Some(114),
+        StandardScript::P2PKH(_) => Some(148),
+        // Nested P2WPKH
+        StandardScript::P2SH(_) => Some(91),
+        StandardScript::P2WPKH(_) => Some(68),
+        _ => None,
+    }
+}
+
+// Picks coins whose effective values sum to at least `target`. Change is only worth making if
+// the excess over the target is larger than `cost_of_change`, the fee of creating and spending it.
+pub trait CoinSelection {
+    fn select(
+        &self,
+        coins: &[Coin],
+        target: u64,
+        cost_of_change: u64,
+    ) -> Result<Vec<usize>, String>;
+}
+
+impl CoinSelection for Box<dyn CoinSelection> {
+    fn select(
+        &self,
+        coins: &[Coin],
+        target: u64,
+        cost_of_change: u64,
+    ) -> Result<Vec<usize>, String> {
+        self.as_ref().select(coins, target, cost_of_change)
+    }
+}
+
+// Searches for a changeless selection whose excess over the target is at most the cost of
+// change, preferring the smallest excess
+pub struct BranchAndBound {
+    pub max_tries: usize,
+}
+
+impl Default for BranchAndBound {
+    fn default() -> Self {
+        Self {
+            max_tries: BNB_MAX_TRIES,
+        }
+    }
+}
+
+impl CoinSelection for BranchAndBound {
+    fn select(
+        &self,
+        coins: &[Coin],
+        target: u64,
+        cost_of_change: u64,
+    ) -> Result<Vec<usize>, String> {
+        let mut pool = positive(coins);
+        pool.sort_by_key(|i| Reverse(coins[*i].effective_value));
+        let values: Vec<_> = pool.iter().map(|i| coins[*i].effective_value).collect();
+
+        // Decisions to include or omit the first coins of the pool
+        let mut included: Vec<bool> = Vec::new();
+        let mut value = 0;
+        // Sum of the coins without a decision yet
+        let mut available: u64 = values.iter().sum();
+        let mut best: Option<(u64, Vec<bool>)> = None;
+        for _ in 0..self.max_tries {
+            let backtrack = if value + available < target || value > target + cost_of_change {
+                true
+            } else if value >= target {
+                let excess = value - target;
+                if best.as_ref().is_none_or(|(best, _)| excess < *best) {
+                    best = Some((excess, included.clone()));
+                }
+                if excess == 0 {
+                    break;
+                }
+                true
+            } else {
+                false
+            };
+
+            if backtrack {
+                while included.last() == Some(&false) {
+                    included.pop();
+                    available += values[included.len()];
+                }
+                match included.last_mut() {
+                    Some(last) => *last = false,
+                    None => break,
+                }
+                value -= values[included.len() - 1];
+            } else {
+                let i = included.len();
+                available -= values[i];
+                value += values[i];
+                included.push(true);
+            }
+        }
+
+        let (_, included) = best.ok_or("No changeless selection of outputs")?;
+        Ok(pool
+            .into_iter()
+            .zip(included)
+            .filter_map(|(i, included)| included.then_some(i))
+            .collect())
+    }
+}
+
+// Selection of Bitcoin Core before Branch-and-Bound, either a single coin larger than the target
+// or the best of random subsets of the smaller coins
+pub struct Knapsack;
+
+impl CoinSelection for Knapsack {
+    fn select(&self, coins: &[Coin], target: u64, _: u64) -> Result<Vec<usize>, String> {
+        let mut pool = positive(coins);
+        pool.shuffle(&mut rand::thread_rng());
+
+        let mut lowest_larger: Option<usize> = None;
+        let mut smaller = Vec::new();
+        let mut smaller_value = 0;
+        for i in pool {
+            let value = coins[i].effective_value;
+            if value == target {
+                return Ok(vec![i]);
+            } else if value < target + DUST_LIMIT {
+                smaller.push(i);
+                smaller_value += value;
+            } else if lowest_larger.is_none_or(|larger| value < coins[larger].effective_value) {
+                lowest_larger = Some(i);
+            }
+        }
+        if smaller_value == target {
+            return Ok(smaller);
+        }
+        if smaller_value < target {
+            return lowest_larger
+                .map(|i| vec![i])
+                .ok_or_else(|| "Spendable balance can't pay the fee".to_owned());
+        }
+
+        smaller.sort_by_key(|i| Reverse(coins[*i].effective_value));
+        let values: Vec<_> = smaller.iter().map(|i| coins[*i].effective_value).collect();
+        let (best, best_value) = best_subset(&values, smaller_value, target);
+        if let Some(larger) = lowest_larger {
+            if best_value != target && coins[larger].effective_value <= best_value {
+                return Ok(vec![larger]);
+            }
+        }
+        Ok(smaller
+            .into_iter()
+            .zip(best)
+            .filter_map(|(i, included)| included.then_some(i))
+            .collect())
+    }
+}
+
+// Stochastic approximation of the subset with the smallest sum reaching the target
+fn best_subset(values: &[u64], total: u64, target: u64) -> (Vec<bool>, u64) {
+    let mut rng = rand::thread_rng();
+    let mut best = vec![true; values.len()];
+    let mut best_value = total;
+    for _ in 0..KNAPSACK_ITERATIONS {
+        if best_value == target {
+            break;
+        }
+        let mut included = vec![false; values.len()];
+        let mut value = 0;
+        let mut reached = false;
+        // Coins are included randomly first and then all of the remaining ones in order
+        for pass in 0..2 {
+            if reached {
+                break;
+            }
+            for i in 0..values.len() {
+                let include = match pass {
+                    0 => rng.gen_bool(0.5),
+                    _ => !included[i],
+                };
+                if !include {
+                    continue;
+                }
+                value += values[i];
+                included[i] = true;
+                if value >= target {
+                    reached = true;
+                    if value < best_value {
+                        best_value = value;
+                        best = included.clone();
+                    }
+                    value -= values[i];
+                    included[i] = false;
+                }
+            }
+        }
+    }
+    (best, best_value)
+}
+
+// Draws random coins until they can pay the target and a change output
+pub struct SingleRandomDraw;
+
+impl CoinSelection for SingleRandomDraw {
+    fn select(
+        &self,
+        coins: &[Coin],
+        target: u64,
+        cost_of_change: u64,
+    ) -> Result<Vec<usize>, String> {
+        let mut pool = positive(coins);
+        pool.shuffle(&mut rand::thread_rng());
+        accumulate(coins, pool, target, target + cost_of_change + DUST_LIMIT)
+    }
+}
+
+pub struct LargestFirst;
+
+impl CoinSelection for LargestFirst {
+    fn select(&self, coins: &[Coin], target: u64, _: u64) -> Result<Vec<usize>, String> {
+        let mut pool = positive(coins);
+        pool.sort_by_key(|i| Reverse(coins[*i].effective_value));
+        accumulate(coins, pool, target, target)
+    }
+}
+
+// Coin control, spending exactly the given outputs
+pub struct Manual(pub Vec<(TxID, u32)>);
+
+impl CoinSelection for Manual {
+    fn select(&self, coins: &[Coin], target: u64, _: u64) -> Result<Vec<usize>, String> {
+        let mut selected = Vec::new();
+        let mut value = 0;
+        for outpoint in &self.0 {
+            let i = coins
+                .iter()
+                .position(|coin| coin.outpoint == *outpoint)
+                .ok_or("Selected output isn't spendable by the account")?;
+            if selected.contains(&i) {
+                return Err("Output selected twice".to_owned());
+            }
+            if coins[i].effective_value == 0 {
+                return Err("Selected output can't pay the fee of spending it".to_owned());
+            }
+            selected.push(i);
+            value += coins[i].effective_value;
+        }
+        if value < target {
+            return Err("Selected outputs can't pay the amount and the fee".to_owned());
+        }
+        Ok(selected)
+    }
+}
+
+// Coins worth spending at the fee rate
+fn positive(coins: &[Coin]) -> Vec<usize> {
+    (0..coins.len())
+        .filter(|i| coins[*i].effective_value > 0)
+        .collect()
+}
+
+// Takes coins in order until reaching `preferred`, settling for `target` if they run out
+fn accumulate(
+    coins: &[Coin],
+    pool: Vec<usize>,
+    target: u64,
+    preferred: u64,
+) -> Result<Vec<usize>, String> {
+    let mut selected = Vec::new();
+    let mut value = 0;
+    for i in pool {
+        selected.push(i);
+        value += coins[i].effective_value;
+        if value >= preferred {
+            break;
+        }
+    }
+    if value < target {
+        return Err("Spendable balance can't pay the fee".to_owned());
+    }
+    Ok(selected)
+}
+
+#[cfg(test)]
+mod tests {
+    use bitaekcoin::{encode::VarInt, transaction::TxOut};
+
+    use super::*;
+
+    fn coins(values: &[u64]) -> Vec<Coin> {
+        let script = StandardScript::P2WPKH(vec![0; 20]).into_script();
+        values
+            .iter()
+            .enumerate()
+            .map(|(i, value)| {
+                let tx_out = TxOut {
+                    amount: *value,
+                    script_size: VarInt(22),
+                    script_pub_key: script.clone(),
+                };
+                Coin::new(([i as u8; 32], 0), Utxo::new(tx_out, 0, false), 0).unwrap()
+            })
+            .collect()
+    }
+
+    fn value(coins: &[Coin], selected: &[usize]) -> u64 {
+        selected.iter().map(|i| coins[*i].effective_value).sum()
+    }
+
+    #[test]
+    fn test_effective_value() {
+        let coin = &coins(&[1000])[0];
+        let coin = Coin::new(coin.outpoint, coin.utxo.clone(), 10).unwrap();
+        assert_eq!(coin.input_vsize, 68);
+        assert_eq!(coin.effective_value, 1000 - 680);
+        let coin = Coin::new(coin.outpoint, coin.utxo, 20).unwrap();
+        assert_eq!(coin.effective_value, 0);
+    }
+
+    #[test]
+    fn test_branch_and_bound() {
+        let coins = coins(&[1000, 2000, 3000, 4000, 5000, 0]);
+        let bnb = BranchAndBound::default();
+        for target in [1000, 6000, 7000, 15000] {
+            let selected = bnb.select(&coins, target, 0).unwrap();
+            assert_eq!(value(&coins, &selected), target);
+        }
+        let selected = bnb.select(&coins, 1500, 500).unwrap();
+        assert_eq!(value(&coins, &selected), 2000);
+        assert!(bnb.select(&coins, 1500, 499).is_err());
+        assert!(bnb.select(&coins, 15001, 1000).is_err());
+        let bnb = BranchAndBound { max_tries: 1 };
+        assert!(bnb.select(&coins, 6000, 0).is_err());
+    }
+
+    #[test]
+    fn test_fallbacks() {
+        let coins = coins(&[1000, 2000, 3000, 50000]);
+        let selected = Knapsack.select(&coins, 5000, 0).unwrap();
+        assert_eq!(value(&coins, &selected), 5000);
+        // A single larger coin beats smaller ones that can't reach the target
+        let selected = Knapsack.select(&coins, 7000, 0).unwrap();
+        assert_eq!(selected, vec![3]);
+        assert!(Knapsack.select(&coins, 56001, 0).is_err());
+
+        for _ in 0..10 {
+            let selected = SingleRandomDraw.select(&coins, 3000, 0).unwrap();
+            assert!(value(&coins, &selected) >= 3000);
+        }
+        assert!(SingleRandomDraw.select(&coins, 56001, 0).is_err());
+
+        assert_eq!(LargestFirst.select(&coins, 51000, 0).unwrap(), vec![3, 2]);
+    }
+
+    #[test]
+    fn test_manual() {
+        let coins = coins(&[1000, 2000, 3000]);
+        let selection = Manual(vec![([2; 32], 0), ([0; 32], 0)]);
+        assert_eq!(selection.select(&coins, 4000, 0).unwrap(), vec![2, 0]);
+        assert!(selection.select(&coins, 4001, 0).is_err());
+        assert!(Manual(vec![([3; 32], 0)]).select(&coins, 0, 0).is_err());
+        assert!(Manual(vec![([0; 32], 0); 2]).select(&coins, 0, 0).is_err());
+
+        // Outputs worth less than the fee of spending them
+        let coins = vec![Coin::new(coins[0].outpoint, coins[0].utxo.clone(), 20).unwrap()];
+        assert!(Manual(vec![([0; 32], 0)]).select(&coins, 0, 0).is_err());
+    }
+}
@@ -1,84 +1,1020 @@
-use std::collections::HashMap;
+use std::{
+    collections::{HashMap, HashSet},
+    io,
+    path::Path,
+    time::{Duration, Instant},
+};
+
+use k256::ecdsa::{SigningKey, VerifyingKey};
 
 use bitaekcoin::{
-    block::Block,
-    script::StandardScript,
-    transaction::{TxID, TxOut},
+    bip32::{DerivationPath, ExtendedPrivateKey},
+    block::{median_time_past, Block, BlockHeader, MEDIAN_TIME_SPAN},
+    encode::Encodable,
+    hash::merkle_root,
+    script::Script,
+    transaction::{Transaction, TxID, TxOut},
+    utils::to_hex,
+};
+
+use crate::{
+    account::{
+        account_path, Account, Key, Purpose, WalletInfoJson, DEFAULT_GAP_LIMIT, EXTERNAL, INTERNAL,
+    },
+    address_index::{AddressIndex, ScriptEvent},
+    archive::ChainArchive,
+    block_index::BlockIndex,
+    chainstate::ChainState,
+    history::WalletTx,
+    keystore::{decrypt_secret, encrypt_secret, Keystore, WalletKey},
+    node::coinbase_script,
+    orphan::OrphanPool,
+    prune::{PruneInfoJson, PruneMode},
+    snapshot::{rollback, UtxoSnapshot},
+    storage::{
+        ChainStateRecord, FileStorage, IndexRecord, MemoryStorage, SeedRecord, Storage,
+        WalletRecord,
+    },
+    time::adjusted_time,
+    tx_index::{TxIndex, TxLocation},
+    undo::BlockUndo,
+    utxo::Utxo,
+    BITS, MINING_REWARD, NETWORK, PRIVATE_KEY,
 };
 
-use crate::{account::Account, PRIVATE_KEY};
+// Blocks and the block index are written right away, the UTXO set at most this often.
+// Blocks connected after the last flush are connected again on startup.
+const FLUSH_INTERVAL: Duration = Duration::from_secs(10);
+
+// Chain verification on startup, see `ChainState::verify_chain`
+pub const DEFAULT_CHECK_LEVEL: u32 = 3;
+pub const DEFAULT_CHECK_BLOCKS: u64 = 6;
 
 pub struct DB {
     pub accounts: Vec<Account>,
-    pub blocks: Vec<Block>,
-    pub utxos: HashMap<(TxID, u32), TxOut>,
+    pub block_index: HashMap<[u8; 32], BlockIndex>,
+    pub blocks: HashMap<[u8; 32], Block>,
+    // Hashes of the blocks in the most-work chain, indexed by height
+    pub active_chain: Vec<[u8; 32]>,
+    // Spent outputs of each connected block to restore them on disconnection
+    pub undo: HashMap<[u8; 32], BlockUndo>,
+    pub utxos: HashMap<(TxID, u32), Utxo>,
+    // Unspent outputs by their locking script
+    pub script_index: HashMap<Script, HashSet<(TxID, u32)>>,
+    // Index of the account each local script belongs to
+    pub script_owners: HashMap<Script, usize>,
+    // Maintained only if enabled
+    pub tx_index: Option<TxIndex>,
+    pub address_index: Option<AddressIndex>,
+    pub prune: Option<PruneMode>,
+    pub orphans: OrphanPool,
+    // Accounts are derived from the wallet seed, see `account_path`. None while the wallet is
+    // locked.
+    pub seed: Option<Vec<u8>>,
+    pub gap_limit: usize,
+    // Set once the wallet is encrypted, the wallet key only while it is unlocked
+    pub keystore: Option<Keystore>,
+    pub wallet_key: Option<WalletKey>,
+    pub unlocked_until: Option<u32>,
+    pub storage: Box<dyn Storage>,
+    last_flush: Instant,
 }
 
 impl DB {
     pub fn new() -> Self {
-        let master = Account::new(0, PRIVATE_KEY.to_vec());
-        Self {
-            accounts: vec![master],
-            blocks: vec![],
+        Self::with_storage(Box::new(MemoryStorage::default()))
+    }
+
+    // Empty node state backed by `storage`, see `load` to restore the state it holds
+    pub fn with_storage(storage: Box<dyn Storage>) -> Self {
+        let master = Account::new(0, PRIVATE_KEY.to_vec()).expect("Invalid mining key");
+        let mut db = Self {
+            accounts: vec![],
+            block_index: HashMap::new(),
+            blocks: HashMap::new(),
+            active_chain: vec![],
+            undo: HashMap::new(),
             utxos: HashMap::new(),
+            script_index: HashMap::new(),
+            script_owners: HashMap::new(),
+            tx_index: None,
+            address_index: None,
+            prune: None,
+            orphans: OrphanPool::default(),
+            seed: Some(rand::random::<[u8; 32]>().to_vec()),
+            gap_limit: DEFAULT_GAP_LIMIT,
+            keystore: None,
+            wallet_key: None,
+            unlocked_until: None,
+            storage,
+            last_flush: Instant::now(),
+        };
+        db.add_account(master);
+        db
+    }
+
+    // Loads the node state from a data directory, creating it if it doesn't exist
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, String> {
+        let storage = FileStorage::open(path).map_err(|err| err.to_string())?;
+        Self::load(Box::new(storage))
+    }
+
+    pub fn load(storage: Box<dyn Storage>) -> Result<Self, String> {
+        let mut db = Self::with_storage(storage);
+        // Encrypted wallets start locked
+        match db.storage.read_seed().map_err(|err| err.to_string())? {
+            Some(SeedRecord::Plain(seed)) => db.seed = Some(seed),
+            Some(SeedRecord::Encrypted(keystore)) => {
+                db.seed = None;
+                db.keystore = Some(keystore);
+            }
+            None => db
+                .storage
+                .write_seed(&SeedRecord::Plain(db.seed.clone().unwrap()))
+                .map_err(|err| err.to_string())?,
+        }
+        match db.storage.read_wallet().map_err(|err| err.to_string())? {
+            Some(records) => {
+                db.accounts.clear();
+                db.script_owners.clear();
+                for account in db.accounts_from_records(records)? {
+                    db.add_account(account);
+                }
+            }
+            None => db
+                .storage
+                .write_wallet(&wallet_records(&db.accounts))
+                .map_err(|err| err.to_string())?,
+        }
+
+        let records = db.storage.read_index().map_err(|err| err.to_string())?;
+        // Pruned blocks are recorded again without data after their files are removed, so
+        // only the latest record of a block tells whether it is stored
+        let have_data: HashMap<_, _> = records
+            .iter()
+            .map(|record| (record.header.hash(), record.have_data))
+            .collect();
+        for record in records {
+            let hash = record.header.hash();
+            if let Some(index) = db.block_index.get_mut(&hash) {
+                index.invalid = record.invalid;
+                index.have_data = record.have_data;
+                continue;
+            }
+            let parent_work = match db.block_index.get(&record.header.prev_block_hash) {
+                Some(parent) => parent.chain_work,
+                None => Default::default(),
+            };
+            if have_data[&hash] {
+                let block = db
+                    .storage
+                    .read_block(&hash, record.header.height)
+                    .map_err(|err| err.to_string())?;
+                db.blocks.insert(hash, block);
+            }
+            db.block_index.insert(
+                hash,
+                BlockIndex {
+                    chain_work: parent_work + record.header.work(),
+                    header: record.header,
+                    sequence: record.sequence,
+                    invalid: record.invalid,
+                    have_data: record.have_data,
+                },
+            );
+        }
+
+        if let Some(chainstate) = db
+            .storage
+            .read_chainstate()
+            .map_err(|err| err.to_string())?
+            // Flushed before any block was connected
+            .filter(|chainstate| chainstate.tip != [0; 32])
+        {
+            let mut hash = chainstate.tip;
+            while let Some(index) = db.block_index.get(&hash) {
+                db.active_chain.push(hash);
+                if index.height() == 0 {
+                    break;
+                }
+                hash = index.header.prev_block_hash;
+            }
+            db.active_chain.reverse();
+            if db.active_chain.last() != Some(&chainstate.tip) || !db.is_active(&chainstate.tip) {
+                return Err("Chain state does not match the block index".to_owned());
+            }
+            for hash in &db.active_chain {
+                if db.block_index[hash].have_data {
+                    let undo = db.storage.read_undo(hash).map_err(|err| err.to_string())?;
+                    db.undo.insert(*hash, undo);
+                }
+            }
+            for (outpoint, utxo) in chainstate.utxos {
+                db.add_utxo(outpoint, utxo);
+            }
+            db.verify_tip()?;
+        }
+
+        db.activate_best_chain()?;
+        db.flush()?;
+        Ok(db)
+    }
+
+    // Indexes the transactions of the active chain and keeps the index up to date
+    pub fn enable_tx_index(&mut self) {
+        let mut tx_index = TxIndex::default();
+        for block in self
+            .active_chain
+            .iter()
+            .filter_map(|hash| self.blocks.get(hash))
+        {
+            tx_index.connect_block(block);
+        }
+        self.tx_index = Some(tx_index);
+    }
+
+    // Records the history of every script in the active chain and keeps it up to date
+    pub fn enable_address_index(&mut self) {
+        let mut address_index = AddressIndex::default();
+        for hash in &self.active_chain {
+            if let (Some(block), Some(undo)) = (self.blocks.get(hash), self.undo.get(hash)) {
+                address_index.connect_block(block, undo);
+            }
+        }
+        self.address_index = Some(address_index);
+    }
+
+    // Starts a fresh node from a snapshot. Blocks up to the snapshot are known by their
+    // headers only and can't be disconnected.
+    pub fn load_snapshot(&mut self, snapshot: UtxoSnapshot) -> Result<(), String> {
+        if !self.block_index.is_empty() {
+            return Err("Snapshot can only be loaded into an empty node".to_owned());
+        }
+        let mut prev_block_hash = [0; 32];
+        let mut chain_work = Default::default();
+        let mut block_index = Vec::new();
+        for (height, header) in snapshot.headers.iter().enumerate() {
+            if header.bits != BITS
+                || !header.validate()
+                || header.prev_block_hash != prev_block_hash
+            {
+                return Err("Invalid snapshot headers".to_owned());
+            }
+            chain_work += header.work();
+            prev_block_hash = header.hash();
+            let mut header = header.clone();
+            header.height = height as u64;
+            block_index.push(BlockIndex {
+                header,
+                chain_work,
+                sequence: height as u64,
+                invalid: false,
+                have_data: false,
+            });
+        }
+        if block_index.is_empty() {
+            return Err("Snapshot has no headers".to_owned());
+        }
+
+        for index in block_index {
+            let hash = index.header.hash();
+            self.block_index.insert(hash, index);
+            self.active_chain.push(hash);
+            self.write_index(&hash)?;
+        }
+        for (outpoint, utxo) in snapshot.utxos {
+            self.add_utxo(outpoint, utxo);
+        }
+        self.flush()
+    }
+
+    pub fn master_key(&self) -> Result<ExtendedPrivateKey, String> {
+        let seed = self.seed.as_ref().ok_or("Wallet is locked")?;
+        Ok(ExtendedPrivateKey::new_master(seed, NETWORK).expect("Invalid wallet seed"))
+    }
+
+    // Replaces the wallet seed and the derived accounts with the ones derived from `seed`
+    // that appear on the chain, the mining account and imported keys stay. Accounts of each
+    // purpose are scanned until one without used addresses. Fails if a replaced account
+    // still holds outputs.
+    pub fn restore_seed(&mut self, seed: Vec<u8>) -> Result<(), String> {
+        let master_key = match ExtendedPrivateKey::new_master(&seed, NETWORK) {
+            Some(master_key) if (16..=64).contains(&seed.len()) => master_key,
+            _ => return Err("Invalid seed".to_owned()),
+        };
+        let record = match &self.keystore {
+            Some(keystore) => {
+                let wallet_key = self.wallet_key.ok_or("Wallet is locked")?;
+                SeedRecord::Encrypted(Keystore {
+                    seed: encrypt_secret(&wallet_key, &seed),
+                    fingerprint: master_key.fingerprint(),
+                    ..keystore.clone()
+                })
+            }
+            None => SeedRecord::Plain(seed.clone()),
+        };
+        let (mut accounts, replaced): (Vec<_>, Vec<_>) = self
+            .accounts
+            .iter()
+            .cloned()
+            .partition(|account| account.purpose.is_none());
+        if Some(&seed) != self.seed.as_ref()
+            && replaced.iter().any(|account| !account.utxos.is_empty())
+        {
+            return Err("Accounts of the current wallet still hold outputs".to_owned());
+        }
+
+        let used = self.used_scripts();
+        for purpose in Purpose::ALL {
+            for number in 0.. {
+                let path = account_path(purpose, number);
+                match self.discover_account(accounts.len(), &master_key, path, &used)? {
+                    Some(account) => accounts.push(account),
+                    None => break,
+                }
+            }
+        }
+
+        self.write(|storage| storage.write_seed(&record))?;
+        if let SeedRecord::Encrypted(keystore) = record {
+            self.keystore = Some(keystore);
+        }
+        self.seed = Some(seed);
+        self.accounts.clear();
+        self.script_owners.clear();
+        for (index, account) in accounts.into_iter().enumerate() {
+            self.add_account(Account { index, ..account });
+        }
+        self.write(|storage| storage.write_wallet(&wallet_records(&self.accounts)))
+    }
+
+    // Derives the addresses of both chains of an account up to the last used one, looking
+    // `gap_limit` addresses past it. None if no address was used.
+    fn discover_account(
+        &self,
+        index: usize,
+        master_key: &ExtendedPrivateKey,
+        path: DerivationPath,
+        used_scripts: &HashSet<Script>,
+    ) -> Result<Option<Account>, String> {
+        let mut account =
+            Account::derive(index, master_key, path).ok_or("Failed to derive the account key")?;
+        let mut used = false;
+        for chain in [EXTERNAL, INTERNAL] {
+            let mut len = 0;
+            let mut i = 0;
+            while i < len + self.gap_limit as u32 {
+                let key = account
+                    .child_key(chain, i)
+                    .ok_or("Failed to derive the address key")?;
+                if key
+                    .scripts
+                    .iter()
+                    .any(|script| self.is_used(used_scripts, script))
+                {
+                    len = i + 1;
+                }
+                i += 1;
+            }
+            used |= len > 0;
+            // Every account keeps an address on both chains
+            for _ in 0..len.max(1) {
+                account
+                    .derive_key(chain)
+                    .ok_or("Failed to derive the address key")?;
+            }
+        }
+        Ok(used.then_some(account))
+    }
+
+    // Keys of derived accounts are derived again from the seed, or from the account xpub while
+    // the wallet is locked. Other keys are stored.
+    fn accounts_from_records(&self, records: Vec<WalletRecord>) -> Result<Vec<Account>, String> {
+        let master_key = self.master_key().ok();
+        let mut accounts: Vec<Account> = Vec::new();
+        for record in records {
+            let path = record
+                .path
+                .as_ref()
+                .and_then(|path| path.parse::<DerivationPath>().ok())
+                .filter(|path| path.0.len() == 5);
+            let account = match accounts.last_mut() {
+                Some(account) if account.index == record.index => account,
+                _ => {
+                    let derived = path.as_ref().and_then(|path| {
+                        let path = DerivationPath(path.0[..3].to_vec());
+                        match &master_key {
+                            Some(master_key) => Account::derive(record.index, master_key, path),
+                            None => {
+                                let xpub = record.xpub.as_ref()?.parse().ok()?;
+                                Account::watch(record.index, xpub, path)
+                            }
+                        }
+                    });
+                    accounts.push(match derived {
+                        Some(account) => account,
+                        // Keys of other derivation paths are kept as they are
+                        None => Account::from_key(record.index, record_key(record.clone())?),
+                    });
+                    accounts.last_mut().unwrap()
+                }
+            };
+            if account.purpose.is_none() {
+                continue;
+            }
+            let key = path
+                .and_then(|path| account.derive_key(path.0[3]))
+                .ok_or("Failed to derive the address key")?;
+            if key.path.map(|path| path.to_string()) != record.path {
+                return Err("Wallet does not match the seed".to_owned());
+            }
         }
+        Ok(accounts)
     }
 
-    pub fn push_block(&mut self, block: Block) {
+    // Scripts that hold outputs or received any in the stored blocks of the active chain,
+    // including the ones spent since
+    fn used_scripts(&self) -> HashSet<Script> {
+        let mut used: HashSet<_> = self.script_index.keys().cloned().collect();
+        for hash in &self.active_chain {
+            if let Some(block) = self.blocks.get(hash) {
+                let outputs = block.transactions.iter().flat_map(|tx| &tx.outputs);
+                used.extend(outputs.map(|tx_out| tx_out.script_pub_key.clone()));
+            }
+            if let Some(undo) = self.undo.get(hash) {
+                let spent = undo.spent_utxos.iter();
+                used.extend(spent.map(|utxo| utxo.tx_out.script_pub_key.clone()));
+            }
+        }
+        used
+    }
+
+    // Whether a script is one of the `used` ones or, if the address index is enabled, it
+    // appears in pruned blocks
+    fn is_used(&self, used: &HashSet<Script>, script: &Script) -> bool {
+        used.contains(script)
+            || self
+                .address_index
+                .as_ref()
+                .is_some_and(|index| !index.history(script).is_empty())
+    }
+
+    // Rebuilds an empty node from an archive, connecting and validating every block before
+    // restoring the wallet. The wallet of an encrypted archive is locked afterwards and is
+    // unlocked with its passphrase.
+    pub fn import_chain(&mut self, archive: ChainArchive) -> Result<(), String> {
+        if !self.block_index.is_empty() {
+            return Err("Chain can only be imported into an empty node".to_owned());
+        }
+        if matches!(archive.seed, SeedRecord::Encrypted(_)) && self.keystore.is_some() {
+            return Err("Wallet is already encrypted".to_owned());
+        }
+        let tip = archive.blocks.last().map(|block| block.header.hash());
+        for block in archive.blocks {
+            let height = block.header.height;
+            self.push_block(block)
+                .map_err(|err| format!("{} at height {}", err, height))?;
+        }
+        if tip.is_some_and(|tip| tip != self.latest_block_hash()) {
+            return Err("Archived blocks do not form a chain".to_owned());
+        }
+
+        match archive.seed {
+            // The archived accounts replace the ones that weren't derived, and are kept by
+            // restoring the seed
+            SeedRecord::Plain(seed) => {
+                let mut accounts = self.accounts_from_records(archive.records)?;
+                if let Some(wallet_key) = &self.wallet_key {
+                    for key in accounts.iter_mut().flat_map(|account| &mut account.keys) {
+                        if let (None, Some(private_key)) = (&key.encrypted_key, &key.private_key) {
+                            key.encrypted_key =
+                                Some(encrypt_secret(wallet_key, &private_key.to_bytes()));
+                        }
+                    }
+                }
+                self.accounts.clear();
+                self.script_owners.clear();
+                for account in accounts {
+                    self.add_account(account);
+                }
+                self.restore_seed(seed)?;
+            }
+            // The archived wallet replaces this one and starts locked, deriving addresses
+            // from the account xpubs
+            SeedRecord::Encrypted(keystore) => {
+                self.write(|storage| storage.write_seed(&SeedRecord::Encrypted(keystore.clone())))?;
+                self.keystore = Some(keystore);
+                self.lock_wallet()?;
+                let accounts = self.accounts_from_records(archive.records)?;
+                self.accounts.clear();
+                self.script_owners.clear();
+                for account in accounts {
+                    self.add_account(account);
+                }
+                self.write(|storage| storage.write_wallet(&wallet_records(&self.accounts)))?;
+            }
+        }
+        self.flush()
+    }
+
+    // Deletes the blocks and undo data allowed by the prune mode, keeping their headers
+    pub fn prune_blocks(&mut self) -> Result<(), String> {
+        let mode = match self.prune {
+            Some(mode) => mode,
+            None => return Ok(()),
+        };
+        let mut sizes: Vec<_> = self
+            .blocks
+            .iter()
+            .map(|(hash, block)| (self.block_index[hash].height(), block.encode().len() as u64))
+            .collect();
+        sizes.sort();
+        let prune_height = mode.prune_height(self.next_height(), &sizes);
+        let pruned: Vec<_> = self
+            .blocks
+            .keys()
+            .filter(|hash| self.block_index[*hash].height() < prune_height)
+            .copied()
+            .collect();
+        if pruned.is_empty() {
+            return Ok(());
+        }
+
+        // Startup connects the blocks after the flushed chain state again, so they must stay
+        self.flush()?;
+        for hash in pruned {
+            self.block_index.get_mut(&hash).unwrap().have_data = false;
+            // The index must not refer to deleted blocks
+            self.write_index(&hash)?;
+            self.write(|storage| storage.remove_block(&hash))?;
+            self.blocks.remove(&hash);
+            self.undo.remove(&hash);
+        }
+        Ok(())
+    }
+
+    // Checks that the latest block is intact
+    pub fn verify_tip(&self) -> Result<(), String> {
+        if let Some(block) = self.latest_block() {
+            if !block.header.validate() {
+                return Err("Latest block hash does not meet the target".to_owned());
+            }
+            let txids = block.transactions.iter().map(|tx| tx.txid()).collect();
+            if block.header.merkle_root != merkle_root(txids) {
+                return Err("Latest block has an invalid merkle root".to_owned());
+            }
+        }
+        Ok(())
+    }
+
+    // UTXO set built by connecting the active chain from genesis, checking the undo data on
+    // the way
+    fn rebuild_utxos(&self) -> Result<HashMap<(TxID, u32), Utxo>, String> {
+        let mut utxos = HashMap::new();
+        for hash in &self.active_chain {
+            let (block, undo) = match (self.blocks.get(hash), self.undo.get(hash)) {
+                (Some(block), Some(undo)) => (block, undo),
+                _ => return Err("Block data to rebuild the UTXO set is missing".to_owned()),
+            };
+            let height = block.header.height;
+            let mut spent_utxos = Vec::new();
+            for tx in &block.transactions {
+                if !tx.is_coinbase() {
+                    for tx_in in &tx.inputs {
+                        match utxos.remove(&(tx_in.txid, tx_in.output_index)) {
+                            Some(utxo) => spent_utxos.push(utxo),
+                            None => {
+                                return Err(format!(
+                                    "Block spends a missing output at height {}",
+                                    height
+                                ))
+                            }
+                        }
+                    }
+                }
+                let txid = tx.txid();
+                for (i, tx_out) in tx.outputs.iter().enumerate() {
+                    let utxo = Utxo::new(tx_out.clone(), height, tx.is_coinbase());
+                    utxos.insert((txid, i as u32), utxo);
+                }
+            }
+            if spent_utxos != undo.spent_utxos {
+                return Err(format!("Undo data does not match at height {}", height));
+            }
+        }
+        Ok(utxos)
+    }
+
+    // Writes the UTXO set so that startup doesn't have to connect the blocks again
+    pub fn flush(&mut self) -> Result<(), String> {
+        let chainstate = ChainStateRecord {
+            tip: self.latest_block_hash(),
+            utxos: self
+                .utxos
+                .iter()
+                .map(|(outpoint, utxo)| (*outpoint, utxo.clone()))
+                .collect(),
+        };
+        self.write(|storage| storage.write_chainstate(&chainstate))?;
+        self.last_flush = Instant::now();
+        Ok(())
+    }
+
+    fn write(&self, f: impl FnOnce(&dyn Storage) -> io::Result<()>) -> Result<(), String> {
+        f(self.storage.as_ref()).map_err(|err| err.to_string())
+    }
+
+    fn write_index(&self, hash: &[u8; 32]) -> Result<(), String> {
+        let index = &self.block_index[hash];
+        let record = IndexRecord {
+            header: index.header.clone(),
+            sequence: index.sequence,
+            invalid: index.invalid,
+            have_data: index.have_data,
+        };
+        self.write(|storage| storage.write_index(&record))
+    }
+
+    // Adds a block to the block tree and switches to the most-work chain.
+    // Returns the transactions of blocks disconnected by a reorganization.
+    pub fn push_block(&mut self, block: Block) -> Result<Vec<Transaction>, String> {
+        let hash = block.header.hash();
+        if self.block_index.contains_key(&hash) {
+            return Err("Block already known".to_owned());
+        }
+        self.validate_header(&block.header, adjusted_time())?;
+        let txids = block.transactions.iter().map(|tx| tx.txid()).collect();
+        if block.transactions.is_empty() || block.header.merkle_root != merkle_root(txids) {
+            return Err("Invalid merkle root".to_owned());
+        }
+
+        let parent_work = match self.block_index.get(&block.header.prev_block_hash) {
+            Some(parent) => parent.chain_work,
+            None => Default::default(),
+        };
+        let index = BlockIndex {
+            header: block.header.clone(),
+            chain_work: parent_work + block.header.work(),
+            sequence: self.block_index.len() as u64,
+            invalid: false,
+            have_data: true,
+        };
+        // The block data has to be on disk before the index refers to it
+        self.write(|storage| storage.write_block(&block))?;
+        self.block_index.insert(hash, index);
+        self.blocks.insert(hash, block);
+        self.write_index(&hash)?;
+
+        let (disconnected, err) = self.activate_best_chain()?;
+        match err {
+            Some(err) if self.block_index[&hash].invalid => Err(err),
+            _ => Ok(disconnected),
+        }
+    }
+
+    // Reorganizes to the valid chain with the most work, marking blocks that fail to connect
+    // as invalid. Returns the disconnected transactions that are not part of the new chain
+    // and the last connection error, fails if the storage can't be written.
+    fn activate_best_chain(&mut self) -> Result<(Vec<Transaction>, Option<String>), String> {
+        let mut disconnected = Vec::new();
+        let mut connected = HashSet::new();
+        let mut last_err = None;
+        while let Some(best) = self.best_candidate() {
+            if self.active_chain.last() == Some(&best) {
+                break;
+            }
+
+            // The best chain may end at an ancestor of the tip after blocks are invalidated
+            let branch = self.branch_from_active_chain(best);
+            let fork_height = match branch.first() {
+                Some(first) => self.block_index[first].height(),
+                None => self.block_index[&best].height() + 1,
+            };
+            while self.active_chain.len() as u64 > fork_height {
+                let mut block = self.disconnect_tip();
+                block.transactions.reverse();
+                disconnected.extend(block.transactions);
+            }
+            for hash in branch {
+                let block = self.blocks[&hash].clone();
+                if let Err(err) = self.validate_block(&block) {
+                    self.block_index.get_mut(&hash).unwrap().invalid = true;
+                    self.write_index(&hash)?;
+                    last_err = Some(err);
+                    break;
+                }
+                self.connect_block(&block)?;
+                connected.extend(block.transactions.iter().map(|tx| tx.txid()));
+            }
+        }
+
+        disconnected.reverse();
+        let disconnected = disconnected
+            .into_iter()
+            .filter(|tx| !tx.is_coinbase() && !connected.contains(&tx.txid()))
+            .collect();
+        Ok((disconnected, last_err))
+    }
+
+    // Block with the most work whose chain contains no invalid block
+    fn best_candidate(&self) -> Option<[u8; 32]> {
+        self.block_index
+            .iter()
+            .filter(|(hash, _)| self.is_valid_chain(**hash))
+            .max_by(|(_, a), (_, b)| {
+                a.chain_work
+                    .cmp(&b.chain_work)
+                    .then(b.sequence.cmp(&a.sequence))
+            })
+            .map(|(hash, _)| *hash)
+    }
+
+    // Whether the chain ending at `hash` can become the active chain
+    fn is_valid_chain(&self, mut hash: [u8; 32]) -> bool {
+        while let Some(index) = self.block_index.get(&hash) {
+            if index.invalid {
+                return false;
+            }
+            if self.is_active(&hash) {
+                // Disconnecting the blocks after the fork needs their undo data
+                let fork = index.height() as usize + 1;
+                return self.active_chain[fork..]
+                    .iter()
+                    .all(|hash| self.undo.contains_key(hash));
+            }
+            if !index.have_data {
+                return false;
+            }
+            // A chain from another genesis block replaces the whole active chain
+            if index.height() == 0 {
+                return self
+                    .active_chain
+                    .iter()
+                    .all(|hash| self.undo.contains_key(hash));
+            }
+            hash = index.header.prev_block_hash;
+        }
+        // Chains that do not reach a genesis block can't be connected
+        false
+    }
+
+    // `hash` and the blocks built on it
+    fn descendants(&self, hash: &[u8; 32]) -> HashSet<[u8; 32]> {
+        let mut indexes: Vec<_> = self.block_index.iter().collect();
+        indexes.sort_by_key(|(_, index)| index.height());
+        let mut descendants = HashSet::from([*hash]);
+        for (hash, index) in indexes {
+            if descendants.contains(&index.header.prev_block_hash) {
+                descendants.insert(*hash);
+            }
+        }
+        descendants
+    }
+
+    fn is_active(&self, hash: &[u8; 32]) -> bool {
+        match self.block_index.get(hash) {
+            Some(index) => self.active_chain.get(index.height() as usize) == Some(hash),
+            None => false,
+        }
+    }
+
+    // Blocks from the first block not in the active chain up to `hash`, in ascending order
+    fn branch_from_active_chain(&self, mut hash: [u8; 32]) -> Vec<[u8; 32]> {
+        let mut branch = Vec::new();
+        while !self.is_active(&hash) {
+            let index = &self.block_index[&hash];
+            branch.push(hash);
+            if index.height() == 0 {
+                break;
+            }
+            hash = index.header.prev_block_hash;
+        }
+        branch.reverse();
+        branch
+    }
+
+    // Connects a validated block, which is disconnected again if its undo data can't be stored
+    fn connect_block(&mut self, block: &Block) -> Result<(), String> {
+        let height = block.header.height;
+        let mut undo = BlockUndo::default();
         for tx in &block.transactions {
             let txid = tx.txid();
-            for tx_in in &tx.inputs {
-                if tx_in.txid != [0; 32] {
-                    let (pubkey, amount) = {
-                        let utxo = self.utxos.get(&(tx_in.txid, tx_in.output_index)).unwrap();
-                        (unwrap_utxo_p2pk(utxo), utxo.amount)
-                    };
-                    let account = self.account_by_pubkey(pubkey);
-                    account.balance -= amount;
-                    account.utxos.remove(&(tx_in.txid, tx_in.output_index));
+            if !tx.is_coinbase() {
+                for tx_in in &tx.inputs {
+                    let utxo = self.remove_utxo(&(tx_in.txid, tx_in.output_index));
+                    undo.spent_utxos.push(utxo.unwrap());
                 }
             }
             for (i, tx_out) in tx.outputs.iter().enumerate() {
-                let pubkey = unwrap_utxo_p2pk(tx_out);
-                let account = self.account_by_pubkey(pubkey);
-                account.balance += tx_out.amount;
-                account.utxos.insert((txid, i as u32), tx_out.clone());
-                self.utxos.insert((txid, i as u32), tx_out.clone());
+                let utxo = Utxo::new(tx_out.clone(), height, tx.is_coinbase());
+                self.add_utxo((txid, i as u32), utxo);
             }
         }
-        self.blocks.push(block);
+        let hash = block.header.hash();
+        self.undo.insert(hash, undo);
+        if let Some(tx_index) = &mut self.tx_index {
+            tx_index.connect_block(block);
+        }
+        if let Some(address_index) = &mut self.address_index {
+            address_index.connect_block(block, &self.undo[&hash]);
+        }
+        self.active_chain.push(hash);
+        if let Err(err) = self.write(|storage| storage.write_undo(&hash, &self.undo[&hash])) {
+            self.disconnect_tip();
+            return Err(err);
+        }
+        Ok(())
     }
 
-    pub fn blocks(&self) -> Vec<Block> {
-        self.blocks.clone()
+    fn disconnect_tip(&mut self) -> Block {
+        let hash = self.active_chain.pop().unwrap();
+        let block = self.blocks[&hash].clone();
+        let undo = self.undo.remove(&hash).unwrap();
+        if let Some(address_index) = &mut self.address_index {
+            address_index.disconnect_block(&block, &undo);
+        }
+        let mut spent_utxos = undo.spent_utxos;
+        for tx in block.transactions.iter().rev() {
+            let txid = tx.txid();
+            for i in 0..tx.outputs.len() {
+                self.remove_utxo(&(txid, i as u32));
+            }
+            if !tx.is_coinbase() {
+                for tx_in in tx.inputs.iter().rev() {
+                    let utxo = spent_utxos.pop().unwrap();
+                    self.add_utxo((tx_in.txid, tx_in.output_index), utxo);
+                }
+            }
+        }
+        if let Some(tx_index) = &mut self.tx_index {
+            tx_index.disconnect_block(&block);
+        }
+        block
     }
 
-    pub fn latest_block(&self) -> Option<Block> {
-        self.blocks.last().cloned()
+    fn add_utxo(&mut self, outpoint: (TxID, u32), utxo: Utxo) {
+        let script = &utxo.tx_out.script_pub_key;
+        if let Some(index) = self.script_owners.get(script) {
+            self.accounts[*index].utxos.insert(outpoint, utxo.clone());
+        }
+        self.script_index
+            .entry(script.clone())
+            .or_default()
+            .insert(outpoint);
+        self.utxos.insert(outpoint, utxo);
     }
 
-    pub fn create_account(&mut self) -> Account {
-        let index = self.accounts.len();
-        let account = Account::random(index);
-        self.accounts.push(account.clone());
-        account
+    fn remove_utxo(&mut self, outpoint: &(TxID, u32)) -> Option<Utxo> {
+        let utxo = self.utxos.remove(outpoint)?;
+        let script = &utxo.tx_out.script_pub_key;
+        if let Some(index) = self.script_owners.get(script) {
+            self.accounts[*index].utxos.remove(outpoint);
+        }
+        if let Some(outpoints) = self.script_index.get_mut(script) {
+            outpoints.remove(outpoint);
+            if outpoints.is_empty() {
+                self.script_index.remove(script);
+            }
+        }
+        Some(utxo)
     }
 
-    pub fn accounts(&self) -> Vec<Account> {
-        self.accounts.clone()
+    // Registers the scripts of an account and hands it the outputs it already owns
+    fn add_account(&mut self, mut account: Account) {
+        let keys = std::mem::take(&mut account.keys);
+        self.accounts.push(account);
+        for key in keys {
+            self.add_key(key, self.accounts.len() - 1);
+        }
     }
 
-    pub fn account(&self, i: usize) -> Account {
-        self.accounts[i].clone()
+    fn add_key(&mut self, key: Key, index: usize) {
+        for script in &key.scripts {
+            self.script_owners.insert(script.clone(), index);
+            for (outpoint, utxo) in self.utxos_by_script(script) {
+                self.accounts[index].utxos.insert(outpoint, utxo);
+            }
+        }
+        self.accounts[index].keys.push(key);
     }
 
-    fn account_by_pubkey(&mut self, pubkey: Vec<u8>) -> &mut Account {
-        for account in &mut self.accounts {
-            if account.public_key.to_sec1_bytes().to_vec() == pubkey {
-                return account;
+    // Checks a block extending the latest block against the current UTXO set
+    pub fn validate_block(&self, block: &Block) -> Result<(), String> {
+        if block.header.prev_block_hash != self.latest_block_hash() {
+            return Err("Block does not extend the latest block".to_owned());
+        }
+        self.validate_header(&block.header, adjusted_time())?;
+        let coinbase = block
+            .transactions
+            .first()
+            .ok_or("Block has no transactions")?;
+        if !coinbase.is_coinbase() {
+            return Err("First transaction of block is not a coinbase".to_owned());
+        }
+        if block.transactions.iter().skip(1).any(|tx| tx.is_coinbase()) {
+            return Err("Block contains more than one coinbase".to_owned());
+        }
+        if coinbase.inputs[0].script_sig.0.first() != coinbase_script(block.header.height).0.first()
+        {
+            return Err("Coinbase does not commit to the block height".to_owned());
+        }
+        let median_time_past = self.median_time_past();
+        for tx in &block.transactions {
+            if !tx.is_final(block.header.height, median_time_past) {
+                return Err("Block contains non-final transaction".to_owned());
             }
         }
-        panic!("can't find account with public key");
+
+        // Outputs created earlier in the block can be spent by later transactions
+        let mut tx_outs = self.tx_outs();
+        let mut created = HashMap::new();
+        let mut spent = HashSet::new();
+        for tx in block.transactions.iter().skip(1) {
+            for tx_in in &tx.inputs {
+                let outpoint = (tx_in.txid, tx_in.output_index);
+                if !spent.insert(outpoint) {
+                    return Err("Block contains double spend".to_owned());
+                }
+                if !created.contains_key(&outpoint) {
+                    self.validate_input(&outpoint, block.header.height)?;
+                }
+            }
+            let txid = tx.txid();
+            for (i, tx_out) in tx.outputs.iter().enumerate() {
+                created.insert((txid, i as u32), tx_out.clone());
+            }
+        }
+        tx_outs.extend(created);
+        if !block.validate(&tx_outs) {
+            return Err("Invalid block".to_owned());
+        }
+        let fees: u64 = block
+            .transactions
+            .iter()
+            .skip(1)
+            .filter_map(|tx| tx.fee(&tx_outs))
+            .sum();
+        let reward: u64 = coinbase.outputs.iter().map(|tx_out| tx_out.amount).sum();
+        if reward > MINING_REWARD + fees {
+            return Err("Coinbase pays more than the reward and fees".to_owned());
+        }
+        Ok(())
+    }
+
+    // Context-free checks and checks of a header against its parent
+    pub fn validate_header(&self, header: &BlockHeader, adjusted_time: u32) -> Result<(), String> {
+        if header.bits != BITS {
+            return Err("Block bits don't match the difficulty target".to_owned());
+        }
+        if !header.validate() {
+            return Err("Block hash does not meet the target".to_owned());
+        }
+        let median_time_past = match self.block_index.get(&header.prev_block_hash) {
+            Some(parent) if header.height == parent.height() + 1 => {
+                self.median_time_past_of(header.prev_block_hash)
+            }
+            None if header.height == 0 && header.prev_block_hash == [0; 32] => 0,
+            Some(_) => return Err("Block height does not follow its parent".to_owned()),
+            None => return Err("Unknown parent block".to_owned()),
+        };
+        if !header.validate_timestamp(median_time_past, adjusted_time) {
+            return Err("Block timestamp is out of range".to_owned());
+        }
+        Ok(())
+    }
+
+    fn validate_input(&self, outpoint: &(TxID, u32), height: u64) -> Result<(), String> {
+        match self.utxos.get(outpoint) {
+            Some(utxo) if !utxo.is_mature(height) => {
+                Err("Spending immature coinbase output".to_owned())
+            }
+            Some(_) => Ok(()),
+            None => Err("Spending unknown output".to_owned()),
+        }
+    }
+
+    pub fn tx_outs(&self) -> HashMap<(TxID, u32), TxOut> {
+        self.utxos
+            .iter()
+            .map(|(outpoint, utxo)| (*outpoint, utxo.tx_out.clone()))
+            .collect()
+    }
+
+    // Median time past of the chain ending at `hash`
+    fn median_time_past_of(&self, mut hash: [u8; 32]) -> u32 {
+        let mut timestamps = Vec::new();
+        while let Some(index) = self.block_index.get(&hash) {
+            timestamps.push(index.header.timestamp);
+            if timestamps.len() == MEDIAN_TIME_SPAN {
+                break;
+            }
+            hash = index.header.prev_block_hash;
+        }
+        median_time_past(&timestamps)
+    }
+
+    pub fn account(&self, i: usize) -> Account {
+        self.accounts[i].clone()
     }
 }
 
@@ -88,10 +1024,1600 @@ impl Default for DB {
     }
 }
 
-fn unwrap_utxo_p2pk(utxo: &TxOut) -> Vec<u8> {
-    if let Some(StandardScript::P2PK(pubkey)) = utxo.script_pub_key.to_standard() {
-        pubkey
-    } else {
-        panic!("Trying to unwrap non p2pk utxo");
+impl ChainState for DB {
+    fn block(&self, hash: &[u8; 32]) -> Option<Block> {
+        self.blocks.get(hash).cloned()
+    }
+
+    fn block_at(&self, height: u64) -> Option<Block> {
+        self.active_chain
+            .get(height as usize)
+            .and_then(|hash| self.blocks.get(hash).cloned())
+    }
+
+    // Blocks known only by their headers are left out
+    fn blocks(&self) -> Vec<Block> {
+        self.active_chain
+            .iter()
+            .filter_map(|hash| self.blocks.get(hash).cloned())
+            .collect()
+    }
+
+    fn latest_block(&self) -> Option<Block> {
+        self.active_chain
+            .last()
+            .and_then(|hash| self.blocks.get(hash).cloned())
+    }
+
+    fn latest_block_hash(&self) -> [u8; 32] {
+        self.active_chain.last().copied().unwrap_or([0; 32])
+    }
+
+    fn next_height(&self) -> u64 {
+        self.active_chain.len() as u64
+    }
+
+    fn median_time_past(&self) -> u32 {
+        match self.active_chain.last() {
+            Some(hash) => self.median_time_past_of(*hash),
+            None => 0,
+        }
+    }
+
+    fn utxo(&self, outpoint: &(TxID, u32)) -> Option<Utxo> {
+        self.utxos.get(outpoint).cloned()
+    }
+
+    fn utxos_by_script(&self, script: &Script) -> Vec<((TxID, u32), Utxo)> {
+        match self.script_index.get(script) {
+            Some(outpoints) => outpoints
+                .iter()
+                .map(|outpoint| (*outpoint, self.utxos[outpoint].clone()))
+                .collect(),
+            None => vec![],
+        }
+    }
+
+    fn transaction(&self, id: &TxID) -> Result<Option<(Transaction, TxLocation)>, String> {
+        let tx_index = match &self.tx_index {
+            Some(tx_index) => tx_index,
+            None => return Err("Transaction index is disabled".to_owned()),
+        };
+        let location = match tx_index.get(id) {
+            Some(location) => location,
+            None => return Ok(None),
+        };
+        match self.blocks.get(&location.block_hash) {
+            Some(block) => {
+                let tx = block.transactions[location.position as usize].clone();
+                Ok(Some((tx, location)))
+            }
+            None => Err("Block of the transaction is pruned".to_owned()),
+        }
+    }
+
+    fn script_history(&self, script: &Script) -> Result<Vec<ScriptEvent>, String> {
+        match &self.address_index {
+            Some(address_index) => Ok(address_index.history(script)),
+            None => Err("Address index is disabled".to_owned()),
+        }
+    }
+
+    // UTXO set as of the block at `height` of the active chain, rolled back from the tip
+    fn utxo_snapshot(&self, height: u64) -> Result<UtxoSnapshot, String> {
+        if height >= self.next_height() {
+            return Err("Block height is beyond the tip".to_owned());
+        }
+        let mut utxos = self.utxos.clone();
+        for hash in self.active_chain[height as usize + 1..].iter().rev() {
+            match (self.blocks.get(hash), self.undo.get(hash)) {
+                (Some(block), Some(undo)) => rollback(&mut utxos, block, undo)?,
+                _ => return Err("Block data to roll back is missing".to_owned()),
+            }
+        }
+        let headers = self.active_chain[..=height as usize]
+            .iter()
+            .map(|hash| self.block_index[hash].header.clone())
+            .collect();
+        Ok(UtxoSnapshot::new(headers, utxos))
+    }
+
+    fn verify_chain(&self, level: u32, depth: u64) -> Result<(), String> {
+        let depth = match depth {
+            0 => self.active_chain.len(),
+            depth => depth as usize,
+        };
+        let mut utxos = self.utxos.clone();
+        for hash in self.active_chain.iter().rev().take(depth) {
+            let index = &self.block_index[hash];
+            // Pruned blocks and the blocks of a snapshot can't be checked
+            if !index.have_data {
+                break;
+            }
+            let height = index.height();
+            let err = |message: &str| format!("{} at height {}", message, height);
+
+            let block = self
+                .storage
+                .read_block(hash, height)
+                .map_err(|e| err(&e.to_string()))?;
+            if block.header.hash() != *hash || !block.header.validate() {
+                return Err(err("Stored block has an invalid hash"));
+            }
+            if level >= 1 {
+                let txids = block.transactions.iter().map(|tx| tx.txid()).collect();
+                if block.header.merkle_root != merkle_root(txids) {
+                    return Err(err("Stored block has an invalid merkle root"));
+                }
+                if block.encode() != self.blocks[hash].encode() {
+                    return Err(err("Stored block differs from block in memory"));
+                }
+            }
+            if level >= 2 {
+                let undo = self
+                    .storage
+                    .read_undo(hash)
+                    .map_err(|e| err(&e.to_string()))?;
+                if undo != self.undo[hash] {
+                    return Err(err("Stored undo data does not match"));
+                }
+            }
+            if level >= 3 {
+                rollback(&mut utxos, &block, &self.undo[hash]).map_err(|e| err(&e))?;
+            }
+        }
+        if level >= 4 && self.rebuild_utxos()? != self.utxos {
+            return Err("UTXO set does not match the chain".to_owned());
+        }
+        Ok(())
+    }
+
+    fn chain_archive(&self) -> Result<ChainArchive, String> {
+        if self
+            .active_chain
+            .iter()
+            .any(|hash| !self.blocks.contains_key(hash))
+        {
+            return Err("Pruned blocks can't be exported".to_owned());
+        }
+        let imported: Vec<_> = self
+            .accounts
+            .iter()
+            .filter(|account| account.purpose.is_none())
+            .cloned()
+            .collect();
+        let (seed, records) = match &self.keystore {
+            Some(keystore) => (
+                SeedRecord::Encrypted(keystore.clone()),
+                wallet_records(&self.accounts),
+            ),
+            None => (
+                SeedRecord::Plain(self.seed.clone().ok_or("Wallet is locked")?),
+                wallet_records(&imported),
+            ),
+        };
+        Ok(ChainArchive {
+            blocks: self.blocks(),
+            seed,
+            records,
+        })
+    }
+
+    fn prune_info(&self) -> PruneInfoJson {
+        let prune_height = self
+            .active_chain
+            .iter()
+            .position(|hash| self.blocks.contains_key(hash))
+            .unwrap_or(self.active_chain.len());
+        PruneInfoJson {
+            pruned: self.prune.is_some(),
+            prune_height: prune_height as u64,
+            size: self
+                .blocks
+                .values()
+                .map(|block| block.encode().len() as u64)
+                .sum(),
+        }
+    }
+
+    // Checks that a transaction accepted now could be included in the next block
+    fn validate_transaction(
+        &self,
+        tx: &Transaction,
+        pending: &HashMap<(TxID, u32), TxOut>,
+    ) -> Result<(), String> {
+        if !tx.is_final(self.next_height(), self.median_time_past()) {
+            return Err("Non-final transaction".to_owned());
+        }
+        let mut tx_outs = HashMap::new();
+        for tx_in in &tx.inputs {
+            let outpoint = (tx_in.txid, tx_in.output_index);
+            let tx_out = match pending.get(&outpoint) {
+                Some(tx_out) => tx_out.clone(),
+                None => {
+                    self.validate_input(&outpoint, self.next_height())?;
+                    self.utxos[&outpoint].tx_out.clone()
+                }
+            };
+            tx_outs.insert(outpoint, tx_out);
+        }
+        if tx.fee(&tx_outs).is_none() {
+            return Err("Outputs spend more than the inputs".to_owned());
+        }
+        Ok(())
+    }
+
+    // Accepts blocks in any order. Blocks with an unknown parent wait in the orphan pool
+    // and are connected once the parent arrives.
+    fn process_block(&mut self, block: Block) -> Result<Vec<Transaction>, String> {
+        let hash = block.header.hash();
+        if self.block_index.contains_key(&hash) || self.orphans.contains(&hash) {
+            return Err("Block already known".to_owned());
+        }
+        let is_genesis = block.header.height == 0 && block.header.prev_block_hash == [0; 32];
+        if !is_genesis && !self.block_index.contains_key(&block.header.prev_block_hash) {
+            if block.header.bits != BITS {
+                return Err("Block bits don't match the difficulty target".to_owned());
+            }
+            if !block.header.validate() {
+                return Err("Block hash does not meet the target".to_owned());
+            }
+            self.orphans.insert(block)?;
+            return Ok(vec![]);
+        }
+
+        let mut disconnected = self.push_block(block)?;
+        let mut parents = vec![hash];
+        while let Some(parent) = parents.pop() {
+            for child in self.orphans.take_children(&parent) {
+                let child_hash = child.header.hash();
+                if let Ok(transactions) = self.push_block(child) {
+                    disconnected.extend(transactions);
+                    parents.push(child_hash);
+                }
+            }
+        }
+        if self.last_flush.elapsed() >= FLUSH_INTERVAL {
+            self.prune_blocks()?;
+            self.flush()?;
+        }
+        Ok(disconnected)
+    }
+
+    fn invalidate_block(&mut self, hash: &[u8; 32]) -> Result<Vec<Transaction>, String> {
+        let height = match self.block_index.get(hash) {
+            Some(index) if index.height() == 0 => {
+                return Err("Genesis block can't be invalidated".to_owned())
+            }
+            Some(index) => index.height() as usize,
+            None => return Err("Unknown block".to_owned()),
+        };
+        if self.is_active(hash)
+            && !self.active_chain[height..]
+                .iter()
+                .all(|hash| self.undo.contains_key(hash))
+        {
+            return Err("Block data to roll back is missing".to_owned());
+        }
+        for hash in self.descendants(hash) {
+            self.block_index.get_mut(&hash).unwrap().invalid = true;
+            self.write_index(&hash)?;
+        }
+        let (disconnected, _) = self.activate_best_chain()?;
+        Ok(disconnected)
+    }
+
+    fn reconsider_block(&mut self, hash: &[u8; 32]) -> Result<Vec<Transaction>, String> {
+        if !self.block_index.contains_key(hash) {
+            return Err("Unknown block".to_owned());
+        }
+        let mut hashes = self.descendants(hash);
+        let mut ancestor = self.block_index[hash].header.prev_block_hash;
+        while let Some(index) = self.block_index.get(&ancestor) {
+            hashes.insert(ancestor);
+            ancestor = index.header.prev_block_hash;
+        }
+        for hash in hashes {
+            let index = self.block_index.get_mut(&hash).unwrap();
+            if index.invalid {
+                index.invalid = false;
+                self.write_index(&hash)?;
+            }
+        }
+        // Blocks that really are invalid are marked again when they fail to connect
+        let (disconnected, err) = self.activate_best_chain()?;
+        match err {
+            Some(err) if self.block_index[hash].invalid => Err(err),
+            _ => Ok(disconnected),
+        }
+    }
+
+    fn wallet_info(&self) -> WalletInfoJson {
+        let fingerprint = match &self.keystore {
+            Some(keystore) => keystore.fingerprint,
+            None => self.master_key().unwrap().fingerprint(),
+        };
+        WalletInfoJson {
+            fingerprint: to_hex(&fingerprint),
+            gap_limit: self.gap_limit,
+            encrypted: self.keystore.is_some(),
+            unlocked_until: self.unlocked_until,
+        }
+    }
+
+    fn accounts(&self) -> Vec<Account> {
+        self.accounts.clone()
+    }
+
+    fn wallet_history(&self, index: usize) -> Result<Vec<WalletTx>, String> {
+        let account = self.accounts.get(index).ok_or("Unknown account")?;
+        let scripts: HashSet<_> = account.scripts().cloned().collect();
+        let mut history = Vec::new();
+        for hash in &self.active_chain {
+            let (block, undo) = match (self.blocks.get(hash), self.undo.get(hash)) {
+                (Some(block), Some(undo)) => (block, undo),
+                _ => continue,
+            };
+            let height = Some(block.header.height);
+            let mut spent_utxos = undo.spent_utxos.iter();
+            for tx in &block.transactions {
+                let spent: Vec<_> = match tx.is_coinbase() {
+                    true => vec![],
+                    false => (&mut spent_utxos)
+                        .take(tx.inputs.len())
+                        .map(|utxo| utxo.tx_out.clone())
+                        .collect(),
+                };
+                history.extend(WalletTx::new(tx, &spent, &scripts, height));
+            }
+        }
+        Ok(history)
+    }
+
+    // Like BIP44 wallets, a new account of a purpose waits for the previous one to be used
+    // so that restoring the wallet finds it
+    fn create_account(&mut self, purpose: Purpose) -> Result<Account, String> {
+        let previous: Vec<_> = self
+            .accounts
+            .iter()
+            .filter(|account| account.purpose == Some(purpose))
+            .collect();
+        if let Some(previous) = previous.last() {
+            let used = self.used_scripts();
+            if !previous.scripts().any(|script| self.is_used(&used, script)) {
+                return Err(format!("Account {} has not been used yet", previous.index));
+            }
+        }
+        let index = self.accounts.len();
+        let path = account_path(purpose, previous.len() as u32);
+        let mut account = Account::derive(index, &self.master_key()?, path)
+            .ok_or("Failed to derive the account key")?;
+        for chain in [EXTERNAL, INTERNAL] {
+            account
+                .derive_key(chain)
+                .ok_or("Failed to derive the address key")?;
+        }
+        let mut records = wallet_records(&self.accounts);
+        records.extend(wallet_records(std::slice::from_ref(&account)));
+        self.write(|storage| storage.write_wallet(&records))?;
+        self.add_account(account);
+        Ok(self.accounts[index].clone())
+    }
+
+    // Fails once the chain ends with `gap_limit` unused addresses, which restoring the
+    // wallet would not find anymore
+    fn new_address(&mut self, index: usize, chain: u32) -> Result<Script, String> {
+        let mut account = self.accounts.get(index).ok_or("Unknown account")?.clone();
+        let used = self.used_scripts();
+        let unused = account
+            .keys
+            .iter()
+            .rev()
+            .filter(|key| key.chain() == Some(chain))
+            .take_while(|key| !key.scripts.iter().any(|script| self.is_used(&used, script)))
+            .count();
+        if unused >= self.gap_limit {
+            return Err("Too many unused addresses".to_owned());
+        }
+        let key = account
+            .derive_key(chain)
+            .ok_or("Account has a single key")?;
+        let records: Vec<_> = [
+            &self.accounts[..index],
+            std::slice::from_ref(&account),
+            &self.accounts[index + 1..],
+        ]
+        .into_iter()
+        .flat_map(wallet_records)
+        .collect();
+        self.write(|storage| storage.write_wallet(&records))?;
+        let script = key.scripts[0].clone();
+        self.add_key(key, index);
+        Ok(script)
+    }
+
+    fn import_key(&mut self, mut key: Key) -> Result<Account, String> {
+        if let (Some(private_key), Some(_)) = (&key.private_key, &self.keystore) {
+            let wallet_key = self.wallet_key.ok_or("Wallet is locked")?;
+            key.encrypted_key = Some(encrypt_secret(&wallet_key, &private_key.to_bytes()));
+        }
+        if let Some(index) = key
+            .scripts
+            .iter()
+            .find_map(|script| self.script_owners.get(script))
+        {
+            return Err(format!("Key already belongs to account {}", index));
+        }
+        let index = self.accounts.len();
+        let account = Account::from_key(index, key);
+        let mut records = wallet_records(&self.accounts);
+        records.extend(wallet_records(std::slice::from_ref(&account)));
+        self.write(|storage| storage.write_wallet(&records))?;
+        self.add_account(account);
+        Ok(self.accounts[index].clone())
+    }
+
+    fn restore_wallet(&mut self, seed: Vec<u8>) -> Result<Vec<Account>, String> {
+        self.restore_seed(seed)?;
+        Ok(self.accounts.clone())
+    }
+
+    // The old wallet files keep their secrets in the clear, so backups taken before
+    // encrypting aren't protected
+    fn encrypt_wallet(&mut self, passphrase: &str) -> Result<(), String> {
+        if self.keystore.is_some() {
+            return Err("Wallet is already encrypted".to_owned());
+        }
+        if passphrase.is_empty() {
+            return Err("Passphrase can't be empty".to_owned());
+        }
+        let master_key = self.master_key()?;
+        let seed = self.seed.as_ref().unwrap();
+        let (keystore, wallet_key) = Keystore::new(passphrase, seed, master_key.fingerprint());
+        let mut accounts = self.accounts.clone();
+        for key in accounts.iter_mut().flat_map(|account| &mut account.keys) {
+            if let (None, Some(private_key)) = (&key.path, &key.private_key) {
+                key.encrypted_key = Some(encrypt_secret(&wallet_key, &private_key.to_bytes()));
+            }
+        }
+        // The keystore is written first so that stored keys can always be decrypted
+        self.write(|storage| storage.write_seed(&SeedRecord::Encrypted(keystore.clone())))?;
+        self.keystore = Some(keystore);
+        self.accounts = accounts;
+        self.write(|storage| storage.write_wallet(&wallet_records(&self.accounts)))?;
+        self.lock_wallet()
+    }
+
+    fn unlock_wallet(&mut self, passphrase: &str, until: u32) -> Result<(), String> {
+        let keystore = self.keystore.as_ref().ok_or("Wallet is not encrypted")?;
+        let wallet_key = keystore.unlock(passphrase)?;
+        let seed = decrypt_secret(&wallet_key, &keystore.seed)?;
+        let master_key =
+            ExtendedPrivateKey::new_master(&seed, NETWORK).ok_or("Invalid wallet seed")?;
+        let mut accounts = self.accounts.clone();
+        for account in &mut accounts {
+            account.account_key = match &account.path {
+                Some(path) => master_key.derive_path(path),
+                None => None,
+            };
+            for key in &mut account.keys {
+                key.private_key = match (&key.path, &key.encrypted_key) {
+                    (Some(path), _) => account
+                        .account_key
+                        .as_ref()
+                        .and_then(|account_key| {
+                            account_key.derive_path(&DerivationPath(path.0[3..].to_vec()))
+                        })
+                        .map(|key| key.private_key),
+                    (None, Some(encrypted_key)) => {
+                        let private_key = decrypt_secret(&wallet_key, encrypted_key)?;
+                        Some(
+                            SigningKey::from_slice(&private_key)
+                                .map_err(|_| "Invalid private key in wallet")?,
+                        )
+                    }
+                    (None, None) => None,
+                };
+            }
+        }
+        self.accounts = accounts;
+        self.seed = Some(seed);
+        self.wallet_key = Some(wallet_key);
+        self.unlocked_until = Some(until);
+        Ok(())
+    }
+
+    fn lock_wallet(&mut self) -> Result<(), String> {
+        if self.keystore.is_none() {
+            return Err("Wallet is not encrypted".to_owned());
+        }
+        for account in &mut self.accounts {
+            account.account_key = None;
+            for key in &mut account.keys {
+                key.private_key = None;
+            }
+        }
+        self.seed = None;
+        self.wallet_key = None;
+        self.unlocked_until = None;
+        Ok(())
+    }
+
+    fn change_passphrase(&mut self, old: &str, new: &str) -> Result<(), String> {
+        let mut keystore = self.keystore.clone().ok_or("Wallet is not encrypted")?;
+        if new.is_empty() {
+            return Err("Passphrase can't be empty".to_owned());
+        }
+        keystore.change_passphrase(old, new)?;
+        self.write(|storage| storage.write_seed(&SeedRecord::Encrypted(keystore.clone())))?;
+        self.keystore = Some(keystore);
+        Ok(())
+    }
+}
+
+// One record for every key, in the order the keys of each account were derived
+fn wallet_records(accounts: &[Account]) -> Vec<WalletRecord> {
+    accounts
+        .iter()
+        .flat_map(|account| {
+            account.keys.iter().map(|key| {
+                // Derived keys are derived again and encrypted keys stay encrypted
+                let private_key = key
+                    .private_key
+                    .as_ref()
+                    .filter(|_| key.path.is_none() && key.encrypted_key.is_none());
+                WalletRecord {
+                    index: account.index,
+                    private_key: private_key.map(|key| key.to_bytes().to_vec()),
+                    path: key.path.as_ref().map(|path| path.to_string()),
+                    public_key: key
+                        .public_key
+                        .filter(|_| private_key.is_none())
+                        .map(|key| key.to_sec1_bytes().to_vec()),
+                    script: key.public_key.is_none().then(|| key.scripts[0].clone()),
+                    encrypted_key: key.encrypted_key.clone(),
+                    xpub: account.xpub.as_ref().map(|xpub| xpub.to_string()),
+                }
+            })
+        })
+        .collect()
+}
+
+// Key of a record that wasn't derived
+fn record_key(record: WalletRecord) -> Result<Key, String> {
+    let key = match (record.private_key, record.public_key, record.script) {
+        (Some(private_key), _, _) => Key::new(private_key),
+        (None, Some(public_key), _) => VerifyingKey::from_sec1_bytes(&public_key)
+            .map(Key::watch)
+            .map_err(|_| "Invalid public key in wallet".to_owned()),
+        (None, None, Some(script)) => Ok(Key::watch_script(script)),
+        _ => Err("Wallet record without a key".to_owned()),
+    };
+    key.map(|key| Key {
+        encrypted_key: record.encrypted_key,
+        ..key
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use bitaekcoin::{
+        bip32::ExtendedPublicKey,
+        bip39::Mnemonic,
+        encode::decode_all,
+        hash::hash160,
+        script::{instruction::PushBytes, StandardScript},
+        transaction::Witness,
+    };
+
+    use crate::{account::AccountJson, address_index::ScriptEventKind, prune::MIN_BLOCKS_TO_KEEP};
+
+    use crate::{
+        history::{TxDirection, WalletTxJson},
+        node::{initialize_block, mine, next_block},
+        tx_builder::TxBuilder,
+        PUBLIC_KEY,
+    };
+
+    use super::*;
+
+    fn mine_next(db: &DB, transactions: Vec<Transaction>) -> Block {
+        mine(next_block(db, BITS, PUBLIC_KEY.to_vec(), transactions))
+    }
+
+    fn push_blocks(db: &mut DB, n: usize) {
+        for _ in 0..n {
+            db.push_block(mine_next(db, vec![])).unwrap();
+        }
+    }
+
+    fn db_with_blocks(n: usize) -> DB {
+        let mut db = DB::new();
+        push_blocks(&mut db, n);
+        db
+    }
+
+    // Another node with the same chain
+    fn copy_chain(db: &DB) -> DB {
+        let mut other = DB::new();
+        for block in db.blocks() {
+            other.push_block(block).unwrap();
+        }
+        other
+    }
+
+    fn outpoints(db: &DB) -> HashSet<(TxID, u32)> {
+        db.utxos.keys().copied().collect()
+    }
+
+    #[test]
+    fn test_reorg() {
+        let mut db = db_with_blocks(101);
+        let mut other = copy_chain(&db);
+
+        let receiver = db.create_account(Purpose::NativeSegwit).unwrap();
+        let tx = db
+            .account(0)
+            .transfer(&receiver, 1, db.next_height())
+            .unwrap();
+        db.push_block(mine_next(&db, vec![tx.clone()])).unwrap();
+        assert_eq!(db.account(receiver.index).balance(db.next_height()), 1);
+
+        // A competing chain with equal work does not replace the first seen one
+        let fork = mine_next(&other, vec![]);
+        other.push_block(fork.clone()).unwrap();
+        assert!(db.push_block(fork).unwrap().is_empty());
+        assert_eq!(db.next_height(), 102);
+        assert_ne!(db.latest_block_hash(), other.latest_block_hash());
+
+        // The chain with more work is selected and the transfer goes back to the caller
+        let block = mine_next(&other, vec![]);
+        other.push_block(block.clone()).unwrap();
+        let disconnected = db.push_block(block).unwrap();
+        assert_eq!(disconnected.len(), 1);
+        assert_eq!(disconnected[0].txid(), tx.txid());
+        assert_eq!(db.latest_block_hash(), other.latest_block_hash());
+        assert_eq!(outpoints(&db), outpoints(&other));
+        assert_eq!(db.account(receiver.index).balance(db.next_height()), 0);
+        assert!(db.validate_transaction(&tx, &HashMap::new()).is_ok());
+    }
+
+    #[test]
+    fn test_connect_disconnect() {
+        let mut db = db_with_blocks(101);
+        let utxos = db.utxos.clone();
+        let accounts = db.accounts();
+
+        // The second transfer spends an output created in the same block
+        let height = db.next_height();
+        let master = db.account(0);
+        let mut receiver = db.create_account(Purpose::NativeSegwit).unwrap();
+        let tx1 = master.transfer(&receiver, 10, height).unwrap();
+        let utxo = Utxo::new(tx1.outputs[0].clone(), height, false);
+        receiver.utxos.insert((tx1.txid(), 0), utxo);
+        let tx2 = receiver.transfer(&master, 4, height).unwrap();
+
+        let block = mine_next(&db, vec![tx1.clone(), tx2.clone()]);
+        let hash = block.header.hash();
+        db.push_block(block).unwrap();
+        assert_eq!(db.latest_block_hash(), hash);
+        assert_eq!(db.undo[&hash].spent_utxos.len(), 2);
+        assert!(!db
+            .utxos
+            .contains_key(&(tx1.inputs[0].txid, tx1.inputs[0].output_index)));
+        assert!(!db.utxos.contains_key(&(tx1.txid(), 0)));
+        assert!(db.utxos.contains_key(&(tx2.txid(), 0)));
+
+        db.disconnect_tip();
+        assert_eq!(db.utxos, utxos);
+        assert!(!db.undo.contains_key(&hash));
+        for account in accounts {
+            assert_eq!(db.account(account.index).utxos, account.utxos);
+        }
+        assert!(db.account(receiver.index).utxos.is_empty());
+    }
+
+    #[test]
+    fn test_transaction_fees() {
+        let mut db = db_with_blocks(101);
+        let height = db.next_height();
+        let receiver = db.create_account(Purpose::NativeSegwit).unwrap();
+        let tx = TxBuilder::new(&db.account(0))
+            .add_recipient(receiver.receive_key().scripts[0].clone(), 1000)
+            .fee_rate(2)
+            .build(height)
+            .unwrap();
+        db.validate_transaction(&tx, &HashMap::new()).unwrap();
+        let tx_outs = db
+            .utxos
+            .iter()
+            .map(|(outpoint, utxo)| (*outpoint, utxo.tx_out.clone()))
+            .collect();
+        let fee = tx.fee(&tx_outs).unwrap();
+        assert!(fee >= tx.vsize() as u64 * 2);
+
+        // The coinbase can't claim more than the reward and the fees
+        let block = mine_next(&db, vec![tx.clone()]);
+        assert_eq!(block.transactions[0].outputs[0].amount, MINING_REWARD + fee);
+        let header = &block.header;
+        let greedy = mine(initialize_block(
+            height,
+            header.prev_block_hash,
+            header.timestamp,
+            header.bits,
+            PUBLIC_KEY.to_vec(),
+            MINING_REWARD + fee + 1,
+            vec![tx],
+        ));
+        assert!(db.push_block(greedy).is_err());
+        db.push_block(block).unwrap();
+        assert_eq!(db.account(receiver.index).balance(height + 1), 1000);
+    }
+
+    #[test]
+    fn test_coinbase() {
+        let mut db = db_with_blocks(2);
+        let genesis_coinbase = db.blocks()[0].transactions[0].txid();
+        let remine = |mut block: Block| {
+            let txids = block.transactions.iter().map(|tx| tx.txid()).collect();
+            block.header.merkle_root = merkle_root(txids);
+            mine(block)
+        };
+
+        // The first transaction must be a coinbase, so its inputs are never left unchecked
+        for outpoint in [([1; 32], 0), (genesis_coinbase, 0)] {
+            let mut block = mine_next(&db, vec![]);
+            let tx = &mut block.transactions[0];
+            (tx.inputs[0].txid, tx.inputs[0].output_index) = outpoint;
+            assert_eq!(
+                db.push_block(remine(block)).unwrap_err(),
+                "First transaction of block is not a coinbase"
+            );
+        }
+
+        let mut block = mine_next(&db, vec![]);
+        let mut coinbase = block.transactions[0].clone();
+        coinbase.inputs[0].script_sig = coinbase_script(db.next_height() + 1);
+        block.transactions.push(coinbase.clone());
+        assert_eq!(
+            db.push_block(remine(block)).unwrap_err(),
+            "Block contains more than one coinbase"
+        );
+
+        let mut block = mine_next(&db, vec![]);
+        block.transactions[0] = coinbase;
+        assert_eq!(
+            db.push_block(remine(block)).unwrap_err(),
+            "Coinbase does not commit to the block height"
+        );
+
+        push_blocks(&mut db, 1);
+        assert_eq!(db.next_height(), 3);
+    }
+
+    #[test]
+    fn test_wallet_history() {
+        let mut db = db_with_blocks(101);
+        let height = db.next_height();
+        let receiver = db.create_account(Purpose::NativeSegwit).unwrap();
+        let script = receiver.receive_key().scripts[0].clone();
+        let tx = TxBuilder::new(&db.account(0))
+            .add_recipient(script.clone(), 1000)
+            .fee_rate(2)
+            .build(height)
+            .unwrap();
+        db.push_block(mine_next(&db, vec![tx.clone()])).unwrap();
+        db.push_block(mine_next(&db, vec![])).unwrap();
+
+        let history = db.wallet_history(0).unwrap();
+        assert_eq!(history.len(), 104);
+        let mined = history
+            .iter()
+            .filter(|wallet_tx| wallet_tx.direction == TxDirection::Mined);
+        assert_eq!(mined.count(), 103);
+        let sent = &history[102];
+        assert_eq!(sent.txid, tx.txid());
+        assert_eq!(sent.direction, TxDirection::Sent);
+        let fee = sent.fee.unwrap();
+        assert!(fee >= tx.vsize() as u64 * 2);
+        assert_eq!(sent.amount, -1000 - fee as i64);
+        assert_eq!(sent.counterparties, vec![script]);
+
+        let history = db.wallet_history(receiver.index).unwrap();
+        assert_eq!(history.len(), 1);
+        assert_eq!(history[0].direction, TxDirection::Received);
+        assert_eq!(history[0].amount, 1000);
+        assert_eq!(history[0].fee, None);
+        let mining_script = StandardScript::P2PK(PUBLIC_KEY.to_vec()).into_script();
+        assert_eq!(history[0].counterparties, vec![mining_script]);
+        let json = WalletTxJson::new(history[0].clone(), db.next_height());
+        assert_eq!(json.height, Some(height));
+        assert_eq!(json.confirmations, 2);
+
+        db.disconnect_tip();
+        db.disconnect_tip();
+        assert!(db.wallet_history(receiver.index).unwrap().is_empty());
+        assert!(db.wallet_history(5).is_err());
+    }
+
+    #[test]
+    fn test_script_index() {
+        let mut db = db_with_blocks(106);
+        let receiver = db.create_account(Purpose::NativeSegwit).unwrap();
+        let foreign = Account::random(2);
+        let null_data = StandardScript::NullData(b"bitaekcoin".to_vec()).into_script();
+        let mut scripts: Vec<_> = receiver.scripts().cloned().collect();
+        scripts.push(foreign.keys[0].scripts[1].clone());
+        scripts.push(null_data.clone());
+
+        // Outputs of every kind, whether or not a local account owns them
+        let mut master = db.account(0);
+        let mut transactions = Vec::new();
+        for script in scripts {
+            let tx = master
+                .transfer_to_script(script, 1, db.next_height())
+                .unwrap();
+            for tx_in in &tx.inputs {
+                master.utxos.remove(&(tx_in.txid, tx_in.output_index));
+            }
+            transactions.push(tx);
+        }
+        db.push_block(mine_next(&db, transactions)).unwrap();
+        assert_eq!(db.account(receiver.index).balance(db.next_height()), 2);
+        assert_eq!(db.utxos_by_script(&foreign.keys[0].scripts[1]).len(), 1);
+        assert_eq!(db.utxos_by_script(&null_data).len(), 1);
+
+        let tx = db
+            .account(receiver.index)
+            .transfer(&master, 2, db.next_height())
+            .unwrap();
+        assert_eq!(tx.inputs.len(), 2);
+        db.push_block(mine_next(&db, vec![tx])).unwrap();
+        assert!(db.account(receiver.index).utxos.is_empty());
+        for script in receiver.scripts() {
+            assert!(db.utxos_by_script(script).is_empty());
+        }
+    }
+
+    #[test]
+    fn test_tx_index() {
+        let mut db = db_with_blocks(101);
+        let mut other = copy_chain(&db);
+        assert!(db.transaction(&[0; 32]).is_err());
+        db.enable_tx_index();
+        let coinbase = db.block_at(0).unwrap().transactions[0].clone();
+        let (_, location) = db.transaction(&coinbase.txid()).unwrap().unwrap();
+        assert_eq!(location.height, 0);
+
+        let receiver = db.create_account(Purpose::NativeSegwit).unwrap();
+        let funding = db
+            .account(0)
+            .transfer_to_script(
+                receiver.receive_key().scripts[0].clone(),
+                1,
+                db.next_height(),
+            )
+            .unwrap();
+        db.push_block(mine_next(&db, vec![funding])).unwrap();
+        // Spending the P2WPKH output gives the transaction a distinct wtxid
+        let tx = db
+            .account(receiver.index)
+            .transfer(&receiver, 1, db.next_height())
+            .unwrap();
+        assert_ne!(tx.txid(), tx.wtxid());
+        let block = mine_next(&db, vec![tx.clone()]);
+        db.push_block(block.clone()).unwrap();
+        for id in [tx.txid(), tx.wtxid()] {
+            let (found, location) = db.transaction(&id).unwrap().unwrap();
+            assert_eq!(found.txid(), tx.txid());
+            assert_eq!(location.block_hash, block.header.hash());
+            assert_eq!(location.position, 1);
+        }
+
+        // Transactions of disconnected blocks are no longer found
+        for _ in 0..3 {
+            let block = mine_next(&other, vec![]);
+            other.push_block(block.clone()).unwrap();
+            db.push_block(block).unwrap();
+        }
+        assert!(db.transaction(&tx.txid()).unwrap().is_none());
+        assert!(db.transaction(&tx.wtxid()).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_address_index() {
+        let mut db = db_with_blocks(101);
+        let mut other = copy_chain(&db);
+        let receiver = db.create_account(Purpose::NativeSegwit).unwrap();
+        let script = receiver.receive_key().scripts[0].clone();
+        let tx = db
+            .account(0)
+            .transfer_to_script(script.clone(), 10, db.next_height())
+            .unwrap();
+        db.push_block(mine_next(&db, vec![tx.clone()])).unwrap();
+        db.enable_address_index();
+        let spend = db
+            .account(receiver.index)
+            .transfer(&Account::random(2), 4, db.next_height())
+            .unwrap();
+        db.push_block(mine_next(&db, vec![spend.clone()])).unwrap();
+
+        let history = db.script_history(&script).unwrap();
+        assert_eq!(history.len(), 2);
+        assert_eq!(history[0].kind, ScriptEventKind::Funding);
+        assert_eq!((history[0].txid, history[0].amount), (tx.txid(), 10));
+        assert_eq!(history[0].height, Some(101));
+        assert_eq!(history[1].kind, ScriptEventKind::Spending);
+        assert_eq!((history[1].txid, history[1].amount), (spend.txid(), 10));
+        assert_eq!(history[1].height, Some(102));
+        let master_script = &db.account(0).keys[0].scripts[0];
+        // Coinbases plus the spent coinbase and the change of the first transfer
+        assert_eq!(db.script_history(master_script).unwrap().len(), 105);
+
+        // Disconnecting the blocks removes their events
+        for _ in 0..4 {
+            let block = mine_next(&other, vec![]);
+            other.push_block(block.clone()).unwrap();
+            db.push_block(block).unwrap();
+        }
+        assert!(db.script_history(&script).unwrap().is_empty());
+        assert_eq!(db.script_history(master_script).unwrap().len(), 105);
+    }
+
+    #[test]
+    fn test_utxo_snapshot() {
+        let mut db = db_with_blocks(101);
+        let mut other = copy_chain(&db);
+        let receiver = db.create_account(Purpose::NativeSegwit).unwrap();
+        let tx = db
+            .account(0)
+            .transfer(&receiver, 1, db.next_height())
+            .unwrap();
+        let block = mine_next(&db, vec![tx]);
+        db.push_block(block.clone()).unwrap();
+        other.push_block(block).unwrap();
+        db.push_block(mine_next(&db, vec![])).unwrap();
+
+        // Rolling back a block gives the UTXO set of a node that didn't connect it
+        let snapshot = other.utxo_snapshot(101).unwrap();
+        assert_eq!(db.utxo_snapshot(101).unwrap().hash(), snapshot.hash());
+        assert_ne!(db.utxo_snapshot(102).unwrap().hash(), snapshot.hash());
+        assert!(db.utxo_snapshot(103).is_err());
+
+        let mut bytes = snapshot.encode();
+        let decoded: UtxoSnapshot = decode_all(bytes.clone()).unwrap();
+        assert_eq!(decoded.hash(), snapshot.hash());
+        assert_eq!(decoded.base_hash(), other.latest_block_hash());
+        let last = bytes.len() - 40;
+        bytes[last] ^= 1;
+        assert!(decode_all::<UtxoSnapshot>(bytes).is_err());
+
+        // A fresh node continues the chain from the snapshot and keeps it across restarts
+        let dir = tempfile::tempdir().unwrap();
+        let mut fresh = DB::open(dir.path()).unwrap();
+        fresh.load_snapshot(decoded).unwrap();
+        assert!(other.load_snapshot(snapshot).is_err());
+        assert_eq!(fresh.next_height(), 102);
+        assert!(fresh.blocks().is_empty());
+        let block = mine_next(&other, vec![]);
+        other.push_block(block.clone()).unwrap();
+        fresh.process_block(block).unwrap();
+        assert_eq!(fresh.latest_block_hash(), other.latest_block_hash());
+        assert_eq!(fresh.utxos, other.utxos);
+        drop(fresh);
+
+        let fresh = DB::open(dir.path()).unwrap();
+        assert_eq!(fresh.latest_block_hash(), other.latest_block_hash());
+        assert_eq!(fresh.utxos, other.utxos);
+        assert_eq!(fresh.blocks().len(), 1);
+        assert_eq!(fresh.account(0).utxos, other.account(0).utxos);
+    }
+
+    #[test]
+    fn test_prune() {
+        let storage = MemoryStorage::default();
+        let mut db = DB::load(Box::new(storage.clone())).unwrap();
+        db.prune = Some(PruneMode::KeepBlocks(0));
+        push_blocks(&mut db, 300);
+        let first = db.block_at(0).unwrap().header.hash();
+        db.prune_blocks().unwrap();
+
+        // Only the blocks within the safety depth are left
+        let info = db.prune_info();
+        assert!(info.pruned);
+        assert_eq!(info.prune_height, 300 - MIN_BLOCKS_TO_KEEP);
+        assert_eq!(db.blocks().len() as u64, MIN_BLOCKS_TO_KEEP);
+        assert!(db.block_at(0).is_none());
+        assert!(storage.read_block(&first, 0).is_err());
+        assert!(db.utxo_snapshot(5).is_err());
+        assert_eq!(
+            db.median_time_past(),
+            db.median_time_past_of(db.latest_block_hash())
+        );
+
+        db.push_block(mine_next(&db, vec![])).unwrap();
+        let tip = db.latest_block_hash();
+        let utxos = db.utxos.clone();
+        drop(db);
+
+        let db = DB::load(Box::new(storage)).unwrap();
+        assert_eq!(db.latest_block_hash(), tip);
+        assert_eq!(db.utxos, utxos);
+        assert_eq!(db.prune_info().prune_height, 300 - MIN_BLOCKS_TO_KEEP);
+        assert_eq!(db.block_index.len(), 301);
+    }
+
+    #[test]
+    fn test_other_genesis() {
+        let mut db = db_with_blocks(290);
+        let mut pruned = copy_chain(&db);
+        pruned.prune = Some(PruneMode::KeepBlocks(0));
+        pruned.prune_blocks().unwrap();
+        let tip = pruned.latest_block_hash();
+
+        // A chain with more work from another genesis block
+        let mut other = DB::new();
+        let miner_pk = Account::random(0).keys[0]
+            .public_key
+            .unwrap()
+            .to_sec1_bytes();
+        other
+            .push_block(mine(next_block(&other, BITS, miner_pk.to_vec(), vec![])))
+            .unwrap();
+        push_blocks(&mut other, 290);
+
+        // Without the undo data of its whole chain, the pruned node keeps it
+        for block in other.blocks() {
+            db.push_block(block.clone()).unwrap();
+            pruned.push_block(block).unwrap();
+        }
+        assert_eq!(pruned.latest_block_hash(), tip);
+        assert_eq!(db.latest_block_hash(), other.latest_block_hash());
+        assert_eq!(outpoints(&db), outpoints(&other));
+    }
+
+    #[test]
+    fn test_verify_chain() {
+        let storage = MemoryStorage::default();
+        let mut db = DB::load(Box::new(storage.clone())).unwrap();
+        push_blocks(&mut db, 101);
+        let receiver = db.create_account(Purpose::NativeSegwit).unwrap();
+        let tx = db
+            .account(0)
+            .transfer(&receiver, 1, db.next_height())
+            .unwrap();
+        db.push_block(mine_next(&db, vec![tx])).unwrap();
+        db.push_block(mine_next(&db, vec![])).unwrap();
+        for level in 0..=4 {
+            db.verify_chain(level, 0).unwrap();
+        }
+
+        // Undo data is checked from level 2 down to the given depth
+        let hash = db.active_chain[101];
+        storage.write_undo(&hash, &BlockUndo::default()).unwrap();
+        assert!(db.verify_chain(1, 0).is_ok());
+        assert!(db.verify_chain(2, 1).is_ok());
+        assert!(db.verify_chain(2, 2).is_err());
+        storage.write_undo(&hash, &db.undo[&hash]).unwrap();
+
+        // A block differing from the stored one without changing its merkle root
+        let tip = db.latest_block_hash();
+        let stored = db.blocks[&tip].clone();
+        let coinbase = &mut db.blocks.get_mut(&tip).unwrap().transactions[0];
+        coinbase.flag = Some(1);
+        coinbase.witnesses = vec![Witness(vec![PushBytes::from_bytes(vec![0; 32])])];
+        assert_eq!(
+            db.verify_chain(1, 1),
+            Err("Stored block differs from block in memory at height 102".to_owned())
+        );
+        db.blocks.insert(tip, stored);
+
+        // Outputs missing from the UTXO set are found by rolling it back near the tip and
+        // by rebuilding it otherwise
+        let tip = db.latest_block().unwrap();
+        let outpoint = (tip.transactions[0].txid(), 0);
+        let utxo = db.remove_utxo(&outpoint).unwrap();
+        assert!(db.verify_chain(2, 0).is_ok());
+        assert!(db.verify_chain(3, 1).is_err());
+        db.add_utxo(outpoint, utxo);
+
+        let (outpoint, _) = db.utxos.iter().find(|(_, utxo)| utxo.height == 10).unwrap();
+        let outpoint = *outpoint;
+        db.remove_utxo(&outpoint).unwrap();
+        assert!(db.verify_chain(3, 2).is_ok());
+        assert!(db.verify_chain(4, 2).is_err());
+    }
+
+    #[test]
+    fn test_invalidate_block() {
+        let mut db = db_with_blocks(101);
+        let receiver = db.create_account(Purpose::NativeSegwit).unwrap();
+        let tx = db
+            .account(0)
+            .transfer(&receiver, 1, db.next_height())
+            .unwrap();
+        db.push_block(mine_next(&db, vec![tx.clone()])).unwrap();
+        let tip = db.latest_block_hash();
+        assert!(db.invalidate_block(&[1; 32]).is_err());
+        assert!(db.invalidate_block(&db.active_chain[0].clone()).is_err());
+
+        // Invalidating the tip rewinds to its parent and gives back its transactions
+        let disconnected = db.invalidate_block(&tip).unwrap();
+        assert_eq!(disconnected.len(), 1);
+        assert_eq!(disconnected[0].txid(), tx.txid());
+        assert_eq!(db.next_height(), 101);
+        assert_eq!(db.account(receiver.index).balance(db.next_height()), 0);
+
+        // A block mined on the parent takes over, until the old tip is reconsidered and
+        // wins as the first seen block of equal work
+        let mut block = next_block(&db, BITS, PUBLIC_KEY.to_vec(), vec![]);
+        block.header.timestamp += 1;
+        db.push_block(mine(block)).unwrap();
+        assert_ne!(db.latest_block_hash(), tip);
+        assert!(db.reconsider_block(&tip).unwrap().is_empty());
+        assert_eq!(db.latest_block_hash(), tip);
+        assert_eq!(db.account(receiver.index).balance(db.next_height()), 1);
+
+        // Descendants of an invalidated block are invalid too, reconsidering one of them
+        // restores its ancestors
+        let middle = db.active_chain[99];
+        db.invalidate_block(&middle).unwrap();
+        assert_eq!(db.next_height(), 99);
+        assert!(db.block_index[&tip].invalid);
+        db.reconsider_block(&tip).unwrap();
+        assert_eq!(db.latest_block_hash(), tip);
+        assert!(!db.block_index[&middle].invalid);
+    }
+
+    #[test]
+    fn test_chain_archive() {
+        let mut db = db_with_blocks(101);
+        let receiver = db.create_account(Purpose::NativeSegwit).unwrap();
+        let imported = db.import_key(Key::new(vec![3; 32]).unwrap()).unwrap();
+        let watched = db
+            .import_key(Key::watch(Account::random(0).keys[0].public_key.unwrap()))
+            .unwrap();
+        let mut master = db.account(0);
+        let mut transactions = Vec::new();
+        for account in [&receiver, &imported] {
+            let tx = master.transfer(account, 1, db.next_height()).unwrap();
+            for tx_in in &tx.inputs {
+                master.utxos.remove(&(tx_in.txid, tx_in.output_index));
+            }
+            transactions.push(tx);
+        }
+        db.push_block(mine_next(&db, transactions)).unwrap();
+
+        // A fresh node connects the archived blocks and restores the wallet
+        let archive = db.chain_archive().unwrap();
+        let decoded: ChainArchive = decode_all(archive.encode()).unwrap();
+        let mut fresh = DB::new();
+        fresh.import_chain(decoded).unwrap();
+        assert_eq!(fresh.latest_block_hash(), db.latest_block_hash());
+        assert_eq!(fresh.utxos, db.utxos);
+        // Accounts that weren't derived come first after restoring the seed
+        let find = |db: &DB, account: &Account| {
+            db.accounts
+                .iter()
+                .find(|other| other.scripts().eq(account.scripts()))
+                .cloned()
+                .unwrap()
+        };
+        assert_eq!(fresh.accounts.len(), db.accounts.len());
+        for account in [&receiver, &imported] {
+            assert_eq!(find(&fresh, account).balance(fresh.next_height()), 1);
+        }
+        assert_eq!(
+            find(&fresh, &imported).keys[0].private_key,
+            imported.keys[0].private_key
+        );
+        assert!(find(&fresh, &watched).is_watch_only());
+        assert!(fresh.import_chain(archive.clone()).is_err());
+
+        // The seed and keys of an encrypted wallet stay encrypted, even while it's locked
+        let seed = fresh.seed.clone().unwrap();
+        fresh.encrypt_wallet("passphrase").unwrap();
+        let encrypted = fresh.chain_archive().unwrap();
+        assert!(matches!(encrypted.seed, SeedRecord::Encrypted(_)));
+        let plain_seed = serde_json::to_vec(&seed).unwrap();
+        assert!(!encrypted
+            .encode()
+            .windows(plain_seed.len())
+            .any(|bytes| bytes == plain_seed));
+        assert!(encrypted
+            .records
+            .iter()
+            .all(|record| record.private_key.is_none()));
+        let mut restored = DB::new();
+        restored
+            .import_chain(decode_all(encrypted.encode()).unwrap())
+            .unwrap();
+        assert!(restored.wallet_info().encrypted && restored.seed.is_none());
+        assert_eq!(restored.accounts.len(), db.accounts.len());
+        let height = restored.next_height();
+        assert_eq!(find(&restored, &receiver).balance(height), 1);
+        restored.unlock_wallet("passphrase", 0).unwrap();
+        assert_eq!(restored.seed, Some(seed));
+        let account = find(&restored, &imported);
+        assert_eq!(account.keys[0].private_key, imported.keys[0].private_key);
+        assert_eq!(account.balance(height), 1);
+
+        // Blocks are validated on import
+        let mut broken = archive;
+        broken.blocks.remove(50);
+        assert!(DB::new().import_chain(broken).is_err());
+    }
+
+    #[test]
+    fn test_derived_accounts() {
+        let storage = MemoryStorage::default();
+        let mut db = DB::load(Box::new(storage.clone())).unwrap();
+        let seed = vec![7; 32];
+        db.restore_seed(seed.clone()).unwrap();
+        let account = db.create_account(Purpose::NativeSegwit).unwrap();
+        assert_eq!(account.path, Some(account_path(Purpose::NativeSegwit, 0)));
+        // The next account of a purpose waits for the previous one to be used
+        assert!(db.create_account(Purpose::NativeSegwit).is_err());
+        let taproot = db.create_account(Purpose::Taproot).unwrap();
+        assert_eq!(taproot.path, Some(account_path(Purpose::Taproot, 0)));
+        let address = db.new_address(account.index, EXTERNAL).unwrap();
+        db.gap_limit = 2;
+        assert!(db.new_address(account.index, EXTERNAL).is_err());
+
+        // The same seed gives the same accounts, whose addresses are children of the xpub
+        let mut other = DB::new();
+        other.restore_seed(seed).unwrap();
+        let same = other.create_account(Purpose::NativeSegwit).unwrap();
+        assert_eq!(same.keys[0].public_key, account.keys[0].public_key);
+        let json = AccountJson::new(account, 0);
+        let xpub: ExtendedPublicKey = json.xpub.unwrap().parse().unwrap();
+        let child = xpub
+            .derive_child(EXTERNAL)
+            .unwrap()
+            .derive_child(1)
+            .unwrap();
+        assert_eq!(
+            StandardScript::P2WPKH(hash160(child.public_key.to_sec1_bytes().to_vec()).to_vec())
+                .into_script(),
+            address
+        );
+
+        let db = DB::load(Box::new(storage)).unwrap();
+        assert_eq!(db.account(1).keys.len(), 3);
+        assert_eq!(db.account(1).receive_key().scripts[0], address);
+        assert_eq!(db.account(2).purpose, Some(Purpose::Taproot));
+        assert_eq!(db.seed, Some(vec![7; 32]));
+        assert!(db.account(0).path.is_none());
+    }
+
+    #[test]
+    fn test_restore_wallet() {
+        let mnemonic: Mnemonic = "abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon about".parse().unwrap();
+        let seed = mnemonic.to_seed("").to_vec();
+        let mut db = db_with_blocks(101);
+        db.restore_wallet(seed.clone()).unwrap();
+        let segwit = db.create_account(Purpose::NativeSegwit).unwrap();
+        let legacy = db.create_account(Purpose::Legacy).unwrap();
+        db.new_address(segwit.index, EXTERNAL).unwrap();
+        // The third address of the external chain
+        let script = db.new_address(segwit.index, EXTERNAL).unwrap();
+        let mut master = db.account(0);
+        let mut transactions = Vec::new();
+        for script in [script, legacy.receive_key().scripts[0].clone()] {
+            let tx = master
+                .transfer_to_script(script, 1, db.next_height())
+                .unwrap();
+            for tx_in in &tx.inputs {
+                master.utxos.remove(&(tx_in.txid, tx_in.output_index));
+            }
+            transactions.push(tx);
+        }
+        db.push_block(mine_next(&db, transactions)).unwrap();
+        let second = db.create_account(Purpose::NativeSegwit).unwrap();
+        let tx = db
+            .account(0)
+            .transfer(&second, 1, db.next_height())
+            .unwrap();
+        db.push_block(mine_next(&db, vec![tx])).unwrap();
+        // The first account counts as used after spending all of its outputs
+        let tx = db
+            .account(segwit.index)
+            .transfer(&db.account(0), 1, db.next_height())
+            .unwrap();
+        db.push_block(mine_next(&db, vec![tx])).unwrap();
+        assert_eq!(db.account(segwit.index).balance(db.next_height()), 0);
+        // Funds of the derived accounts would be lost
+        assert!(db.restore_seed(vec![8; 32]).is_err());
+
+        let mut fresh = copy_chain(&db);
+        fresh.restore_seed(vec![8; 32]).unwrap();
+        assert_eq!(fresh.accounts.len(), 1);
+        // The used address is past the gap limit
+        fresh.gap_limit = 2;
+        assert_eq!(fresh.restore_wallet(seed.clone()).unwrap().len(), 2);
+
+        fresh.gap_limit = 3;
+        let accounts = fresh.restore_wallet(seed).unwrap();
+        let paths: Vec<_> = accounts
+            .iter()
+            .map(|account| account.path.clone())
+            .collect();
+        assert_eq!(
+            paths,
+            [
+                None,
+                Some(account_path(Purpose::Legacy, 0)),
+                Some(account_path(Purpose::NativeSegwit, 0)),
+                Some(account_path(Purpose::NativeSegwit, 1)),
+            ]
+        );
+        assert_eq!(accounts[2].chain_len(EXTERNAL), 3);
+        assert_eq!(accounts[2].chain_len(INTERNAL), 1);
+        let balances: Vec<_> = accounts[1..]
+            .iter()
+            .map(|account| account.balance(fresh.next_height()))
+            .collect();
+        assert_eq!(balances, [1, 0, 1]);
+        let scripts: HashSet<_> = db.script_owners.keys().collect();
+        assert_eq!(fresh.script_owners.keys().collect::<HashSet<_>>(), scripts);
+    }
+
+    #[test]
+    fn test_import_keys() {
+        let storage = MemoryStorage::default();
+        let mut db = DB::load(Box::new(storage.clone())).unwrap();
+        push_blocks(&mut db, 103);
+        let owned = Account::random(0);
+        let watched = Account::random(0);
+        let address = Account::random(0);
+        let mut master = db.account(0);
+        let mut transactions = Vec::new();
+        for script in [
+            owned.keys[0].scripts[1].clone(),
+            watched.keys[0].scripts[3].clone(),
+            address.keys[0].scripts[2].clone(),
+        ] {
+            let tx = master
+                .transfer_to_script(script, 1, db.next_height())
+                .unwrap();
+            for tx_in in &tx.inputs {
+                master.utxos.remove(&(tx_in.txid, tx_in.output_index));
+            }
+            transactions.push(tx);
+        }
+        db.push_block(mine_next(&db, transactions)).unwrap();
+
+        let imported = [
+            owned.keys[0].clone(),
+            Key::watch(watched.keys[0].public_key.unwrap()),
+            Key::watch_script(address.keys[0].scripts[2].clone()),
+        ]
+        .map(|key| db.import_key(key).unwrap());
+        for account in &imported {
+            assert_eq!(account.balance(db.next_height()), 1);
+        }
+        assert!(!imported[0].is_watch_only());
+        assert!(imported[1].is_watch_only() && imported[2].is_watch_only());
+        assert!(imported[1]
+            .transfer(&db.account(0), 1, db.next_height())
+            .is_err());
+        assert!(imported[0]
+            .transfer(&db.account(0), 1, db.next_height())
+            .is_ok());
+        // The watched script is already owned
+        assert!(db.import_key(owned.keys[0].clone()).is_err());
+        assert!(db.import_key(address.keys[0].clone()).is_err());
+
+        let reloaded = DB::load(Box::new(storage.clone())).unwrap();
+        for (account, imported) in reloaded.accounts[1..].iter().zip(&imported) {
+            assert!(account.scripts().eq(imported.scripts()));
+            assert_eq!(account.is_watch_only(), imported.is_watch_only());
+            assert_eq!(account.balance(db.next_height()), 1);
+        }
+        db.restore_seed(vec![8; 32]).unwrap();
+        assert_eq!(db.accounts.len(), 4);
+        assert_eq!(db.account(1).keys[0].private_key, owned.keys[0].private_key);
+        assert_eq!(db.account(3).balance(db.next_height()), 1);
+
+        // Malformed keys in the wallet fail to load instead of panicking
+        let mut records = storage.read_wallet().unwrap().unwrap();
+        for private_key in [vec![0; 32], vec![1; 31]] {
+            records[1].private_key = Some(private_key);
+            storage.write_wallet(&records).unwrap();
+            assert_eq!(
+                DB::load(Box::new(storage.clone())).err(),
+                Some("Invalid private key in wallet".to_owned())
+            );
+        }
+    }
+
+    #[test]
+    fn test_encrypted_wallet() {
+        let storage = MemoryStorage::default();
+        let mut db = DB::load(Box::new(storage.clone())).unwrap();
+        push_blocks(&mut db, 102);
+        let account = db.create_account(Purpose::NativeSegwit).unwrap();
+        let imported = db.import_key(Key::new(vec![3; 32]).unwrap()).unwrap();
+        let seed = db.seed.clone();
+        let fingerprint = db.wallet_info().fingerprint;
+        db.encrypt_wallet("passphrase").unwrap();
+        assert!(db.encrypt_wallet("other").is_err());
+        // Nothing is stored in the clear
+        assert!(matches!(
+            storage.read_seed().unwrap(),
+            Some(SeedRecord::Encrypted(_))
+        ));
+        let records = storage.read_wallet().unwrap().unwrap();
+        assert!(records.iter().all(|record| record.private_key.is_none()));
+
+        assert!(db.seed.is_none());
+        assert_eq!(db.wallet_info().fingerprint, fingerprint);
+        let err = db
+            .account(0)
+            .transfer(&account, 1, db.next_height())
+            .unwrap_err();
+        assert_eq!(err, "Wallet is locked");
+        assert!(!db.account(0).is_watch_only());
+        assert!(db.create_account(Purpose::Legacy).is_err());
+        assert!(db.import_key(Key::new(vec![4; 32]).unwrap()).is_err());
+        // Addresses are derived from the xpub of the account
+        let script = db.new_address(account.index, EXTERNAL).unwrap();
+
+        db.unlock_wallet("passphrase", 100).unwrap();
+        assert_eq!(db.wallet_info().unlocked_until, Some(100));
+        assert_eq!(db.seed, seed);
+        let tx = db
+            .account(0)
+            .transfer(&db.account(account.index), 1, db.next_height())
+            .unwrap();
+        db.push_block(mine_next(&db, vec![tx])).unwrap();
+        let key = db.account(account.index).receive_key().clone();
+        assert_eq!(key.scripts, [script]);
+        assert!(key.private_key.is_some());
+        assert_eq!(
+            db.account(imported.index).keys[0].private_key,
+            imported.keys[0].private_key
+        );
+        db.lock_wallet().unwrap();
+        assert!(db.account(0).keys[0].private_key.is_none());
+        db.change_passphrase("passphrase", "new").unwrap();
+
+        let mut reloaded = DB::load(Box::new(storage)).unwrap();
+        assert!(reloaded.wallet_info().encrypted);
+        assert!(reloaded.seed.is_none());
+        assert_eq!(reloaded.account(account.index).balance(db.next_height()), 1);
+        assert!(reloaded.unlock_wallet("passphrase", 0).is_err());
+        reloaded.unlock_wallet("new", 0).unwrap();
+        let tx = reloaded
+            .account(account.index)
+            .transfer(&reloaded.account(imported.index), 1, db.next_height())
+            .unwrap();
+        reloaded.validate_transaction(&tx, &HashMap::new()).unwrap();
+    }
+
+    #[test]
+    fn test_out_of_order() {
+        let mut db = DB::new();
+        let mut other = DB::new();
+        let mut blocks = Vec::new();
+        for _ in 0..4 {
+            let block = mine_next(&other, vec![]);
+            other.push_block(block.clone()).unwrap();
+            blocks.push(block);
+        }
+
+        for block in blocks.iter().skip(1).rev() {
+            assert!(db.process_block(block.clone()).unwrap().is_empty());
+        }
+        assert_eq!(db.orphans.len(), 3);
+        assert_eq!(db.next_height(), 0);
+        assert!(db.process_block(blocks[3].clone()).is_err());
+
+        db.process_block(blocks[0].clone()).unwrap();
+        assert!(db.orphans.is_empty());
+        assert_eq!(db.latest_block_hash(), other.latest_block_hash());
+        assert_eq!(outpoints(&db), outpoints(&other));
+    }
+
+    #[test]
+    fn test_reload() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut db = DB::open(dir.path()).unwrap();
+        for _ in 0..3 {
+            db.process_block(mine_next(&db, vec![])).unwrap();
+        }
+        let account = db.create_account(Purpose::NativeSegwit).unwrap();
+        db.flush().unwrap();
+        // Connected after the last flush
+        db.process_block(mine_next(&db, vec![])).unwrap();
+        let tip = db.latest_block_hash();
+        let utxos = db.utxos.clone();
+        drop(db);
+
+        let db = DB::open(dir.path()).unwrap();
+        assert_eq!(db.latest_block_hash(), tip);
+        assert_eq!(db.next_height(), 4);
+        assert_eq!(db.utxos, utxos);
+        assert_eq!(db.undo.len(), 4);
+        assert_eq!(db.account(0).utxos.len(), 4);
+        assert_eq!(
+            db.account(account.index).receive_key().public_key,
+            account.receive_key().public_key
+        );
+        assert_eq!(db.blocks()[2].header.height, 2);
+    }
+
+    #[test]
+    fn test_reload_from_memory() {
+        let storage = MemoryStorage::default();
+        let mut db = DB::load(Box::new(storage.clone())).unwrap();
+        for _ in 0..2 {
+            db.process_block(mine_next(&db, vec![])).unwrap();
+        }
+        let tip = db.latest_block_hash();
+        drop(db);
+
+        let db = DB::load(Box::new(storage)).unwrap();
+        assert_eq!(db.latest_block_hash(), tip);
+        assert_eq!(db.block_at(1).unwrap().header.hash(), tip);
+        assert_eq!(db.account(0).utxos.len(), 2);
+    }
+
+    #[test]
+    fn test_corrupted_block() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut db = DB::open(dir.path()).unwrap();
+        db.process_block(mine_next(&db, vec![])).unwrap();
+        let tip = db.latest_block_hash();
+        drop(db);
+
+        let path = dir
+            .path()
+            .join("blocks")
+            .join(format!("{}.dat", bitaekcoin::utils::to_hex(&tip)));
+        let mut bytes = std::fs::read(&path).unwrap();
+        bytes[4] ^= 1;
+        std::fs::write(&path, bytes).unwrap();
+        assert!(DB::open(dir.path()).is_err());
+    }
+
+    #[test]
+    fn test_storage_failure() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut db = DB::open(dir.path()).unwrap();
+        db.process_block(mine_next(&db, vec![])).unwrap();
+        let tip = db.latest_block_hash();
+        let utxos = outpoints(&db);
+
+        // Undo data can't be written, the block stays unconnected
+        std::fs::remove_dir_all(dir.path().join("undo")).unwrap();
+        let block = mine_next(&db, vec![]);
+        assert!(db.process_block(block.clone()).is_err());
+        assert_eq!(db.latest_block_hash(), tip);
+        assert_eq!(outpoints(&db), utxos);
+        assert!(!db.block_index[&block.header.hash()].invalid);
+    }
+
+    #[test]
+    fn test_invalid_branch() {
+        let mut db = db_with_blocks(2);
+        let mut other = copy_chain(&db);
+        db.push_block(mine_next(&db, vec![])).unwrap();
+        let tip = db.latest_block_hash();
+
+        // The second block of the branch spends an immature coinbase
+        let receiver = Account::random(1);
+        let tx = other.account(0).transfer(&receiver, 1, 200).unwrap();
+        let mut first = next_block(&other, BITS, PUBLIC_KEY.to_vec(), vec![]);
+        first.header.timestamp += 1;
+        let first = mine(first);
+        other.push_block(first.clone()).unwrap();
+        let second = mine_next(&other, vec![tx]);
+
+        db.push_block(first).unwrap();
+        assert_eq!(db.latest_block_hash(), tip);
+        assert!(db.push_block(second).is_err());
+        assert_eq!(db.latest_block_hash(), tip);
+        assert_eq!(db.next_height(), 3);
     }
 }
@@ -0,0 +1,260 @@
+use std::collections::{HashMap, HashSet};
+
+use serde::{Deserialize, Serialize};
+
+use bitaekcoin::{
+    address::Address,
+    encode::Encodable,
+    script::Script,
+    transaction::{Transaction, TxID, TxOut},
+    utils::to_hex,
+};
+
+use crate::NETWORK;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum TxDirection {
+    Mined,
+    Received,
+    Sent,
+    // Spends outputs of the account and pays only back to it
+    SelfTransfer,
+}
+
+// Transaction of an account, seen through the scripts of the account
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WalletTx {
+    pub txid: TxID,
+    pub direction: TxDirection,
+    // Change of the balance of the account
+    pub amount: i64,
+    // Only of transactions funded by the account
+    pub fee: Option<u64>,
+    // Scripts paid by a sent transaction, or spent by a received one
+    pub counterparties: Vec<Script>,
+    // None while the transaction is in the mempool
+    pub height: Option<u64>,
+}
+
+impl WalletTx {
+    // `spent` holds the outputs spent by the inputs in order, None if `tx` neither spends from
+    // nor pays to `scripts`
+    pub fn new(
+        tx: &Transaction,
+        spent: &[TxOut],
+        scripts: &HashSet<Script>,
+        height: Option<u64>,
+    ) -> Option<Self> {
+        let is_own = |tx_out: &&TxOut| scripts.contains(&tx_out.script_pub_key);
+        let received: u64 = tx.outputs.iter().filter(is_own).map(|o| o.amount).sum();
+        let sent: u64 = spent.iter().filter(is_own).map(|o| o.amount).sum();
+        if received == 0 && sent == 0 {
+            return None;
+        }
+
+        let others = |tx_outs: &[TxOut]| {
+            let mut counterparties = Vec::new();
+            for tx_out in tx_outs.iter().filter(|tx_out| !is_own(tx_out)) {
+                if !counterparties.contains(&tx_out.script_pub_key) {
+                    counterparties.push(tx_out.script_pub_key.clone());
+                }
+            }
+            counterparties
+        };
+        let (direction, counterparties) = if tx.is_coinbase() {
+            (TxDirection::Mined, vec![])
+        } else if sent == 0 {
+            (TxDirection::Received, others(spent))
+        } else {
+            match others(&tx.outputs) {
+                others if others.is_empty() => (TxDirection::SelfTransfer, others),
+                others => (TxDirection::Sent, others),
+            }
+        };
+        let fee = match sent {
+            0 => None,
+            _ => tx.fee(&spent_outputs(tx, spent)),
+        };
+        Some(Self {
+            txid: tx.txid(),
+            direction,
+            amount: received as i64 - sent as i64,
+            fee,
+            counterparties,
+            height,
+        })
+    }
+}
+
+fn spent_outputs(tx: &Transaction, spent: &[TxOut]) -> HashMap<(TxID, u32), TxOut> {
+    tx.inputs
+        .iter()
+        .map(|tx_in| (tx_in.txid, tx_in.output_index))
+        .zip(spent.iter().cloned())
+        .collect()
+}
+
+// Transactions of the mempool in order, `prevout` looks up the confirmed outputs they spend.
+// Transactions spending unknown outputs are left out.
+pub fn pending_history(
+    transactions: &[Transaction],
+    scripts: &HashSet<Script>,
+    prevout: impl Fn(&(TxID, u32)) -> Option<TxOut>,
+) -> Vec<WalletTx> {
+    let mut history = Vec::new();
+    let mut created: HashMap<(TxID, u32), TxOut> = HashMap::new();
+    for tx in transactions {
+        let spent: Option<Vec<_>> = tx
+            .inputs
+            .iter()
+            .map(|tx_in| {
+                let outpoint = (tx_in.txid, tx_in.output_index);
+                created
+                    .get(&outpoint)
+                    .cloned()
+                    .or_else(|| prevout(&outpoint))
+            })
+            .collect();
+        if let Some(wallet_tx) = spent.and_then(|spent| WalletTx::new(tx, &spent, scripts, None)) {
+            history.push(wallet_tx);
+        }
+        let txid = tx.txid();
+        for (i, tx_out) in tx.outputs.iter().enumerate() {
+            created.insert((txid, i as u32), tx_out.clone());
+        }
+    }
+    history
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WalletTxJson {
+    pub txid: String,
+    pub direction: TxDirection,
+    pub amount: i64,
+    pub fee: Option<u64>,
+    // Addresses, or scripts in hex if they have none
+    pub counterparties: Vec<String>,
+    pub height: Option<u64>,
+    pub confirmations: u64,
+}
+
+impl WalletTxJson {
+    // Confirmations are counted as seen by a transaction included at `height`
+    pub fn new(wallet_tx: WalletTx, height: u64) -> Self {
+        Self {
+            txid: to_hex(&wallet_tx.txid),
+            direction: wallet_tx.direction,
+            amount: wallet_tx.amount,
+            fee: wallet_tx.fee,
+            counterparties: wallet_tx
+                .counterparties
+                .iter()
+                .map(|script| match Address::from_script(script, NETWORK) {
+                    Some(address) => address.to_string(),
+                    None => to_hex(&script.encode()),
+                })
+                .collect(),
+            height: wallet_tx.height,
+            confirmations: wallet_tx.height.map_or(0, |tx_height| height - tx_height),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bitaekcoin::{
+        encode::VarInt,
+        script::StandardScript,
+        transaction::{TxIn, Witness},
+    };
+
+    use super::*;
+
+    fn script(byte: u8) -> Script {
+        StandardScript::P2WPKH(vec![byte; 20]).into_script()
+    }
+
+    fn tx_out(byte: u8, amount: u64) -> TxOut {
+        TxOut {
+            amount,
+            script_size: VarInt(22),
+            script_pub_key: script(byte),
+        }
+    }
+
+    fn transaction(inputs: usize, outputs: Vec<TxOut>) -> Transaction {
+        Transaction {
+            version: 1,
+            flag: None,
+            inputs: (0..inputs)
+                .map(|i| TxIn {
+                    txid: [1; 32],
+                    output_index: i as u32,
+                    script_size: VarInt(0),
+                    script_sig: Script(vec![]),
+                    sequence: 0xffffffff,
+                })
+                .collect(),
+            outputs,
+            witnesses: Vec::<Witness>::new(),
+            lock_time: 0,
+        }
+    }
+
+    #[test]
+    fn test_wallet_tx() {
+        let scripts = HashSet::from_iter([script(0), script(1)]);
+
+        // Pays 600 to another script and 300 back as change, with a fee of 100
+        let tx = transaction(2, vec![tx_out(2, 600), tx_out(1, 300)]);
+        let spent = [tx_out(0, 500), tx_out(0, 500)];
+        let wallet_tx = WalletTx::new(&tx, &spent, &scripts, Some(3)).unwrap();
+        assert_eq!(wallet_tx.direction, TxDirection::Sent);
+        assert_eq!(wallet_tx.amount, -700);
+        assert_eq!(wallet_tx.fee, Some(100));
+        assert_eq!(wallet_tx.counterparties, vec![script(2)]);
+        let json = WalletTxJson::new(wallet_tx, 5);
+        assert_eq!(json.confirmations, 2);
+        assert!(json.counterparties[0].starts_with("bcrt1q"));
+
+        // The recipient sees the spent scripts of the sender and no fee
+        let other = HashSet::from_iter([script(2)]);
+        let wallet_tx = WalletTx::new(&tx, &spent, &other, None).unwrap();
+        assert_eq!(wallet_tx.direction, TxDirection::Received);
+        assert_eq!(wallet_tx.amount, 600);
+        assert_eq!(wallet_tx.fee, None);
+        assert_eq!(wallet_tx.counterparties, vec![script(0)]);
+        assert_eq!(WalletTxJson::new(wallet_tx, 5).confirmations, 0);
+
+        let tx = transaction(1, vec![tx_out(1, 900)]);
+        let wallet_tx = WalletTx::new(&tx, &[tx_out(0, 1000)], &scripts, Some(3)).unwrap();
+        assert_eq!(wallet_tx.direction, TxDirection::SelfTransfer);
+        assert_eq!(wallet_tx.amount, -100);
+        assert!(wallet_tx.counterparties.is_empty());
+
+        let tx = transaction(1, vec![tx_out(2, 900)]);
+        assert!(WalletTx::new(&tx, &[tx_out(3, 1000)], &scripts, Some(3)).is_none());
+    }
+
+    #[test]
+    fn test_pending_history() {
+        let scripts = HashSet::from_iter([script(0)]);
+        let tx1 = transaction(1, vec![tx_out(0, 800)]);
+        let mut tx2 = transaction(1, vec![tx_out(2, 700)]);
+        tx2.inputs[0].txid = tx1.txid();
+        tx2.inputs[0].output_index = 0;
+        // Spends an output that is neither confirmed nor pending
+        let mut tx3 = transaction(1, vec![tx_out(0, 100)]);
+        tx3.inputs[0].txid = [9; 32];
+
+        let history = pending_history(&[tx1, tx2, tx3], &scripts, |outpoint| {
+            (outpoint.0 == [1; 32]).then(|| tx_out(2, 1000))
+        });
+        assert_eq!(history.len(), 2);
+        assert_eq!(history[0].direction, TxDirection::Received);
+        assert_eq!(history[1].direction, TxDirection::Sent);
+        assert_eq!(history[1].amount, -800);
+        assert_eq!(history[1].fee, Some(100));
+        assert!(history.iter().all(|wallet_tx| wallet_tx.height.is_none()));
+    }
+}
@@ -0,0 +1,109 @@
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+use bitaekcoin::crypto::{decrypt, encrypt, scrypt};
+
+// scrypt parameters of deriving the key of a passphrase, the ones of BIP38
+pub const SCRYPT_LOG_N: u8 = 14;
+// Costs accepted from a keystore file, larger ones would take too long or too much memory
+const SCRYPT_LOG_N_RANGE: std::ops::RangeInclusive<u8> = 10..=20;
+const SCRYPT_R: u32 = 8;
+const SCRYPT_P: u32 = 1;
+
+// Random key encrypting the wallet seed and the private keys that weren't derived
+pub type WalletKey = [u8; 32];
+
+// Secrets of an encrypted wallet. The wallet key is itself encrypted with a key derived from
+// the passphrase, so changing the passphrase only encrypts the wallet key again.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Keystore {
+    pub salt: Vec<u8>,
+    pub log_n: u8,
+    pub wallet_key: Vec<u8>,
+    pub seed: Vec<u8>,
+    // Fingerprint of the master key, shown while the wallet is locked
+    pub fingerprint: [u8; 4],
+}
+
+impl Keystore {
+    pub fn new(passphrase: &str, seed: &[u8], fingerprint: [u8; 4]) -> (Self, WalletKey) {
+        let mut wallet_key = [0; 32];
+        rand::thread_rng().fill(&mut wallet_key[..]);
+        let mut keystore = Self {
+            salt: vec![],
+            log_n: SCRYPT_LOG_N,
+            wallet_key: vec![],
+            seed: encrypt_secret(&wallet_key, seed),
+            fingerprint,
+        };
+        keystore.set_passphrase(passphrase, &wallet_key);
+        (keystore, wallet_key)
+    }
+
+    pub fn unlock(&self, passphrase: &str) -> Result<WalletKey, String> {
+        if !SCRYPT_LOG_N_RANGE.contains(&self.log_n) {
+            return Err("Unsupported scrypt parameters".to_owned());
+        }
+        let key = passphrase_key(passphrase, &self.salt, self.log_n)
+            .ok_or("Unsupported scrypt parameters")?;
+        decrypt(&key, &self.wallet_key)
+            .ok()
+            .and_then(|wallet_key| wallet_key.try_into().ok())
+            .ok_or_else(|| "Incorrect passphrase".to_owned())
+    }
+
+    pub fn change_passphrase(&mut self, old: &str, new: &str) -> Result<(), String> {
+        let wallet_key = self.unlock(old)?;
+        self.set_passphrase(new, &wallet_key);
+        Ok(())
+    }
+
+    fn set_passphrase(&mut self, passphrase: &str, wallet_key: &WalletKey) {
+        self.salt = rand::random::<[u8; 16]>().to_vec();
+        self.log_n = SCRYPT_LOG_N;
+        let key = passphrase_key(passphrase, &self.salt, self.log_n).unwrap();
+        self.wallet_key = encrypt(&key, rand::random(), wallet_key);
+    }
+}
+
+pub fn encrypt_secret(wallet_key: &WalletKey, secret: &[u8]) -> Vec<u8> {
+    encrypt(wallet_key, rand::random(), secret)
+}
+
+pub fn decrypt_secret(wallet_key: &WalletKey, data: &[u8]) -> Result<Vec<u8>, String> {
+    decrypt(wallet_key, data).map_err(|_| "Corrupted wallet secret".to_owned())
+}
+
+fn passphrase_key(passphrase: &str, salt: &[u8], log_n: u8) -> Option<WalletKey> {
+    scrypt(passphrase.as_bytes(), salt, log_n, SCRYPT_R, SCRYPT_P, 32)?
+        .try_into()
+        .ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_scrypt_cost() {
+        let mut keystore = Keystore {
+            salt: vec![0; 16],
+            log_n: 0,
+            wallet_key: vec![],
+            seed: vec![],
+            fingerprint: [0; 4],
+        };
+        for log_n in [0, 9, 21, 64, 255] {
+            keystore.log_n = log_n;
+            assert_eq!(
+                keystore.unlock("passphrase"),
+                Err("Unsupported scrypt parameters".to_owned())
+            );
+        }
+        keystore.log_n = 10;
+        assert_eq!(
+            keystore.unlock("passphrase"),
+            Err("Incorrect passphrase".to_owned())
+        );
+    }
+}
@@ -1,12 +1,34 @@
+use bitaekcoin::address::Network;
 use hex_literal::hex;
 
 pub mod account;
+pub mod address_index;
+pub mod archive;
+pub mod block_index;
+pub mod chainstate;
+pub mod coin_selection;
 pub mod database;
+pub mod history;
+pub mod keystore;
 pub mod mempool;
 pub mod node;
+pub mod orphan;
+pub mod prune;
 pub mod server;
+pub mod snapshot;
+pub mod storage;
+pub mod time;
+pub mod tx_builder;
+pub mod tx_index;
+pub mod undo;
+pub mod utxo;
 
+const NETWORK: Network = Network::Regtest;
+// Difficulty target of every block, trivial in tests to mine blocks instantly
+#[cfg(not(test))]
 const BITS: u32 = 0x1f00ffff;
+#[cfg(test)]
+const BITS: u32 = 0x207fffff;
 const MINING_REWARD: u64 = 100000000;
 const PUBLIC_KEY: [u8; 33] =
     hex!("037e96a57281401690c12c3509f2e7414163554a7029fe28e6e784dbaf2348882d");
@@ -1,15 +1,86 @@
 use std::sync::{Arc, RwLock};
 
-use masternode::{database::DB, mempool::Mempool, node::Node, server::run_server};
+use bitaekcoin::{encode::decode_all, utils::from_hex};
+use masternode::{
+    account::DEFAULT_GAP_LIMIT,
+    chainstate::ChainState,
+    database::{DB, DEFAULT_CHECK_BLOCKS, DEFAULT_CHECK_LEVEL},
+    mempool::Mempool,
+    node::Node,
+    prune::PruneMode,
+    server::run_server,
+};
+
+const USAGE: &str = "Usage: masternode [--txindex] [--addressindex] [--loadsnapshot <file>] \
+    [--importchain <file>] [--seed <hex>] [--gaplimit <N>] [--prune <MB>] [--pruneblocks <N>] \
+    [--checklevel <0-4>] [--checkblocks <N>] [data dir]";
 
 fn main() {
+    let mut data_dir = "data".to_owned();
+    let mut tx_index = false;
+    let mut address_index = false;
+    let mut snapshot = None;
+    let mut archive = None;
+    let mut seed = None;
+    let mut gap_limit = DEFAULT_GAP_LIMIT;
+    let mut prune = None;
+    let mut check_level = DEFAULT_CHECK_LEVEL;
+    let mut check_blocks = DEFAULT_CHECK_BLOCKS;
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--txindex" => tx_index = true,
+            "--addressindex" => address_index = true,
+            "--loadsnapshot" => snapshot = args.next(),
+            "--importchain" => archive = args.next(),
+            "--seed" => seed = args.next(),
+            "--gaplimit" => gap_limit = parse(&args.next().unwrap_or_default()).max(1) as usize,
+            "--prune" => prune = args.next().map(|mb| PruneMode::MaxBytes(parse(&mb) << 20)),
+            "--pruneblocks" => prune = args.next().map(|n| PruneMode::KeepBlocks(parse(&n))),
+            "--checklevel" => check_level = parse(&args.next().unwrap_or_default()) as u32,
+            "--checkblocks" => check_blocks = parse(&args.next().unwrap_or_default()),
+            // A mistyped option would otherwise become the data directory
+            _ if arg.starts_with("--") => {
+                eprintln!("Unknown option {}\n{}", arg, USAGE);
+                std::process::exit(1);
+            }
+            _ => data_dir = arg,
+        }
+    }
+    let mut db = DB::open(&data_dir).expect("Failed to load data directory");
+    db.verify_chain(check_level, check_blocks)
+        .expect("Chain state is corrupted");
+    db.gap_limit = gap_limit;
+    if let Some(seed) = seed.map(|seed| from_hex(&seed).expect("Invalid seed")) {
+        if Some(&seed) != db.seed.as_ref() {
+            db.restore_seed(seed).expect("Failed to restore seed");
+        }
+    }
+    if let Some(path) = snapshot {
+        let bytes = std::fs::read(path).expect("Failed to read snapshot");
+        let snapshot = decode_all(bytes).expect("Invalid snapshot");
+        db.load_snapshot(snapshot).expect("Failed to load snapshot");
+    }
+    if let Some(path) = archive {
+        let bytes = std::fs::read(path).expect("Failed to read chain archive");
+        let archive = decode_all(bytes).expect("Invalid chain archive");
+        db.import_chain(archive).expect("Failed to import chain");
+    }
+    db.prune = prune;
+    if tx_index {
+        db.enable_tx_index();
+    }
+    if address_index {
+        db.enable_address_index();
+    }
+
     let mempool = Arc::new(RwLock::new(Mempool::new()));
     let mempool2 = mempool.clone();
-    let db = Arc::new(RwLock::new(DB::new()));
+    let db = Arc::new(RwLock::new(db));
     let db2 = db.clone();
 
     let server_thread = std::thread::spawn(move || {
-        run_server(mempool, db);
+        run_server(mempool, db, data_dir.into());
     });
 
     let node_thread = std::thread::spawn(move || {
@@ -20,3 +91,7 @@ fn main() {
     server_thread.join().unwrap();
     node_thread.join().unwrap();
 }
+
+fn parse(arg: &str) -> u64 {
+    arg.parse().expect("Invalid number")
+}
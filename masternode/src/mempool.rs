@@ -1,6 +1,6 @@
-use std::collections::{HashSet, VecDeque};
+use std::collections::{HashMap, HashSet, VecDeque};
 
-use bitaekcoin::transaction::Transaction;
+use bitaekcoin::transaction::{Transaction, TxID, TxOut};
 
 pub struct Mempool {
     pub transactions: VecDeque<Transaction>,
@@ -22,13 +22,28 @@ impl Mempool {
         }
         for tx_in in &new_tx.inputs {
             if !tx_ins.insert((tx_in.txid, tx_in.output_index)) {
-                return Err("Already spent utxo".to_owned());
+                return Err("Output already spent by a pending transaction".to_owned());
             }
         }
         self.transactions.push_back(new_tx);
         Ok(())
     }
 
+    // Outputs of pending transactions that no other pending transaction spends
+    pub fn outputs(&self) -> HashMap<(TxID, u32), TxOut> {
+        let mut outputs = HashMap::new();
+        for tx in &self.transactions {
+            for tx_in in &tx.inputs {
+                outputs.remove(&(tx_in.txid, tx_in.output_index));
+            }
+            let txid = tx.txid();
+            for (i, tx_out) in tx.outputs.iter().enumerate() {
+                outputs.insert((txid, i as u32), tx_out.clone());
+            }
+        }
+        outputs
+    }
+
     pub fn pop(&mut self) -> Vec<Transaction> {
         self.transactions.drain(..).collect()
     }
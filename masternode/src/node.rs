@@ -1,27 +1,32 @@
 use std::{
+    collections::HashMap,
     sync::{Arc, RwLock},
-    time::{SystemTime, UNIX_EPOCH},
 };
 
 use bitaekcoin::{
     block::{Block, BlockHeader},
     encode::{Encodable, VarInt},
     hash::merkle_root,
-    script::{Script, StandardScript},
+    script::{
+        instruction::{Instruction, PushBytes},
+        Script, StandardScript,
+    },
     transaction::{Transaction, TxIn, TxOut},
 };
 
-use crate::{database::DB, mempool::Mempool, BITS, MINING_REWARD, PUBLIC_KEY};
+use crate::{
+    chainstate::ChainState, mempool::Mempool, time::adjusted_time, BITS, MINING_REWARD, PUBLIC_KEY,
+};
 
-pub struct Node {
+pub struct Node<C> {
     pub bits: u32,
     pub public_key: Vec<u8>,
     pub mempool: Arc<RwLock<Mempool>>,
-    pub db: Arc<RwLock<DB>>,
+    pub db: Arc<RwLock<C>>,
 }
 
-impl Node {
-    pub fn new(mempool: Arc<RwLock<Mempool>>, db: Arc<RwLock<DB>>) -> Self {
+impl<C: ChainState> Node<C> {
+    pub fn new(mempool: Arc<RwLock<Mempool>>, db: Arc<RwLock<C>>) -> Self {
         Self {
             bits: BITS,
             public_key: PUBLIC_KEY.to_vec(),
@@ -32,51 +37,145 @@ impl Node {
 
     pub fn run(self) {
         loop {
-            let block = self.db.read().unwrap().latest_block();
-            let (height, prev_block_hash) = match block {
-                Some(block) => (block.header.height + 1, block.header.hash()),
-                None => (0, [0; 32]),
-            };
             let transactions = self.mempool.write().unwrap().pop();
-            let mut block = initialize_block(
-                height,
-                prev_block_hash,
+            let block = next_block(
+                &*self.db.read().unwrap(),
                 self.bits,
                 self.public_key.clone(),
                 transactions,
             );
-            let utxos = self.db.read().unwrap().utxos.clone();
-            loop {
-                if block.validate(&utxos) {
-                    self.db.write().unwrap().push_block(block);
-                    break;
-                }
-                block.header.nonce += 1;
+            let block = mine(block);
+            let mined = block.transactions[1..].to_vec();
+            // A block that fails to connect, e.g. as the tip moved while mining, is dropped
+            let disconnected = self
+                .db
+                .write()
+                .unwrap()
+                .process_block(block)
+                .unwrap_or_default();
+            // Transactions of a mined block that failed or went stale return to the mempool
+            update_mempool(&self.db, &self.mempool, [disconnected, mined].concat());
+        }
+    }
+}
+
+// Adds a block received from elsewhere, returning the transactions of disconnected blocks to the
+// mempool and evicting the ones the new chain confirms or conflicts with
+pub fn submit_block(
+    db: &RwLock<impl ChainState>,
+    mempool: &RwLock<Mempool>,
+    block: Block,
+) -> Result<(), String> {
+    let disconnected = db.write().unwrap().process_block(block)?;
+    update_mempool(db, mempool, disconnected);
+    Ok(())
+}
+
+pub fn invalidate_block(
+    db: &RwLock<impl ChainState>,
+    mempool: &RwLock<Mempool>,
+    hash: &[u8; 32],
+) -> Result<(), String> {
+    let disconnected = db.write().unwrap().invalidate_block(hash)?;
+    update_mempool(db, mempool, disconnected);
+    Ok(())
+}
+
+pub fn reconsider_block(
+    db: &RwLock<impl ChainState>,
+    mempool: &RwLock<Mempool>,
+    hash: &[u8; 32],
+) -> Result<(), String> {
+    let disconnected = db.write().unwrap().reconsider_block(hash)?;
+    update_mempool(db, mempool, disconnected);
+    Ok(())
+}
+
+// Revalidates the pending transactions against the active chain after it changed, following
+// the `restored` ones that left it. Confirmed transactions and conflicting ones are dropped.
+fn update_mempool(
+    db: &RwLock<impl ChainState>,
+    mempool: &RwLock<Mempool>,
+    restored: Vec<Transaction>,
+) {
+    let db = db.read().unwrap();
+    let mut mempool = mempool.write().unwrap();
+    let pending = mempool.pop();
+    for tx in restored.into_iter().chain(pending) {
+        if db.validate_transaction(&tx, &mempool.outputs()).is_ok() {
+            let _ = mempool.push(tx);
+        }
+    }
+}
+
+// Template for a block on top of the latest block of `db`
+pub(crate) fn next_block(
+    db: &impl ChainState,
+    bits: u32,
+    miner_pk: Vec<u8>,
+    transactions: Vec<Transaction>,
+) -> Block {
+    let height = db.next_height();
+    let prev_block_hash = db.latest_block_hash();
+    // The timestamp has to exceed the median time past even if blocks come faster than a second
+    let timestamp = adjusted_time().max(db.median_time_past() + 1);
+    // Including fees of transactions spending outputs of earlier ones
+    let mut tx_outs = HashMap::new();
+    let mut fees = 0;
+    for tx in &transactions {
+        for tx_in in &tx.inputs {
+            let outpoint = (tx_in.txid, tx_in.output_index);
+            if let Some(utxo) = db.utxo(&outpoint) {
+                tx_outs.insert(outpoint, utxo.tx_out);
             }
         }
+        fees += tx.fee(&tx_outs).unwrap_or(0);
+        let txid = tx.txid();
+        for (i, tx_out) in tx.outputs.iter().enumerate() {
+            tx_outs.insert((txid, i as u32), tx_out.clone());
+        }
     }
+    initialize_block(
+        height,
+        prev_block_hash,
+        timestamp,
+        bits,
+        miner_pk,
+        MINING_REWARD + fees,
+        transactions,
+    )
 }
 
-fn initialize_block(
+pub(crate) fn mine(mut block: Block) -> Block {
+    while !block.header.validate() {
+        block.header.nonce += 1;
+    }
+    block
+}
+
+pub(crate) fn initialize_block(
     height: u64,
     prev_block_hash: [u8; 32],
+    timestamp: u32,
     bits: u32,
     miner_pk: Vec<u8>,
+    reward: u64,
     mut transactions: Vec<Transaction>,
 ) -> Block {
     let output_script = StandardScript::P2PK(miner_pk).into_script();
+    let coinbase_script = coinbase_script(height);
     let coinbase_transaction = Transaction {
         version: 1,
         flag: None,
         inputs: vec![TxIn {
             txid: [0; 32],
             output_index: 0xffffffff,
-            script_size: VarInt(0),
-            script_sig: Script(vec![]),
+            script_size: VarInt(coinbase_script.encode().len() as u64),
+            script_sig: coinbase_script,
             sequence: 0,
         }],
         outputs: vec![TxOut {
-            amount: MINING_REWARD,
+            amount: reward,
             script_size: VarInt(output_script.encode().len() as u64),
             script_pub_key: output_script,
         }],
@@ -93,7 +192,7 @@ fn initialize_block(
             version: 1,
             prev_block_hash,
             merkle_root,
-            timestamp: get_timestamp(),
+            timestamp,
             bits,
             nonce: 0,
         },
@@ -101,18 +200,39 @@ fn initialize_block(
     }
 }
 
-fn get_timestamp() -> u32 {
-    SystemTime::now()
-        .duration_since(UNIX_EPOCH)
-        .unwrap()
-        .as_secs() as u32
+// Committing the height keeps coinbase txids unique (BIP34)
+pub(crate) fn coinbase_script(height: u64) -> Script {
+    Script(vec![Instruction::PushBytes(PushBytes::from_bytes(
+        script_num(height),
+    ))])
+}
+
+// Minimally encoded little-endian number as pushed by scripts
+fn script_num(n: u64) -> Vec<u8> {
+    let mut bytes = n.to_le_bytes().to_vec();
+    while bytes.last() == Some(&0) {
+        bytes.pop();
+    }
+    if bytes.last().is_some_and(|byte| byte & 0x80 != 0) {
+        bytes.push(0);
+    }
+    bytes
 }
 
 #[cfg(test)]
 mod tests {
     use std::collections::HashMap;
 
-    use crate::{account::Account, PRIVATE_KEY};
+    use bitaekcoin::block::{COINBASE_MATURITY, MAX_FUTURE_BLOCK_TIME, MEDIAN_TIME_SPAN};
+
+    use crate::{
+        account::{Account, Purpose},
+        coin_selection::Manual,
+        database::DB,
+        tx_builder::TxBuilder,
+        utxo::Utxo,
+        PRIVATE_KEY,
+    };
 
     use super::*;
 
@@ -128,28 +248,203 @@ mod tests {
 
     #[test]
     fn test_transfer() {
-        let mut sender = Account::new(0, PRIVATE_KEY.to_vec());
+        let mut sender = Account::new(0, PRIVATE_KEY.to_vec()).unwrap();
         let receiver = Account::random(1);
 
         let locking_script =
-            StandardScript::P2PK(sender.public_key.to_sec1_bytes().to_vec()).into_script();
-        let utxos = {
-            HashMap::from_iter([(
-                ([0; 32], 0),
-                TxOut {
-                    amount: 1,
-                    script_size: VarInt(locking_script.encode().len() as u64),
-                    script_pub_key: locking_script.clone(),
-                },
-            )])
+            StandardScript::P2PK(sender.keys[0].public_key.unwrap().to_sec1_bytes().to_vec())
+                .into_script();
+        let tx_out = TxOut {
+            amount: 1,
+            script_size: VarInt(locking_script.encode().len() as u64),
+            script_pub_key: locking_script.clone(),
         };
-        sender.balance = 1;
-        sender.utxos = utxos.clone();
-        let tx = sender.transfer(&receiver.public_key, 1).unwrap();
+        let utxos = HashMap::from_iter([(([0; 32], 0), tx_out.clone())]);
+        sender.utxos = HashMap::from_iter([(([0; 32], 0), Utxo::new(tx_out, 0, false))]);
+        let tx = sender.transfer(&receiver, 1, 1).unwrap();
         assert!(tx.validate(0, &tx.inputs[0].script_sig, &locking_script, 1));
 
         let transactions = vec![tx];
-        let block = initialize_block(0, [0; 32], 0x22ffffff, PUBLIC_KEY.to_vec(), transactions);
+        let block = mine(initialize_block(
+            0,
+            [0; 32],
+            adjusted_time(),
+            BITS,
+            PUBLIC_KEY.to_vec(),
+            MINING_REWARD,
+            transactions,
+        ));
         assert!(block.validate(&utxos));
     }
+
+    #[test]
+    fn test_time_warp() {
+        let mut db = DB::new();
+        for _ in 0..MEDIAN_TIME_SPAN {
+            let block = mine(next_block(&db, BITS, PUBLIC_KEY.to_vec(), vec![]));
+            db.push_block(block).unwrap();
+        }
+        let median_time_past = db.median_time_past();
+
+        // Not after the median time past
+        let mut block = next_block(&db, BITS, PUBLIC_KEY.to_vec(), vec![]);
+        block.header.timestamp = median_time_past;
+        assert!(db.push_block(mine(block)).is_err());
+
+        // Too far ahead of the adjusted time
+        let mut block = next_block(&db, BITS, PUBLIC_KEY.to_vec(), vec![]);
+        block.header.timestamp = adjusted_time() + MAX_FUTURE_BLOCK_TIME + 60;
+        assert!(db.push_block(mine(block)).is_err());
+
+        let mut block = next_block(&db, BITS, PUBLIC_KEY.to_vec(), vec![]);
+        block.header.timestamp = median_time_past + 1;
+        assert!(db.push_block(mine(block)).is_ok());
+    }
+
+    #[test]
+    fn test_difficulty() {
+        let mut db = DB::new();
+        db.push_block(mine(next_block(&db, BITS, PUBLIC_KEY.to_vec(), vec![])))
+            .unwrap();
+        for bits in [0x207ffffe, 0x0200ffff] {
+            let block = next_block(&db, bits, PUBLIC_KEY.to_vec(), vec![]);
+            assert!(db.process_block(block.clone()).is_err());
+            // Nor as an orphan
+            let mut orphan = block;
+            orphan.header.prev_block_hash = [1; 32];
+            assert!(db.process_block(orphan).is_err());
+        }
+    }
+
+    #[test]
+    fn test_coinbase_maturity() {
+        let mut db = DB::new();
+        let mine_next = |db: &DB, transactions: Vec<Transaction>| {
+            mine(next_block(db, BITS, PUBLIC_KEY.to_vec(), transactions))
+        };
+
+        db.push_block(mine_next(&db, vec![])).unwrap();
+        let receiver = Account::random(1);
+        let master = db.account(0);
+        assert_eq!(master.balance(db.next_height()), 0);
+        assert_eq!(master.immature_balance(db.next_height()), MINING_REWARD);
+        assert!(master.transfer(&receiver, 1, db.next_height()).is_err());
+
+        // A transaction spending the coinbase too early is rejected
+        let tx = master.transfer(&receiver, 1, COINBASE_MATURITY).unwrap();
+        assert!(db.validate_transaction(&tx, &HashMap::new()).is_err());
+        let block = mine_next(&db, vec![tx.clone()]);
+        assert!(db.validate_block(&block).is_err());
+
+        while db.next_height() < COINBASE_MATURITY {
+            db.push_block(mine_next(&db, vec![])).unwrap();
+        }
+        let master = db.account(0);
+        assert_eq!(master.balance(db.next_height()), MINING_REWARD);
+        assert!(db.validate_transaction(&tx, &HashMap::new()).is_ok());
+        let block = mine_next(&db, vec![tx]);
+        assert!(db.validate_block(&block).is_ok());
+    }
+
+    #[test]
+    fn test_mempool_updates() {
+        let db = RwLock::new(DB::new());
+        let mempool = RwLock::new(Mempool::new());
+        let mine_next = |transactions: Vec<Transaction>| {
+            let db = db.read().unwrap();
+            mine(next_block(&*db, BITS, PUBLIC_KEY.to_vec(), transactions))
+        };
+        while db.read().unwrap().next_height() < COINBASE_MATURITY + 1 {
+            submit_block(&db, &mempool, mine_next(vec![])).unwrap();
+        }
+        let script = Account::random(1).receive_key().scripts[0].clone();
+        let master = db.read().unwrap().account(0);
+        let height = db.read().unwrap().next_height();
+        let spend = |coinbase_height: u64, amount: u64| {
+            let block = db.read().unwrap().block_at(coinbase_height).unwrap();
+            let coinbase = block.transactions[0].txid();
+            TxBuilder::new(&master)
+                .add_recipient(script.clone(), amount)
+                .coin_selection(Manual(vec![(coinbase, 0)]))
+                .build(height)
+                .unwrap()
+        };
+        let confirmed = spend(0, 1000);
+        let pending = spend(1, 2000);
+        let conflict = spend(1, 3000);
+        mempool.write().unwrap().push(confirmed.clone()).unwrap();
+        mempool.write().unwrap().push(pending).unwrap();
+
+        // A block from elsewhere confirms one and double-spends the other
+        let block = mine_next(vec![confirmed.clone(), conflict.clone()]);
+        let hash = block.header.hash();
+        submit_block(&db, &mempool, block).unwrap();
+        assert!(mempool.read().unwrap().transactions.is_empty());
+
+        // Disconnected transactions come back
+        invalidate_block(&db, &mempool, &hash).unwrap();
+        let txids: Vec<_> = mempool
+            .read()
+            .unwrap()
+            .transactions
+            .iter()
+            .map(|tx| tx.txid())
+            .collect();
+        assert_eq!(txids, vec![confirmed.txid(), conflict.txid()]);
+    }
+
+    #[test]
+    fn test_pending_transfers() {
+        let mut db = DB::new();
+        // Only the first coinbase is mature
+        while db.next_height() < COINBASE_MATURITY {
+            let block = mine(next_block(&db, BITS, PUBLIC_KEY.to_vec(), vec![]));
+            db.push_block(block).unwrap();
+        }
+        let receiver = db.create_account(Purpose::NativeSegwit).unwrap();
+        let height = db.next_height();
+        let mut mempool = Mempool::new();
+        let pending_account = |mempool: &Mempool, index: usize| {
+            let mut account = db.account(index);
+            let transactions: Vec<_> = mempool.transactions.iter().cloned().collect();
+            account.apply_mempool(&transactions, height);
+            account
+        };
+
+        let master = pending_account(&mempool, 0);
+        let tx1 = master.transfer(&receiver, 1000, height).unwrap();
+        db.validate_transaction(&tx1, &mempool.outputs()).unwrap();
+        mempool.push(tx1.clone()).unwrap();
+
+        // The coinbase is spent by the pending transaction, which pays change back
+        let master = pending_account(&mempool, 0);
+        assert_eq!(master.balance(height), 0);
+        assert_eq!(master.pending_balance(), MINING_REWARD - 1000);
+        assert_eq!(master.spendable_balance(height), MINING_REWARD - 1000);
+        let conflict = db.account(0).transfer(&receiver, 2000, height).unwrap();
+        assert!(mempool.push(conflict).is_err());
+
+        // The next transfer spends the pending change
+        let tx2 = master.transfer(&receiver, 2000, height).unwrap();
+        assert_eq!(tx2.inputs[0].txid, tx1.txid());
+        assert!(db.validate_transaction(&tx2, &HashMap::new()).is_err());
+        db.validate_transaction(&tx2, &mempool.outputs()).unwrap();
+        mempool.push(tx2).unwrap();
+
+        // Outputs received from others aren't spent before they confirm
+        let pending_receiver = pending_account(&mempool, receiver.index);
+        assert_eq!(pending_receiver.balance(height), 0);
+        assert_eq!(pending_receiver.pending_balance(), 3000);
+        assert_eq!(pending_receiver.spendable_balance(height), 0);
+        assert_eq!(
+            pending_account(&mempool, 0).pending_balance(),
+            MINING_REWARD - 3000
+        );
+
+        let block = next_block(&db, BITS, PUBLIC_KEY.to_vec(), mempool.pop());
+        db.push_block(mine(block)).unwrap();
+        let receiver = db.account(receiver.index);
+        assert_eq!(receiver.balance(db.next_height()), 3000);
+        assert!(receiver.pending_utxos.is_empty());
+    }
 }
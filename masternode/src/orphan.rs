@@ -0,0 +1,162 @@
+use std::collections::{HashMap, VecDeque};
+
+use bitaekcoin::{block::Block, encode::Encodable};
+
+pub const MAX_ORPHAN_BLOCKS: usize = 100;
+pub const MAX_ORPHAN_BYTES: usize = 32 * 1024 * 1024;
+
+// Blocks whose parent is not known yet
+pub struct OrphanPool {
+    pub blocks: HashMap<[u8; 32], (Block, usize)>,
+    // Orphans waiting for each missing parent
+    pub children: HashMap<[u8; 32], Vec<[u8; 32]>>,
+    // Arrival order, the oldest orphan is evicted first
+    order: VecDeque<[u8; 32]>,
+    bytes: usize,
+    max_blocks: usize,
+    max_bytes: usize,
+}
+
+impl OrphanPool {
+    pub fn new(max_blocks: usize, max_bytes: usize) -> Self {
+        Self {
+            blocks: HashMap::new(),
+            children: HashMap::new(),
+            order: VecDeque::new(),
+            bytes: 0,
+            max_blocks,
+            max_bytes,
+        }
+    }
+
+    pub fn insert(&mut self, block: Block) -> Result<(), String> {
+        let hash = block.header.hash();
+        if self.blocks.contains_key(&hash) {
+            return Ok(());
+        }
+        let size = block.encode().len();
+        if size > self.max_bytes {
+            return Err("Orphan block is too large".to_owned());
+        }
+        while self.blocks.len() >= self.max_blocks || self.bytes + size > self.max_bytes {
+            self.evict();
+        }
+
+        self.children
+            .entry(block.header.prev_block_hash)
+            .or_default()
+            .push(hash);
+        self.order.push_back(hash);
+        self.bytes += size;
+        self.blocks.insert(hash, (block, size));
+        Ok(())
+    }
+
+    // Removes and returns the orphans whose parent is `hash`
+    pub fn take_children(&mut self, hash: &[u8; 32]) -> Vec<Block> {
+        let children = self.children.remove(hash).unwrap_or_default();
+        children
+            .into_iter()
+            .filter_map(|child| self.remove(&child))
+            .collect()
+    }
+
+    pub fn contains(&self, hash: &[u8; 32]) -> bool {
+        self.blocks.contains_key(hash)
+    }
+
+    pub fn len(&self) -> usize {
+        self.blocks.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.blocks.is_empty()
+    }
+
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
+
+    fn evict(&mut self) {
+        if let Some(hash) = self.order.front().copied() {
+            let prev_block_hash = self.blocks[&hash].0.header.prev_block_hash;
+            if let Some(siblings) = self.children.get_mut(&prev_block_hash) {
+                siblings.retain(|sibling| *sibling != hash);
+                if siblings.is_empty() {
+                    self.children.remove(&prev_block_hash);
+                }
+            }
+            self.remove(&hash);
+        }
+    }
+
+    fn remove(&mut self, hash: &[u8; 32]) -> Option<Block> {
+        let (block, size) = self.blocks.remove(hash)?;
+        self.order.retain(|h| h != hash);
+        self.bytes -= size;
+        Some(block)
+    }
+}
+
+impl Default for OrphanPool {
+    fn default() -> Self {
+        Self::new(MAX_ORPHAN_BLOCKS, MAX_ORPHAN_BYTES)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bitaekcoin::block::BlockHeader;
+
+    use super::*;
+
+    fn block(prev_block_hash: [u8; 32], nonce: u32) -> Block {
+        Block {
+            header: BlockHeader {
+                height: 1,
+                version: 1,
+                prev_block_hash,
+                merkle_root: [0; 32],
+                timestamp: 0,
+                bits: 0x207fffff,
+                nonce,
+            },
+            transactions: vec![],
+        }
+    }
+
+    #[test]
+    fn test_take_children() {
+        let mut pool = OrphanPool::default();
+        pool.insert(block([1; 32], 0)).unwrap();
+        pool.insert(block([1; 32], 1)).unwrap();
+        pool.insert(block([2; 32], 2)).unwrap();
+        assert_eq!(pool.len(), 3);
+
+        let children = pool.take_children(&[1; 32]);
+        assert_eq!(children.len(), 2);
+        assert_eq!(pool.len(), 1);
+        assert!(pool.take_children(&[1; 32]).is_empty());
+    }
+
+    #[test]
+    fn test_eviction() {
+        let size = block([0; 32], 0).encode().len();
+        let mut pool = OrphanPool::new(2, size * 10);
+        let first = block([1; 32], 0);
+        pool.insert(first.clone()).unwrap();
+        pool.insert(block([2; 32], 1)).unwrap();
+        pool.insert(block([3; 32], 2)).unwrap();
+        assert_eq!(pool.len(), 2);
+        assert!(!pool.contains(&first.header.hash()));
+        assert!(pool.take_children(&[1; 32]).is_empty());
+
+        // The memory limit evicts as well
+        let mut pool = OrphanPool::new(10, size * 2);
+        for nonce in 0..3 {
+            pool.insert(block([nonce as u8; 32], nonce)).unwrap();
+        }
+        assert_eq!(pool.len(), 2);
+        assert_eq!(pool.bytes(), size * 2);
+    }
+}
@@ -0,0 +1,66 @@
+use serde::{Deserialize, Serialize};
+
+// Blocks this close to the tip are never pruned so that reorganizations stay possible
+pub const MIN_BLOCKS_TO_KEEP: u64 = 288;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PruneMode {
+    // Keep the data of this many blocks below the tip
+    KeepBlocks(u64),
+    // Keep the stored blocks within this many bytes
+    MaxBytes(u64),
+}
+
+impl PruneMode {
+    // Blocks below the returned height can be pruned. `sizes` are the heights and sizes of
+    // the stored blocks in ascending order of height, `next_height` is the height of the
+    // next block.
+    pub fn prune_height(&self, next_height: u64, sizes: &[(u64, u64)]) -> u64 {
+        let max_prune_height = next_height.saturating_sub(MIN_BLOCKS_TO_KEEP);
+        match *self {
+            PruneMode::KeepBlocks(keep) => next_height.saturating_sub(keep.max(MIN_BLOCKS_TO_KEEP)),
+            PruneMode::MaxBytes(max_bytes) => {
+                let mut total: u64 = sizes.iter().map(|(_, size)| size).sum();
+                let mut prune_height = 0;
+                // The oldest blocks go first
+                for (height, size) in sizes {
+                    if total <= max_bytes || *height >= max_prune_height {
+                        break;
+                    }
+                    total -= size;
+                    prune_height = height + 1;
+                }
+                prune_height
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PruneInfoJson {
+    pub pruned: bool,
+    // Lowest height of the active chain whose block is stored
+    pub prune_height: u64,
+    pub size: u64,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_prune_height() {
+        let sizes: Vec<_> = (0..1000).map(|height| (height, 100)).collect();
+        assert_eq!(PruneMode::KeepBlocks(500).prune_height(1000, &sizes), 500);
+        assert_eq!(PruneMode::KeepBlocks(10).prune_height(1000, &sizes), 712);
+        assert_eq!(
+            PruneMode::KeepBlocks(10).prune_height(100, &sizes[..100]),
+            0
+        );
+
+        assert_eq!(PruneMode::MaxBytes(50_000).prune_height(1000, &sizes), 500);
+        assert_eq!(PruneMode::MaxBytes(200_000).prune_height(1000, &sizes), 0);
+        // Blocks within the safety depth are kept even above the limit
+        assert_eq!(PruneMode::MaxBytes(0).prune_height(1000, &sizes), 712);
+    }
+}
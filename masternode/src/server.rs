@@ -1,11 +1,49 @@
-use std::sync::{Arc, RwLock};
+use std::{
+    collections::{BTreeMap, HashSet},
+    fs::{self, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+    thread,
+    time::Duration,
+};
 
-use bitaekcoin::block::Block;
+use bitaekcoin::{
+    address::Address,
+    bip39::Mnemonic,
+    block::Block,
+    encode::{decode_all, Encodable},
+    script::Script,
+    transaction::{Transaction, TxID},
+    utils::{from_hex, to_hex},
+    wif::PrivateKey,
+};
 use jsonrpc_core::{Error, IoHandler, Result};
 use jsonrpc_derive::rpc;
 use jsonrpc_http_server::ServerBuilder;
+use k256::ecdsa::VerifyingKey;
 
-use crate::{account::AccountJson, database::DB, mempool::Mempool};
+use crate::{
+    account::{Account, AccountJson, Key, Purpose, TxOutJson, WalletInfoJson, EXTERNAL, INTERNAL},
+    address_index::{mempool_events, ScriptBalanceJson, ScriptEvent, ScriptEventKind},
+    archive::ChainArchiveJson,
+    chainstate::ChainState,
+    coin_selection::{
+        BranchAndBound, CoinSelection, Knapsack, LargestFirst, Manual, SingleRandomDraw,
+    },
+    history::{pending_history, WalletTxJson},
+    mempool::Mempool,
+    node::{invalidate_block, reconsider_block, submit_block},
+    prune::PruneInfoJson,
+    snapshot::UtxoSetInfoJson,
+    time::adjusted_time,
+    tx_builder::TxBuilder,
+    tx_index::TransactionJson,
+    NETWORK,
+};
+
+// Directory of the data directory that RPCs write files to
+pub const EXPORTS_DIR: &str = "exports";
 
 #[rpc]
 pub trait Rpc {
@@ -15,22 +53,132 @@ pub trait Rpc {
     #[rpc(name = "getLatestBlock")]
     fn get_latest_block(&self) -> Result<Block>;
 
+    #[rpc(name = "submitBlock")]
+    fn submit_block(&self, block: Block) -> Result<()>;
+
+    #[rpc(name = "invalidateBlock")]
+    fn invalidate_block(&self, hash: String) -> Result<()>;
+
+    #[rpc(name = "reconsiderBlock")]
+    fn reconsider_block(&self, hash: String) -> Result<()>;
+
+    #[rpc(name = "getRawTransaction")]
+    fn get_raw_transaction(&self, txid: String) -> Result<TransactionJson>;
+
+    #[rpc(name = "getScriptBalance")]
+    fn get_script_balance(&self, script: String) -> Result<ScriptBalanceJson>;
+
+    #[rpc(name = "getScriptUtxos")]
+    fn get_script_utxos(&self, script: String) -> Result<Vec<TxOutJson>>;
+
+    #[rpc(name = "getScriptHistory")]
+    fn get_script_history(
+        &self,
+        script: String,
+        skip: usize,
+        count: usize,
+    ) -> Result<Vec<ScriptEvent>>;
+
+    #[rpc(name = "getTxOutSetInfo")]
+    fn get_tx_out_set_info(&self) -> Result<UtxoSetInfoJson>;
+
+    #[rpc(name = "dumpTxOutSet")]
+    fn dump_tx_out_set(&self, file_name: String, height: u64) -> Result<UtxoSetInfoJson>;
+
+    #[rpc(name = "exportChain")]
+    fn export_chain(&self, file_name: String) -> Result<ChainArchiveJson>;
+
+    #[rpc(name = "verifyChain")]
+    fn verify_chain(&self, level: u32, depth: u64) -> Result<()>;
+
+    #[rpc(name = "getPruneInfo")]
+    fn get_prune_info(&self) -> Result<PruneInfoJson>;
+
+    #[rpc(name = "getWalletInfo")]
+    fn get_wallet_info(&self) -> Result<WalletInfoJson>;
+
     #[rpc(name = "getAccounts")]
     fn get_accounts(&self) -> Result<Vec<AccountJson>>;
 
     #[rpc(name = "createAccount")]
-    fn create_account(&self) -> Result<AccountJson>;
+    fn create_account(&self, address_type: Option<String>) -> Result<AccountJson>;
+
+    #[rpc(name = "getNewAddress")]
+    fn get_new_address(&self, index: usize, change: Option<bool>) -> Result<String>;
+
+    #[rpc(name = "importPrivateKey")]
+    fn import_private_key(&self, wif: String) -> Result<AccountJson>;
+
+    #[rpc(name = "dumpPrivateKey")]
+    fn dump_private_key(&self, index: usize, address: Option<String>) -> Result<String>;
+
+    #[rpc(name = "importPublicKey")]
+    fn import_public_key(&self, public_key: String) -> Result<AccountJson>;
+
+    #[rpc(name = "importAddress")]
+    fn import_address(&self, script: String) -> Result<AccountJson>;
+
+    #[rpc(name = "createWallet")]
+    fn create_wallet(&self, words: usize, passphrase: Option<String>) -> Result<String>;
+
+    #[rpc(name = "restoreWallet")]
+    fn restore_wallet(
+        &self,
+        mnemonic: String,
+        passphrase: Option<String>,
+    ) -> Result<Vec<AccountJson>>;
+
+    #[rpc(name = "encryptWallet")]
+    fn encrypt_wallet(&self, passphrase: String) -> Result<()>;
+
+    #[rpc(name = "walletPassphrase")]
+    fn wallet_passphrase(&self, passphrase: String, timeout: u32) -> Result<()>;
+
+    #[rpc(name = "walletLock")]
+    fn wallet_lock(&self) -> Result<()>;
+
+    #[rpc(name = "walletPassphraseChange")]
+    fn wallet_passphrase_change(&self, old: String, new: String) -> Result<()>;
+
+    #[rpc(name = "listTransactions")]
+    fn list_transactions(
+        &self,
+        index: usize,
+        skip: usize,
+        count: usize,
+    ) -> Result<Vec<WalletTxJson>>;
 
     #[rpc(name = "transfer")]
-    fn transfer(&self, from: usize, to: usize, amount: u64) -> Result<()>;
+    fn transfer(&self, from: usize, to: usize, amount: u64, fee_rate: Option<u64>) -> Result<()>;
+
+    #[rpc(name = "sendToAddress")]
+    fn send_to_address(
+        &self,
+        from: usize,
+        address: String,
+        amount: u64,
+        fee_rate: Option<u64>,
+    ) -> Result<()>;
+
+    #[rpc(name = "sendMany")]
+    fn send_many(
+        &self,
+        from: usize,
+        recipients: BTreeMap<String, u64>,
+        fee_rate: Option<u64>,
+        lock_time: Option<u32>,
+        coin_selection: Option<String>,
+        inputs: Option<Vec<String>>,
+    ) -> Result<String>;
 }
 
-struct RpcImpl {
+struct RpcImpl<C> {
     pub mempool: Arc<RwLock<Mempool>>,
-    pub db: Arc<RwLock<DB>>,
+    pub db: Arc<RwLock<C>>,
+    pub data_dir: PathBuf,
 }
 
-impl Rpc for RpcImpl {
+impl<C: ChainState + 'static> Rpc for RpcImpl<C> {
     fn get_blocks(&self) -> Result<Vec<Block>> {
         let mut blocks = self.db.read().unwrap().blocks();
         blocks.reverse();
@@ -44,41 +192,533 @@ impl Rpc for RpcImpl {
         }
     }
 
-    fn get_accounts(&self) -> Result<Vec<AccountJson>> {
-        Ok(self
+    fn submit_block(&self, block: Block) -> Result<()> {
+        submit_block(&self.db, &self.mempool, block).map_err(Error::invalid_params)
+    }
+
+    fn invalidate_block(&self, hash: String) -> Result<()> {
+        let hash = parse_hash(&hash, "Invalid block hash")?;
+        invalidate_block(&self.db, &self.mempool, &hash).map_err(Error::invalid_params)
+    }
+
+    fn reconsider_block(&self, hash: String) -> Result<()> {
+        let hash = parse_hash(&hash, "Invalid block hash")?;
+        reconsider_block(&self.db, &self.mempool, &hash).map_err(Error::invalid_params)
+    }
+
+    fn get_raw_transaction(&self, txid: String) -> Result<TransactionJson> {
+        let txid = parse_hash(&txid, "Invalid txid")?;
+        let db = self.db.read().unwrap();
+        match db.transaction(&txid).map_err(Error::invalid_params)? {
+            Some((tx, location)) => Ok(TransactionJson::new(tx, location, db.next_height())),
+            None => Err(Error::invalid_params("Transaction not found")),
+        }
+    }
+
+    fn get_script_balance(&self, script: String) -> Result<ScriptBalanceJson> {
+        let script = parse_script(&script)?;
+        let db = self.db.read().unwrap();
+        let confirmed = db
+            .utxos_by_script(&script)
+            .iter()
+            .map(|(_, utxo)| utxo.tx_out.amount)
+            .sum();
+        let unconfirmed = self
+            .mempool_events(&*db, &script)
+            .iter()
+            .map(|event| match event.kind {
+                ScriptEventKind::Funding => event.amount as i64,
+                ScriptEventKind::Spending => -(event.amount as i64),
+            })
+            .sum();
+        Ok(ScriptBalanceJson {
+            confirmed,
+            unconfirmed,
+        })
+    }
+
+    fn get_script_utxos(&self, script: String) -> Result<Vec<TxOutJson>> {
+        let script = parse_script(&script)?;
+        let db = self.db.read().unwrap();
+        Ok(db
+            .utxos_by_script(&script)
+            .iter()
+            .map(|(outpoint, utxo)| TxOutJson::new(*outpoint, utxo))
+            .collect())
+    }
+
+    // Newest first, starting with unconfirmed events
+    fn get_script_history(
+        &self,
+        script: String,
+        skip: usize,
+        count: usize,
+    ) -> Result<Vec<ScriptEvent>> {
+        let script = parse_script(&script)?;
+        let db = self.db.read().unwrap();
+        let mut history = db.script_history(&script).map_err(Error::invalid_params)?;
+        history.extend(self.mempool_events(&*db, &script));
+        Ok(history.into_iter().rev().skip(skip).take(count).collect())
+    }
+
+    fn get_tx_out_set_info(&self) -> Result<UtxoSetInfoJson> {
+        let db = self.db.read().unwrap();
+        match db.next_height().checked_sub(1) {
+            Some(height) => match db.utxo_snapshot(height) {
+                Ok(snapshot) => Ok(snapshot.info()),
+                Err(err) => Err(Error::invalid_params(err)),
+            },
+            None => Err(Error::invalid_request()),
+        }
+    }
+
+    fn dump_tx_out_set(&self, file_name: String, height: u64) -> Result<UtxoSetInfoJson> {
+        let snapshot = self
+            .db
+            .read()
+            .unwrap()
+            .utxo_snapshot(height)
+            .map_err(Error::invalid_params)?;
+        self.write_export(&file_name, &snapshot.encode())?;
+        Ok(snapshot.info())
+    }
+
+    fn export_chain(&self, file_name: String) -> Result<ChainArchiveJson> {
+        let archive = self
             .db
             .read()
             .unwrap()
-            .accounts()
+            .chain_archive()
+            .map_err(Error::invalid_params)?;
+        self.write_export(&file_name, &archive.encode())?;
+        Ok(archive.info())
+    }
+
+    fn verify_chain(&self, level: u32, depth: u64) -> Result<()> {
+        let db = self.db.read().unwrap();
+        db.verify_chain(level, depth).map_err(Error::invalid_params)
+    }
+
+    fn get_prune_info(&self) -> Result<PruneInfoJson> {
+        Ok(self.db.read().unwrap().prune_info())
+    }
+
+    fn get_wallet_info(&self) -> Result<WalletInfoJson> {
+        Ok(self.db.read().unwrap().wallet_info())
+    }
+
+    // Balances and outputs include the pending transactions of the mempool
+    fn get_accounts(&self) -> Result<Vec<AccountJson>> {
+        let db = self.db.read().unwrap();
+        let height = db.next_height();
+        let mempool = self.mempool.read().unwrap();
+        Ok(pending_accounts(&*db, &mempool)
+            .into_iter()
+            .map(|a| AccountJson::new(a, height))
+            .collect())
+    }
+
+    // Native segwit accounts unless another address type is given
+    fn create_account(&self, address_type: Option<String>) -> Result<AccountJson> {
+        let purpose = match address_type {
+            Some(address_type) => address_type.parse().map_err(Error::invalid_params)?,
+            None => Purpose::NativeSegwit,
+        };
+        let mut db = self.db.write().unwrap();
+        let height = db.next_height();
+        match db.create_account(purpose) {
+            Ok(account) => Ok(AccountJson::new(account, height)),
+            Err(err) => Err(Error::invalid_params(err)),
+        }
+    }
+
+    fn get_new_address(&self, index: usize, change: Option<bool>) -> Result<String> {
+        let chain = match change {
+            Some(true) => INTERNAL,
+            _ => EXTERNAL,
+        };
+        let script = self
+            .db
+            .write()
+            .unwrap()
+            .new_address(index, chain)
+            .map_err(Error::invalid_params)?;
+        Address::from_script(&script, NETWORK)
+            .map(|address| address.to_string())
+            .ok_or_else(Error::internal_error)
+    }
+
+    fn import_private_key(&self, wif: String) -> Result<AccountJson> {
+        let private_key = PrivateKey::parse(&wif, NETWORK)
+            .map_err(|_| Error::invalid_params("Invalid private key"))?;
+        let key = Key::new(private_key.key.to_bytes().to_vec()).map_err(Error::invalid_params)?;
+        self.import_key(key)
+    }
+
+    // Key of the given address of an account, by default of its receiving address
+    fn dump_private_key(&self, index: usize, address: Option<String>) -> Result<String> {
+        let db = self.db.read().unwrap();
+        let accounts = db.accounts();
+        let account = accounts.get(index).ok_or_else(Error::invalid_request)?;
+        let key = match address {
+            Some(address) => {
+                let script = parse_script(&address)?;
+                account
+                    .keys
+                    .iter()
+                    .find(|key| key.scripts.contains(&script))
+                    .ok_or_else(|| Error::invalid_params("Address of another account"))?
+            }
+            None => account.receive_key(),
+        };
+        let key = match (&key.private_key, key.is_watch_only()) {
+            (Some(private_key), _) => private_key.clone(),
+            (None, true) => return Err(Error::invalid_params("Watch-only account")),
+            (None, false) => return Err(Error::invalid_params("Wallet is locked")),
+        };
+        Ok(PrivateKey {
+            network: NETWORK,
+            key,
+        }
+        .to_string())
+    }
+
+    fn import_public_key(&self, public_key: String) -> Result<AccountJson> {
+        let public_key = from_hex(&public_key)
+            .and_then(|bytes| VerifyingKey::from_sec1_bytes(&bytes).ok())
+            .ok_or_else(|| Error::invalid_params("Invalid public key"))?;
+        self.import_key(Key::watch(public_key))
+    }
+
+    fn import_address(&self, script: String) -> Result<AccountJson> {
+        self.import_key(Key::watch_script(parse_script(&script)?))
+    }
+
+    fn create_wallet(&self, words: usize, passphrase: Option<String>) -> Result<String> {
+        // 3 words for every 4 bytes of entropy
+        let entropy: Vec<u8> = (0..words / 3 * 4).map(|_| rand::random()).collect();
+        let mnemonic = match Mnemonic::from_entropy(&entropy) {
+            Ok(mnemonic) if words.is_multiple_of(3) => mnemonic,
+            _ => return Err(Error::invalid_params("Invalid number of words")),
+        };
+        let seed = mnemonic.to_seed(&passphrase.unwrap_or_default());
+        self.db
+            .write()
+            .unwrap()
+            .restore_wallet(seed.to_vec())
+            .map_err(Error::invalid_params)?;
+        Ok(mnemonic.to_string())
+    }
+
+    fn restore_wallet(
+        &self,
+        mnemonic: String,
+        passphrase: Option<String>,
+    ) -> Result<Vec<AccountJson>> {
+        let mnemonic: Mnemonic = mnemonic
+            .parse()
+            .map_err(|_| Error::invalid_params("Invalid mnemonic"))?;
+        let seed = mnemonic.to_seed(&passphrase.unwrap_or_default());
+        let mut db = self.db.write().unwrap();
+        let height = db.next_height();
+        let accounts = db
+            .restore_wallet(seed.to_vec())
+            .map_err(Error::invalid_params)?;
+        Ok(accounts
+            .into_iter()
+            .map(|a| AccountJson::new(a, height))
+            .collect())
+    }
+
+    fn encrypt_wallet(&self, passphrase: String) -> Result<()> {
+        let mut db = self.db.write().unwrap();
+        db.encrypt_wallet(&passphrase)
+            .map_err(Error::invalid_params)
+    }
+
+    // Locks the wallet again after `timeout` seconds
+    fn wallet_passphrase(&self, passphrase: String, timeout: u32) -> Result<()> {
+        let until = adjusted_time().saturating_add(timeout);
+        self.db
+            .write()
+            .unwrap()
+            .unlock_wallet(&passphrase, until)
+            .map_err(Error::invalid_params)?;
+        let db = self.db.clone();
+        thread::spawn(move || {
+            thread::sleep(Duration::from_secs(timeout as u64));
+            let mut db = db.write().unwrap();
+            // Unless it was locked or unlocked again in the meantime
+            if db.wallet_info().unlocked_until == Some(until) {
+                db.lock_wallet().ok();
+            }
+        });
+        Ok(())
+    }
+
+    fn wallet_lock(&self) -> Result<()> {
+        let mut db = self.db.write().unwrap();
+        db.lock_wallet().map_err(Error::invalid_params)
+    }
+
+    fn wallet_passphrase_change(&self, old: String, new: String) -> Result<()> {
+        let mut db = self.db.write().unwrap();
+        db.change_passphrase(&old, &new)
+            .map_err(Error::invalid_params)
+    }
+
+    // Newest first, starting with pending transactions
+    fn list_transactions(
+        &self,
+        index: usize,
+        skip: usize,
+        count: usize,
+    ) -> Result<Vec<WalletTxJson>> {
+        let db = self.db.read().unwrap();
+        let mut history = db.wallet_history(index).map_err(Error::invalid_params)?;
+        let scripts: HashSet<_> = db.accounts()[index].scripts().cloned().collect();
+        let mempool = self.mempool.read().unwrap();
+        let transactions: Vec<_> = mempool.transactions.iter().cloned().collect();
+        history.extend(pending_history(&transactions, &scripts, |outpoint| {
+            db.utxo(outpoint).map(|utxo| utxo.tx_out)
+        }));
+        let height = db.next_height();
+        Ok(history
             .into_iter()
-            .map(|a| a.into())
+            .rev()
+            .skip(skip)
+            .take(count)
+            .map(|wallet_tx| WalletTxJson::new(wallet_tx, height))
             .collect())
     }
 
-    fn create_account(&self) -> Result<AccountJson> {
-        Ok(self.db.write().unwrap().create_account().into())
+    // Fee rates are in satoshis per virtual byte, without a fee by default
+    fn transfer(&self, from: usize, to: usize, amount: u64, fee_rate: Option<u64>) -> Result<()> {
+        let script = {
+            let db = self.db.read().unwrap();
+            let accounts = db.accounts();
+            let account = accounts.get(to).ok_or_else(Error::invalid_request)?;
+            account.receive_key().scripts[0].clone()
+        };
+        self.send(from, vec![(script, amount)], fee_rate, None, None)
+            .map(|_| ())
+    }
+
+    fn send_to_address(
+        &self,
+        from: usize,
+        address: String,
+        amount: u64,
+        fee_rate: Option<u64>,
+    ) -> Result<()> {
+        let script = match Address::parse(&address, NETWORK) {
+            Ok(address) => address.script_pub_key(),
+            Err(_) => return Err(Error::invalid_params("Invalid address")),
+        };
+        self.send(from, vec![(script, amount)], fee_rate, None, None)
+            .map(|_| ())
+    }
+
+    // Recipients are addresses or scripts in hex, the lock time is a height or a unix time.
+    // Inputs given as `<txid>:<index>` are spent instead of selecting coins.
+    fn send_many(
+        &self,
+        from: usize,
+        recipients: BTreeMap<String, u64>,
+        fee_rate: Option<u64>,
+        lock_time: Option<u32>,
+        coin_selection: Option<String>,
+        inputs: Option<Vec<String>>,
+    ) -> Result<String> {
+        let recipients = recipients
+            .into_iter()
+            .map(|(script, amount)| Ok((parse_script(&script)?, amount)))
+            .collect::<Result<_>>()?;
+        let coin_selection: Option<Box<dyn CoinSelection>> = match (coin_selection, inputs) {
+            (_, Some(inputs)) => {
+                let outpoints = inputs
+                    .iter()
+                    .map(|input| parse_outpoint(input))
+                    .collect::<Result<_>>()?;
+                Some(Box::new(Manual(outpoints)))
+            }
+            (Some(name), None) => Some(parse_coin_selection(&name)?),
+            (None, None) => None,
+        };
+        let tx = self.send(from, recipients, fee_rate, lock_time, coin_selection)?;
+        Ok(to_hex(&tx.txid()))
     }
+}
 
-    fn transfer(&self, from: usize, to: usize, amount: u64) -> Result<()> {
-        let accounts = self.db.read().unwrap().accounts();
-        if from >= accounts.len() || to >= accounts.len() {
-            return Err(Error::invalid_request());
+impl<C: ChainState> RpcImpl<C> {
+    fn send(
+        &self,
+        from: usize,
+        recipients: Vec<(Script, u64)>,
+        fee_rate: Option<u64>,
+        lock_time: Option<u32>,
+        coin_selection: Option<Box<dyn CoinSelection>>,
+    ) -> Result<Transaction> {
+        // Holding the mempool until the transaction is added keeps others from spending the
+        // same outputs
+        let db = self.db.read().unwrap();
+        let mut mempool = self.mempool.write().unwrap();
+        let accounts = pending_accounts(&*db, &mempool);
+        let account = accounts.get(from).ok_or_else(Error::invalid_request)?;
+        let mut builder = TxBuilder::new(account)
+            .fee_rate(fee_rate.unwrap_or(0))
+            .lock_time(lock_time.unwrap_or(0));
+        for (script, amount) in recipients {
+            builder = builder.add_recipient(script, amount);
         }
-        match accounts[from].transfer(&accounts[to].public_key, amount) {
-            Ok(tx) => match self.mempool.write().unwrap().push(tx) {
-                Ok(_) => Ok(()),
-                Err(_) => Err(Error::invalid_request()),
-            },
-            Err(_) => Err(Error::invalid_request()),
+        if let Some(coin_selection) = coin_selection {
+            builder = builder.coin_selection(coin_selection);
         }
+        let tx = builder
+            .build(db.next_height())
+            .and_then(|tx| db.validate_transaction(&tx, &mempool.outputs()).map(|_| tx))
+            .map_err(Error::invalid_params)?;
+        mempool.push(tx.clone()).map_err(Error::invalid_params)?;
+        Ok(tx)
+    }
+
+    fn import_key(&self, key: Key) -> Result<AccountJson> {
+        let mut db = self.db.write().unwrap();
+        let height = db.next_height();
+        db.import_key(key)
+            .map(|account| AccountJson::new(account, height))
+            .map_err(Error::invalid_params)
+    }
+
+    // Files are only written to the exports directory of the data directory and never replace
+    // existing ones
+    fn write_export(&self, file_name: &str, bytes: &[u8]) -> Result<()> {
+        let dir = self.data_dir.join(EXPORTS_DIR);
+        let path = export_path(&dir, file_name)?;
+        fs::create_dir_all(&dir).map_err(|_| Error::internal_error())?;
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(path)
+            .map_err(|err| match err.kind() {
+                io::ErrorKind::AlreadyExists => Error::invalid_params("File already exists"),
+                _ => Error::internal_error(),
+            })?;
+        file.write_all(bytes).map_err(|_| Error::internal_error())
+    }
+
+    fn mempool_events(&self, db: &C, script: &Script) -> Vec<ScriptEvent> {
+        let mempool = self.mempool.read().unwrap();
+        let transactions: Vec<_> = mempool.transactions.iter().cloned().collect();
+        mempool_events(script, &transactions, |outpoint| {
+            db.utxo(outpoint).map(|utxo| utxo.tx_out)
+        })
+    }
+}
+
+// Wallet accounts with the spends and outputs of pending transactions
+fn pending_accounts(db: &impl ChainState, mempool: &Mempool) -> Vec<Account> {
+    let height = db.next_height();
+    let transactions: Vec<_> = mempool.transactions.iter().cloned().collect();
+    db.accounts()
+        .into_iter()
+        .map(|mut account| {
+            account.apply_mempool(&transactions, height);
+            account
+        })
+        .collect()
+}
+
+// File names of letters, digits, dots, dashes and underscores that can't leave `dir`
+fn export_path(dir: &Path, file_name: &str) -> Result<PathBuf> {
+    let valid = file_name
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | '_'));
+    if file_name.is_empty() || file_name.starts_with('.') || !valid {
+        return Err(Error::invalid_params("Invalid file name"));
+    }
+    Ok(dir.join(file_name))
+}
+
+fn parse_hash(hex: &str, message: &str) -> Result<[u8; 32]> {
+    match from_hex(hex).map(<[u8; 32]>::try_from) {
+        Some(Ok(hash)) => Ok(hash),
+        _ => Err(Error::invalid_params(message)),
     }
 }
 
-pub fn run_server(mempool: Arc<RwLock<Mempool>>, db: Arc<RwLock<DB>>) {
+fn parse_outpoint(outpoint: &str) -> Result<(TxID, u32)> {
+    let (txid, index) = outpoint
+        .split_once(':')
+        .ok_or_else(|| Error::invalid_params("Invalid outpoint"))?;
+    let index = index
+        .parse()
+        .map_err(|_| Error::invalid_params("Invalid outpoint"))?;
+    Ok((parse_hash(txid, "Invalid outpoint")?, index))
+}
+
+fn parse_coin_selection(name: &str) -> Result<Box<dyn CoinSelection>> {
+    match name {
+        "bnb" => Ok(Box::new(BranchAndBound::default())),
+        "knapsack" => Ok(Box::new(Knapsack)),
+        "srd" => Ok(Box::new(SingleRandomDraw)),
+        "largest" => Ok(Box::new(LargestFirst)),
+        _ => Err(Error::invalid_params("Unknown coin selection")),
+    }
+}
+
+// Script pubkey given as an address or as hex
+fn parse_script(script: &str) -> Result<Script> {
+    if let Ok(address) = Address::parse(script, NETWORK) {
+        return Ok(address.script_pub_key());
+    }
+    match from_hex(script).map(decode_all) {
+        Some(Ok(script)) => Ok(script),
+        _ => Err(Error::invalid_params("Invalid script")),
+    }
+}
+
+pub fn run_server<C: ChainState + 'static>(
+    mempool: Arc<RwLock<Mempool>>,
+    db: Arc<RwLock<C>>,
+    data_dir: PathBuf,
+) {
     let mut io = IoHandler::new();
-    io.extend_with(RpcImpl { mempool, db }.to_delegate());
+    io.extend_with(
+        RpcImpl {
+            mempool,
+            db,
+            data_dir,
+        }
+        .to_delegate(),
+    );
     let server = ServerBuilder::new(io)
         .start_http(&"0.0.0.0:8000".parse().unwrap())
         .unwrap();
     server.wait();
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::database::DB;
+
+    use super::*;
+
+    #[test]
+    fn test_write_export() {
+        let dir = tempfile::tempdir().unwrap();
+        let rpc = RpcImpl {
+            mempool: Arc::new(RwLock::new(Mempool::new())),
+            db: Arc::new(RwLock::new(DB::new())),
+            data_dir: dir.path().to_path_buf(),
+        };
+        rpc.write_export("chain.dat", &[1]).unwrap();
+        assert_eq!(fs::read(dir.path().join("exports/chain.dat")).unwrap(), [1]);
+        // Existing files are kept
+        assert!(rpc.write_export("chain.dat", &[2]).is_err());
+        assert_eq!(fs::read(dir.path().join("exports/chain.dat")).unwrap(), [1]);
+        for file_name in ["", "..", "../seed.json", "/etc/passwd", "a/b", ".hidden"] {
+            assert!(rpc.write_export(file_name, &[3]).is_err());
+        }
+    }
+}
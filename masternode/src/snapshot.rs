@@ -0,0 +1,150 @@
+use std::collections::{HashMap, VecDeque};
+
+use serde::{Deserialize, Serialize};
+
+use bitaekcoin::{
+    block::{Block, BlockHeader},
+    encode::{Decodable, DecodeError, Encodable},
+    hash::sha256,
+    transaction::{TxID, TxOut},
+    utils::to_hex,
+};
+
+use crate::{undo::BlockUndo, utxo::Utxo};
+
+// UTXO set as of the last of `headers`, enough for a fresh node to continue the chain
+#[derive(Debug, Clone)]
+pub struct UtxoSnapshot {
+    // Headers of the chain in ascending order, starting with the genesis block
+    pub headers: Vec<BlockHeader>,
+    // Sorted by outpoint
+    pub utxos: Vec<((TxID, u32), Utxo)>,
+}
+
+impl UtxoSnapshot {
+    pub fn new(headers: Vec<BlockHeader>, utxos: HashMap<(TxID, u32), Utxo>) -> Self {
+        let mut utxos: Vec<_> = utxos.into_iter().collect();
+        utxos.sort_by_key(|(outpoint, _)| *outpoint);
+        Self { headers, utxos }
+    }
+
+    pub fn base_hash(&self) -> [u8; 32] {
+        self.headers
+            .last()
+            .map(|header| header.hash())
+            .unwrap_or([0; 32])
+    }
+
+    // Commitment to the UTXO set, equal for nodes that agree on it
+    pub fn hash(&self) -> [u8; 32] {
+        let mut bytes = Vec::new();
+        for entry in &self.utxos {
+            bytes.extend(encode_entry(entry));
+        }
+        sha256(bytes)
+    }
+
+    pub fn info(&self) -> UtxoSetInfoJson {
+        UtxoSetInfoJson {
+            height: (self.headers.len() as u64).saturating_sub(1),
+            block_hash: to_hex(&self.base_hash()),
+            count: self.utxos.len(),
+            total_amount: self.utxos.iter().map(|(_, utxo)| utxo.tx_out.amount).sum(),
+            hash: to_hex(&self.hash()),
+        }
+    }
+}
+
+// Headers, UTXO entries and the commitment, which is checked on decoding
+impl Encodable for UtxoSnapshot {
+    fn encode(&self) -> Vec<u8> {
+        let mut bytes = self.headers.encode();
+        bytes.extend((self.utxos.len() as u64).encode());
+        for entry in &self.utxos {
+            bytes.extend(encode_entry(entry));
+        }
+        bytes.extend(self.hash().encode());
+        bytes
+    }
+}
+
+impl Decodable for UtxoSnapshot {
+    fn decode(bytes: &mut VecDeque<u8>) -> Result<Self, DecodeError> {
+        let mut headers = Vec::<BlockHeader>::decode(bytes)?;
+        for (height, header) in headers.iter_mut().enumerate() {
+            header.height = height as u64;
+        }
+        let len = u64::decode(bytes)?;
+        let mut utxos = Vec::new();
+        for _ in 0..len {
+            let outpoint = (TxID::decode(bytes)?, u32::decode(bytes)?);
+            let height = u64::decode(bytes)?;
+            let is_coinbase = u8::decode(bytes)? != 0;
+            let tx_out = TxOut::decode(bytes)?;
+            utxos.push((outpoint, Utxo::new(tx_out, height, is_coinbase)));
+        }
+        let snapshot = Self { headers, utxos };
+        if <[u8; 32]>::decode(bytes)? != snapshot.hash() {
+            return Err(DecodeError);
+        }
+        Ok(snapshot)
+    }
+}
+
+fn encode_entry(((txid, output_index), utxo): &((TxID, u32), Utxo)) -> Vec<u8> {
+    let mut bytes = txid.encode();
+    bytes.extend(output_index.encode());
+    bytes.extend(utxo.height.encode());
+    bytes.push(utxo.is_coinbase as u8);
+    bytes.extend(utxo.tx_out.encode());
+    bytes
+}
+
+// Reverts the outputs created and spent by `block`, the tip of the chain `utxos` belongs to.
+// Fails if the UTXO set or the undo data doesn't match the block.
+pub fn rollback(
+    utxos: &mut HashMap<(TxID, u32), Utxo>,
+    block: &Block,
+    undo: &BlockUndo,
+) -> Result<(), String> {
+    let inputs: usize = block
+        .transactions
+        .iter()
+        .filter(|tx| !tx.is_coinbase())
+        .map(|tx| tx.inputs.len())
+        .sum();
+    if undo.spent_utxos.len() != inputs {
+        return Err("Undo data does not match the block".to_owned());
+    }
+    let mut spent_utxos = undo.spent_utxos.iter().rev();
+    for tx in block.transactions.iter().rev() {
+        let txid = tx.txid();
+        for (i, tx_out) in tx.outputs.iter().enumerate() {
+            let utxo = Utxo::new(tx_out.clone(), block.header.height, tx.is_coinbase());
+            if utxos.remove(&(txid, i as u32)) != Some(utxo) {
+                return Err("Output of the block is missing from the UTXO set".to_owned());
+            }
+        }
+        if !tx.is_coinbase() {
+            for tx_in in tx.inputs.iter().rev() {
+                let utxo = spent_utxos.next().unwrap().clone();
+                if utxos
+                    .insert((tx_in.txid, tx_in.output_index), utxo)
+                    .is_some()
+                {
+                    return Err("Output spent by the block is in the UTXO set".to_owned());
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UtxoSetInfoJson {
+    pub height: u64,
+    pub block_hash: String,
+    pub count: usize,
+    pub total_amount: u64,
+    pub hash: String,
+}
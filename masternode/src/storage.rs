@@ -0,0 +1,415 @@
+use std::{
+    collections::HashMap,
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use serde::{Deserialize, Serialize};
+
+use bitaekcoin::{
+    block::{Block, BlockHeader},
+    encode::{decode_all, Encodable},
+    script::Script,
+    transaction::TxID,
+    utils::to_hex,
+};
+
+use crate::{keystore::Keystore, undo::BlockUndo, utxo::Utxo};
+
+// Entry of the block index, a later entry for the same block replaces the earlier one
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct IndexRecord {
+    pub header: BlockHeader,
+    pub sequence: u64,
+    pub invalid: bool,
+    #[serde(default = "have_data_default")]
+    pub have_data: bool,
+}
+
+fn have_data_default() -> bool {
+    true
+}
+
+// UTXO set as of the block `tip`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChainStateRecord {
+    pub tip: [u8; 32],
+    pub utxos: Vec<((TxID, u32), Utxo)>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WalletRecord {
+    pub index: usize,
+    // None for watch-only keys and scripts
+    #[serde(default)]
+    pub private_key: Option<Vec<u8>>,
+    // Derivation path from the wallet seed, None for keys that weren't derived
+    #[serde(default)]
+    pub path: Option<String>,
+    // Kept for watch-only keys and scripts
+    #[serde(default)]
+    pub public_key: Option<Vec<u8>>,
+    #[serde(default)]
+    pub script: Option<Script>,
+    // Private key of an encrypted wallet, see `Keystore`
+    #[serde(default)]
+    pub encrypted_key: Option<Vec<u8>>,
+    // Key of the account of a derived key, which derives addresses while the wallet is locked
+    #[serde(default)]
+    pub xpub: Option<String>,
+}
+
+// Plain wallet seed, or the keystore holding it once the wallet is encrypted
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum SeedRecord {
+    Plain(Vec<u8>),
+    Encrypted(Keystore),
+}
+
+pub trait Storage: Send + Sync {
+    fn write_block(&self, block: &Block) -> io::Result<()>;
+
+    fn read_block(&self, hash: &[u8; 32], height: u64) -> io::Result<Block>;
+
+    fn write_index(&self, record: &IndexRecord) -> io::Result<()>;
+
+    fn read_index(&self) -> io::Result<Vec<IndexRecord>>;
+
+    fn write_undo(&self, hash: &[u8; 32], undo: &BlockUndo) -> io::Result<()>;
+
+    fn read_undo(&self, hash: &[u8; 32]) -> io::Result<BlockUndo>;
+
+    // Deletes a block and its undo data
+    fn remove_block(&self, hash: &[u8; 32]) -> io::Result<()>;
+
+    fn write_chainstate(&self, chainstate: &ChainStateRecord) -> io::Result<()>;
+
+    fn read_chainstate(&self) -> io::Result<Option<ChainStateRecord>>;
+
+    fn write_wallet(&self, accounts: &[WalletRecord]) -> io::Result<()>;
+
+    fn read_wallet(&self) -> io::Result<Option<Vec<WalletRecord>>>;
+
+    fn write_seed(&self, seed: &SeedRecord) -> io::Result<()>;
+
+    fn read_seed(&self) -> io::Result<Option<SeedRecord>>;
+}
+
+// Stores each block and its undo data in a file of its own next to an append-only block
+// index. Other files are replaced atomically, so a crash leaves either the old or the new
+// version and at worst a torn last line of the index.
+pub struct FileStorage {
+    path: PathBuf,
+}
+
+impl FileStorage {
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        fs::create_dir_all(path.join("blocks"))?;
+        fs::create_dir_all(path.join("undo"))?;
+        let storage = Self { path };
+        storage.repair_index()?;
+        Ok(storage)
+    }
+
+    // Drops a record torn by a crash, records are complete once their newline is written
+    fn repair_index(&self) -> io::Result<()> {
+        let path = self.path.join("index.log");
+        let content = match fs::read(&path) {
+            Ok(content) => content,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(err) => return Err(err),
+        };
+        if content.last().is_none_or(|byte| *byte == b'\n') {
+            return Ok(());
+        }
+        let len = content
+            .iter()
+            .rposition(|byte| *byte == b'\n')
+            .map_or(0, |i| i + 1);
+        let file = OpenOptions::new().write(true).open(&path)?;
+        file.set_len(len as u64)?;
+        file.sync_all()
+    }
+
+    fn block_path(&self, hash: &[u8; 32]) -> PathBuf {
+        self.path
+            .join("blocks")
+            .join(format!("{}.dat", to_hex(hash)))
+    }
+
+    fn undo_path(&self, hash: &[u8; 32]) -> PathBuf {
+        self.path
+            .join("undo")
+            .join(format!("{}.json", to_hex(hash)))
+    }
+}
+
+impl Storage for FileStorage {
+    fn write_block(&self, block: &Block) -> io::Result<()> {
+        write_atomic(&self.block_path(&block.header.hash()), &block.encode())
+    }
+
+    fn read_block(&self, hash: &[u8; 32], height: u64) -> io::Result<Block> {
+        let bytes = fs::read(self.block_path(hash))?;
+        let mut block: Block = decode_all(bytes).map_err(|_| invalid_data("corrupted block"))?;
+        if block.header.hash() != *hash {
+            return Err(invalid_data("block does not match its hash"));
+        }
+        block.header.height = height;
+        Ok(block)
+    }
+
+    fn write_index(&self, record: &IndexRecord) -> io::Result<()> {
+        let mut line = serde_json::to_vec(record)?;
+        line.push(b'\n');
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.path.join("index.log"))?;
+        file.write_all(&line)?;
+        file.sync_data()
+    }
+
+    fn read_index(&self) -> io::Result<Vec<IndexRecord>> {
+        let content = match fs::read_to_string(self.path.join("index.log")) {
+            Ok(content) => content,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+            Err(err) => return Err(err),
+        };
+        let mut records = Vec::new();
+        for line in content.lines() {
+            records.push(serde_json::from_str(line)?);
+        }
+        Ok(records)
+    }
+
+    fn write_undo(&self, hash: &[u8; 32], undo: &BlockUndo) -> io::Result<()> {
+        write_atomic(&self.undo_path(hash), &serde_json::to_vec(undo)?)
+    }
+
+    fn read_undo(&self, hash: &[u8; 32]) -> io::Result<BlockUndo> {
+        Ok(serde_json::from_slice(&fs::read(self.undo_path(hash))?)?)
+    }
+
+    fn remove_block(&self, hash: &[u8; 32]) -> io::Result<()> {
+        for path in [self.block_path(hash), self.undo_path(hash)] {
+            match fs::remove_file(path) {
+                Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err),
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    fn write_chainstate(&self, chainstate: &ChainStateRecord) -> io::Result<()> {
+        let path = self.path.join("chainstate.json");
+        write_atomic(&path, &serde_json::to_vec(chainstate)?)
+    }
+
+    fn read_chainstate(&self) -> io::Result<Option<ChainStateRecord>> {
+        read_optional(&self.path.join("chainstate.json"))
+    }
+
+    fn write_wallet(&self, accounts: &[WalletRecord]) -> io::Result<()> {
+        write_atomic(
+            &self.path.join("wallet.json"),
+            &serde_json::to_vec(accounts)?,
+        )
+    }
+
+    fn read_wallet(&self) -> io::Result<Option<Vec<WalletRecord>>> {
+        read_optional(&self.path.join("wallet.json"))
+    }
+
+    fn write_seed(&self, seed: &SeedRecord) -> io::Result<()> {
+        write_atomic(&self.path.join("seed.json"), &serde_json::to_vec(seed)?)
+    }
+
+    fn read_seed(&self) -> io::Result<Option<SeedRecord>> {
+        read_optional(&self.path.join("seed.json"))
+    }
+}
+
+// Keeps everything in memory. Clones share their contents, so a node can be loaded again
+// from a clone of the storage it wrote to.
+#[derive(Clone, Default)]
+pub struct MemoryStorage {
+    inner: Arc<Mutex<MemoryStorageInner>>,
+}
+
+#[derive(Default)]
+struct MemoryStorageInner {
+    blocks: HashMap<[u8; 32], Block>,
+    index: Vec<IndexRecord>,
+    undo: HashMap<[u8; 32], BlockUndo>,
+    chainstate: Option<ChainStateRecord>,
+    wallet: Option<Vec<WalletRecord>>,
+    seed: Option<SeedRecord>,
+}
+
+impl Storage for MemoryStorage {
+    fn write_block(&self, block: &Block) -> io::Result<()> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.blocks.insert(block.header.hash(), block.clone());
+        Ok(())
+    }
+
+    fn read_block(&self, hash: &[u8; 32], height: u64) -> io::Result<Block> {
+        let inner = self.inner.lock().unwrap();
+        let mut block = inner.blocks.get(hash).cloned().ok_or_else(not_found)?;
+        block.header.height = height;
+        Ok(block)
+    }
+
+    fn write_index(&self, record: &IndexRecord) -> io::Result<()> {
+        self.inner.lock().unwrap().index.push(record.clone());
+        Ok(())
+    }
+
+    fn read_index(&self) -> io::Result<Vec<IndexRecord>> {
+        Ok(self.inner.lock().unwrap().index.clone())
+    }
+
+    fn write_undo(&self, hash: &[u8; 32], undo: &BlockUndo) -> io::Result<()> {
+        self.inner.lock().unwrap().undo.insert(*hash, undo.clone());
+        Ok(())
+    }
+
+    fn read_undo(&self, hash: &[u8; 32]) -> io::Result<BlockUndo> {
+        let inner = self.inner.lock().unwrap();
+        inner.undo.get(hash).cloned().ok_or_else(not_found)
+    }
+
+    fn remove_block(&self, hash: &[u8; 32]) -> io::Result<()> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.blocks.remove(hash);
+        inner.undo.remove(hash);
+        Ok(())
+    }
+
+    fn write_chainstate(&self, chainstate: &ChainStateRecord) -> io::Result<()> {
+        self.inner.lock().unwrap().chainstate = Some(chainstate.clone());
+        Ok(())
+    }
+
+    fn read_chainstate(&self) -> io::Result<Option<ChainStateRecord>> {
+        Ok(self.inner.lock().unwrap().chainstate.clone())
+    }
+
+    fn write_wallet(&self, accounts: &[WalletRecord]) -> io::Result<()> {
+        self.inner.lock().unwrap().wallet = Some(accounts.to_vec());
+        Ok(())
+    }
+
+    fn read_wallet(&self) -> io::Result<Option<Vec<WalletRecord>>> {
+        Ok(self.inner.lock().unwrap().wallet.clone())
+    }
+
+    fn write_seed(&self, seed: &SeedRecord) -> io::Result<()> {
+        self.inner.lock().unwrap().seed = Some(seed.clone());
+        Ok(())
+    }
+
+    fn read_seed(&self) -> io::Result<Option<SeedRecord>> {
+        Ok(self.inner.lock().unwrap().seed.clone())
+    }
+}
+
+// Writes to a temporary file first so the file is either fully replaced or left untouched
+fn write_atomic(path: &Path, bytes: &[u8]) -> io::Result<()> {
+    let tmp = path.with_extension("tmp");
+    let mut file = File::create(&tmp)?;
+    file.write_all(bytes)?;
+    file.sync_all()?;
+    fs::rename(&tmp, path)?;
+    if let Some(dir) = path.parent() {
+        File::open(dir)?.sync_all()?;
+    }
+    Ok(())
+}
+
+fn read_optional<T: for<'de> Deserialize<'de>>(path: &Path) -> io::Result<Option<T>> {
+    match fs::read(path) {
+        Ok(bytes) => Ok(Some(serde_json::from_slice(&bytes)?)),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err),
+    }
+}
+
+fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn not_found() -> io::Error {
+    io::Error::new(io::ErrorKind::NotFound, "not found")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header(nonce: u32) -> BlockHeader {
+        BlockHeader {
+            height: 0,
+            version: 1,
+            prev_block_hash: [0; 32],
+            merkle_root: [0; 32],
+            timestamp: 0,
+            bits: 0x207fffff,
+            nonce,
+        }
+    }
+
+    #[test]
+    fn test_torn_index() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = FileStorage::open(dir.path()).unwrap();
+        for nonce in 0..2 {
+            let record = IndexRecord {
+                header: header(nonce),
+                sequence: nonce as u64,
+                invalid: false,
+                have_data: true,
+            };
+            storage.write_index(&record).unwrap();
+        }
+        let mut file = OpenOptions::new()
+            .append(true)
+            .open(dir.path().join("index.log"))
+            .unwrap();
+        file.write_all(b"{\"header\":{").unwrap();
+
+        // Reopening drops the torn record so that later records can be appended
+        let storage = FileStorage::open(dir.path()).unwrap();
+        let record = IndexRecord {
+            header: header(2),
+            sequence: 2,
+            invalid: false,
+            have_data: true,
+        };
+        storage.write_index(&record).unwrap();
+        let records = storage.read_index().unwrap();
+        assert_eq!(records.len(), 3);
+        assert_eq!(records[2].header.nonce, 2);
+    }
+
+    #[test]
+    fn test_missing_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let storages: [Box<dyn Storage>; 2] = [
+            Box::new(FileStorage::open(dir.path()).unwrap()),
+            Box::new(MemoryStorage::default()),
+        ];
+        for storage in storages {
+            assert!(storage.read_index().unwrap().is_empty());
+            assert!(storage.read_chainstate().unwrap().is_none());
+            assert!(storage.read_wallet().unwrap().is_none());
+            assert!(storage.read_block(&[0; 32], 0).is_err());
+            assert!(storage.read_undo(&[0; 32]).is_err());
+        }
+    }
+}
@@ -0,0 +1,432 @@
+use bitaekcoin::{
+    encode::{Encodable, VarInt},
+    script::{Script, StandardScript},
+    transaction::{Transaction, TxID, TxIn, TxOut, Witness},
+};
+
+use crate::{
+    account::Account,
+    coin_selection::{input_vsize, BranchAndBound, Coin, CoinSelection, Knapsack},
+};
+
+// Change below this is left to the fee instead of creating an output hardly worth spending
+pub const DUST_LIMIT: u64 = 546;
+
+// Sequences of inputs, only non-final ones let the lock time apply
+pub const SEQUENCE_FINAL: u32 = 0xffffffff;
+pub const SEQUENCE_ENABLE_LOCKTIME: u32 = 0xfffffffe;
+
+// Builds a signed transaction paying recipients from the spendable outputs of an account. The
+// fee is the fee rate times the virtual size of the signed transaction.
+pub struct TxBuilder<'a> {
+    // Branch-and-Bound falling back to knapsack by default
+    coin_selection: Option<Box<dyn CoinSelection>>,
+    account: &'a Account,
+    recipients: Vec<(Script, u64)>,
+    // Satoshis per virtual byte
+    fee_rate: u64,
+    change_script: Option<Script>,
+    lock_time: u32,
+    sequence: Option<u32>,
+}
+
+impl<'a> TxBuilder<'a> {
+    pub fn new(account: &'a Account) -> Self {
+        Self {
+            coin_selection: None,
+            account,
+            recipients: vec![],
+            fee_rate: 0,
+            change_script: None,
+            lock_time: 0,
+            sequence: None,
+        }
+    }
+
+    pub fn add_recipient(mut self, script: Script, amount: u64) -> Self {
+        self.recipients.push((script, amount));
+        self
+    }
+
+    pub fn fee_rate(mut self, fee_rate: u64) -> Self {
+        self.fee_rate = fee_rate;
+        self
+    }
+
+    // The change address of the account by default
+    pub fn change_script(mut self, script: Script) -> Self {
+        self.change_script = Some(script);
+        self
+    }
+
+    // Inputs enable the lock time unless another sequence is set
+    pub fn lock_time(mut self, lock_time: u32) -> Self {
+        self.lock_time = lock_time;
+        self
+    }
+
+    pub fn sequence(mut self, sequence: u32) -> Self {
+        self.sequence = Some(sequence);
+        self
+    }
+
+    pub fn coin_selection(mut self, coin_selection: impl CoinSelection + 'static) -> Self {
+        self.coin_selection = Some(Box::new(coin_selection));
+        self
+    }
+
+    // For a transaction included at `height`. Outputs are selected by their values net of the
+    // fee of spending them, and selected again with a higher fee while the signed transaction
+    // turns out larger than estimated.
+    pub fn build(&self, height: u64) -> Result<Transaction, String> {
+        if self.account.is_watch_only() {
+            return Err("Watch-only account can't transfer".to_owned());
+        }
+        if self.recipients.is_empty() {
+            return Err("Transaction without recipients".to_owned());
+        }
+        let amount = self
+            .recipients
+            .iter()
+            .try_fold(0u64, |sum, (_, amount)| sum.checked_add(*amount))
+            .ok_or("Transfer amount is too large")?;
+        if amount > self.account.spendable_balance(height) {
+            return Err("Transfer amount is larger than spendable balance".to_owned());
+        }
+        let coins: Vec<_> = self
+            .account
+            .spendable_utxos(height)
+            .filter_map(|(outpoint, utxo)| Coin::new(*outpoint, utxo.clone(), self.fee_rate))
+            .collect();
+
+        // Fees of the transaction without inputs, of a change output and of spending it later
+        let fee = |vsize: u64| vsize.checked_mul(self.fee_rate).ok_or("Fee rate too high");
+        let base_fee = fee(self.unsigned(vec![], 0).vsize() as u64)?;
+        let change_script = self.change_destination();
+        let change_fee = fee(tx_out(change_script.clone(), 0).encode().len() as u64)?;
+        let cost_of_change = change_fee
+            .checked_add(fee(input_vsize(&change_script).unwrap_or(0))?)
+            .ok_or("Fee rate too high")?;
+
+        let mut extra_fee = 0u64;
+        loop {
+            let target = amount
+                .checked_add(base_fee)
+                .and_then(|target| target.checked_add(extra_fee))
+                .ok_or("Insufficient funds")?;
+            let selected = self.select(&coins, target, cost_of_change)?;
+            let inputs: Vec<_> = selected.into_iter().map(|i| &coins[i]).collect();
+            let sum: u64 = inputs.iter().map(|coin| coin.utxo.tx_out.amount).sum();
+            let input_fee = inputs.iter().try_fold(0u64, |sum, coin| {
+                fee(coin.input_vsize)?
+                    .checked_add(sum)
+                    .ok_or("Fee rate too high")
+            })?;
+            // Change that isn't worth making is left to the fee
+            let excess = target
+                .checked_add(input_fee)
+                .and_then(|spent| sum.checked_sub(spent))
+                .ok_or("Insufficient funds")?;
+            let change = match excess > cost_of_change {
+                true => excess - change_fee,
+                false => 0,
+            };
+
+            let tx = self.sign(&inputs, change)?;
+            let paid = sum - tx.outputs.iter().map(|tx_out| tx_out.amount).sum::<u64>();
+            let required = fee(tx.vsize() as u64)?;
+            if paid >= required {
+                return Ok(tx);
+            }
+            extra_fee += required - paid;
+        }
+    }
+
+    fn select(
+        &self,
+        coins: &[Coin],
+        target: u64,
+        cost_of_change: u64,
+    ) -> Result<Vec<usize>, String> {
+        match &self.coin_selection {
+            Some(coin_selection) => coin_selection.select(coins, target, cost_of_change),
+            None => BranchAndBound::default()
+                .select(coins, target, cost_of_change)
+                .or_else(|_| Knapsack.select(coins, target, cost_of_change)),
+        }
+    }
+
+    fn change_destination(&self) -> Script {
+        match &self.change_script {
+            Some(script) => script.clone(),
+            None => self.account.change_key().scripts[0].clone(),
+        }
+    }
+
+    fn sign(&self, coins: &[&Coin], change: u64) -> Result<Transaction, String> {
+        let mut tx = self.unsigned(coins.iter().map(|coin| coin.outpoint).collect(), change);
+        let is_segwit = coins.iter().any(|coin| {
+            matches!(
+                coin.utxo.tx_out.script_pub_key.to_standard(),
+                Some(StandardScript::P2WPKH(_) | StandardScript::P2SH(_))
+            )
+        });
+        if is_segwit {
+            tx.flag = Some(1);
+            tx.witnesses = vec![Witness(vec![]); tx.inputs.len()];
+        }
+
+        for (i, coin) in coins.iter().enumerate() {
+            let tx_out = &coin.utxo.tx_out;
+            let script = &tx_out.script_pub_key;
+            let key = self
+                .account
+                .keys
+                .iter()
+                .find(|key| key.scripts.contains(script))
+                .ok_or("Output doesn't belong to the account")?;
+            key.sign_input(&mut tx, i, script, tx_out.amount)?;
+        }
+        Ok(tx)
+    }
+
+    fn unsigned(&self, outpoints: Vec<(TxID, u32)>, change: u64) -> Transaction {
+        let sequence = self.sequence.unwrap_or(match self.lock_time {
+            0 => SEQUENCE_FINAL,
+            _ => SEQUENCE_ENABLE_LOCKTIME,
+        });
+        let inputs = outpoints
+            .into_iter()
+            .map(|(txid, output_index)| TxIn {
+                txid,
+                output_index,
+                script_size: VarInt(0),
+                script_sig: Script(Vec::new()),
+                sequence,
+            })
+            .collect();
+        let mut outputs: Vec<_> = self
+            .recipients
+            .iter()
+            .map(|(script, amount)| tx_out(script.clone(), *amount))
+            .collect();
+        if change >= DUST_LIMIT {
+            outputs.push(tx_out(self.change_destination(), change));
+        }
+        Transaction {
+            version: 1,
+            flag: None,
+            inputs,
+            outputs,
+            witnesses: Vec::new(),
+            lock_time: self.lock_time,
+        }
+    }
+}
+
+fn tx_out(script: Script, amount: u64) -> TxOut {
+    TxOut {
+        amount,
+        script_size: VarInt(script.encode().len() as u64),
+        script_pub_key: script,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use crate::{
+        coin_selection::{LargestFirst, Manual},
+        utxo::Utxo,
+        PRIVATE_KEY,
+    };
+
+    use super::*;
+
+    fn funded_account(amounts: &[u64]) -> Account {
+        let mut account = Account::new(0, PRIVATE_KEY.to_vec()).unwrap();
+        let script = account.keys[0].scripts[0].clone();
+        for (i, amount) in amounts.iter().enumerate() {
+            let utxo = Utxo::new(tx_out(script.clone(), *amount), 0, false);
+            account.utxos.insert(([i as u8; 32], 0), utxo);
+        }
+        account
+    }
+
+    fn spent_outputs(account: &Account) -> HashMap<(TxID, u32), TxOut> {
+        account
+            .utxos
+            .iter()
+            .map(|(outpoint, utxo)| (*outpoint, utxo.tx_out.clone()))
+            .collect()
+    }
+
+    #[test]
+    fn test_recipients_and_change() {
+        let account = funded_account(&[10000, 10000]);
+        let first = Account::random(1).receive_key().scripts[0].clone();
+        let second = Account::random(2).receive_key().scripts[2].clone();
+        let tx = TxBuilder::new(&account)
+            .add_recipient(first.clone(), 3000)
+            .add_recipient(second.clone(), 4000)
+            .build(1)
+            .unwrap();
+        assert_eq!(tx.inputs.len(), 1);
+        assert_eq!(tx.outputs.len(), 3);
+        assert_eq!(tx.outputs[0].script_pub_key, first);
+        assert_eq!(tx.outputs[1].script_pub_key, second);
+        assert_eq!(tx.outputs[2].amount, 3000);
+        assert_eq!(
+            tx.outputs[2].script_pub_key,
+            account.change_key().scripts[0]
+        );
+        assert_eq!(tx.fee(&spent_outputs(&account)), Some(0));
+
+        let change = Account::random(3).receive_key().scripts[1].clone();
+        let tx = TxBuilder::new(&account)
+            .add_recipient(first, 13000)
+            .change_script(change.clone())
+            .build(1)
+            .unwrap();
+        assert_eq!(tx.inputs.len(), 2);
+        assert_eq!(tx.outputs[1].script_pub_key, change);
+        assert_eq!(tx.outputs[1].amount, 7000);
+    }
+
+    #[test]
+    fn test_fee_rate() {
+        let account = funded_account(&[10000, 10000]);
+        let script = Account::random(1).receive_key().scripts[0].clone();
+        let tx = TxBuilder::new(&account)
+            .add_recipient(script.clone(), 5000)
+            .fee_rate(10)
+            .build(1)
+            .unwrap();
+        let fee = tx.fee(&spent_outputs(&account)).unwrap();
+        assert!(fee >= tx.vsize() as u64 * 10);
+        assert_eq!(tx.outputs[1].amount, 5000 - fee);
+
+        // The fee makes the transaction spend both outputs
+        let tx = TxBuilder::new(&account)
+            .add_recipient(script.clone(), 10000)
+            .fee_rate(10)
+            .build(1)
+            .unwrap();
+        assert_eq!(tx.inputs.len(), 2);
+        assert!(tx.fee(&spent_outputs(&account)).unwrap() >= tx.vsize() as u64 * 10);
+
+        // Change below the dust limit is left to the fee
+        let tx = TxBuilder::new(&account)
+            .add_recipient(script.clone(), 9500)
+            .fee_rate(1)
+            .build(1)
+            .unwrap();
+        assert_eq!(tx.outputs.len(), 1);
+        assert_eq!(tx.fee(&spent_outputs(&account)), Some(500));
+
+        assert!(TxBuilder::new(&account)
+            .add_recipient(script, 20000)
+            .fee_rate(1)
+            .build(1)
+            .is_err());
+    }
+
+    #[test]
+    fn test_coin_selection() {
+        let account = funded_account(&[10000, 20000, 30000]);
+        let script = Account::random(1).receive_key().scripts[0].clone();
+        // Branch-and-Bound spends the output worth the amount and the fee, leaving an excess
+        // smaller than the cost of change to the fee
+        let tx = TxBuilder::new(&account)
+            .add_recipient(script.clone(), 29800)
+            .fee_rate(1)
+            .build(1)
+            .unwrap();
+        assert_eq!(tx.inputs[0].txid, [2; 32]);
+        assert_eq!(tx.outputs.len(), 1);
+        let fee = tx.fee(&spent_outputs(&account)).unwrap();
+        assert!(fee >= tx.vsize() as u64);
+        assert_eq!(fee, 200);
+
+        let tx = TxBuilder::new(&account)
+            .add_recipient(script.clone(), 5000)
+            .coin_selection(LargestFirst)
+            .build(1)
+            .unwrap();
+        assert_eq!(tx.inputs[0].txid, [2; 32]);
+        assert_eq!(tx.outputs[1].amount, 25000);
+
+        let tx = TxBuilder::new(&account)
+            .add_recipient(script.clone(), 25000)
+            .coin_selection(Manual(vec![([0; 32], 0), ([1; 32], 0)]))
+            .build(1)
+            .unwrap();
+        let outpoints: Vec<_> = tx.inputs.iter().map(|tx_in| tx_in.txid).collect();
+        assert_eq!(outpoints, vec![[0; 32], [1; 32]]);
+        assert_eq!(tx.outputs[1].amount, 5000);
+        assert!(TxBuilder::new(&account)
+            .add_recipient(script, 25000)
+            .coin_selection(Manual(vec![([0; 32], 0)]))
+            .build(1)
+            .is_err());
+    }
+
+    #[test]
+    fn test_fee_overflow() {
+        let account = funded_account(&[10000, 100]);
+        let script = Account::random(1).receive_key().scripts[0].clone();
+        // The second output is worth less than the fee of spending it
+        assert_eq!(
+            TxBuilder::new(&account)
+                .add_recipient(script.clone(), 5000)
+                .fee_rate(10)
+                .coin_selection(Manual(vec![([0; 32], 0), ([1; 32], 0)]))
+                .build(1)
+                .err(),
+            Some("Selected output can't pay the fee of spending it".to_owned())
+        );
+        assert_eq!(
+            TxBuilder::new(&account)
+                .add_recipient(script, 5000)
+                .fee_rate(u64::MAX)
+                .build(1)
+                .err(),
+            Some("Fee rate too high".to_owned())
+        );
+    }
+
+    #[test]
+    fn test_lock_time() {
+        let account = funded_account(&[10000]);
+        let script = Account::random(1).receive_key().scripts[0].clone();
+        let builder = TxBuilder::new(&account).add_recipient(script, 1000);
+        let tx = builder.build(1).unwrap();
+        assert_eq!(tx.lock_time, 0);
+        assert_eq!(tx.inputs[0].sequence, SEQUENCE_FINAL);
+
+        let builder = builder.lock_time(500);
+        let tx = builder.build(1).unwrap();
+        assert_eq!(tx.lock_time, 500);
+        assert_eq!(tx.inputs[0].sequence, SEQUENCE_ENABLE_LOCKTIME);
+
+        let tx = builder.sequence(0).build(1).unwrap();
+        assert_eq!(tx.inputs[0].sequence, 0);
+    }
+
+    #[test]
+    fn test_invalid_builds() {
+        let account = funded_account(&[10000]);
+        let script = Account::random(1).receive_key().scripts[0].clone();
+        assert!(TxBuilder::new(&account).build(1).is_err());
+        assert!(TxBuilder::new(&account)
+            .add_recipient(script.clone(), 10001)
+            .build(1)
+            .is_err());
+        assert!(TxBuilder::new(&account)
+            .add_recipient(script.clone(), u64::MAX)
+            .add_recipient(script, 1)
+            .build(1)
+            .is_err());
+    }
+}
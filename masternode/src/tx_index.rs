@@ -0,0 +1,77 @@
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+
+use bitaekcoin::{
+    block::Block,
+    transaction::{Transaction, TxID},
+    utils::to_hex,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TxLocation {
+    pub block_hash: [u8; 32],
+    pub height: u64,
+    // Position of the transaction in the block
+    pub position: u32,
+}
+
+// Locations of the transactions in the active chain
+#[derive(Debug, Clone, Default)]
+pub struct TxIndex {
+    pub locations: HashMap<TxID, TxLocation>,
+    pub wtxids: HashMap<TxID, TxID>,
+}
+
+impl TxIndex {
+    pub fn connect_block(&mut self, block: &Block) {
+        let block_hash = block.header.hash();
+        for (position, tx) in block.transactions.iter().enumerate() {
+            let txid = tx.txid();
+            let location = TxLocation {
+                block_hash,
+                height: block.header.height,
+                position: position as u32,
+            };
+            self.locations.insert(txid, location);
+            self.wtxids.insert(tx.wtxid(), txid);
+        }
+    }
+
+    pub fn disconnect_block(&mut self, block: &Block) {
+        for tx in &block.transactions {
+            self.locations.remove(&tx.txid());
+            self.wtxids.remove(&tx.wtxid());
+        }
+    }
+
+    // Looks up a transaction by its txid or wtxid
+    pub fn get(&self, id: &TxID) -> Option<TxLocation> {
+        let txid = self.wtxids.get(id).unwrap_or(id);
+        self.locations.get(txid).copied()
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TransactionJson {
+    pub txid: String,
+    pub transaction: Transaction,
+    pub block_hash: String,
+    pub height: u64,
+    pub position: u32,
+    pub confirmations: u64,
+}
+
+impl TransactionJson {
+    // Confirmations are counted as seen by a transaction included at `height`
+    pub fn new(transaction: Transaction, location: TxLocation, height: u64) -> Self {
+        Self {
+            txid: to_hex(&transaction.txid()),
+            transaction,
+            block_hash: to_hex(&location.block_hash),
+            height: location.height,
+            position: location.position,
+            confirmations: height - location.height,
+        }
+    }
+}
@@ -0,0 +1,9 @@
+use serde::{Deserialize, Serialize};
+
+use crate::utxo::Utxo;
+
+// Outputs spent by a block, in the order of its non-coinbase inputs
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BlockUndo {
+    pub spent_utxos: Vec<Utxo>,
+}
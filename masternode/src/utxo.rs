@@ -0,0 +1,48 @@
+use serde::{Deserialize, Serialize};
+
+use bitaekcoin::{block::COINBASE_MATURITY, transaction::TxOut};
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Utxo {
+    pub tx_out: TxOut,
+    pub height: u64,
+    pub is_coinbase: bool,
+}
+
+impl Utxo {
+    pub fn new(tx_out: TxOut, height: u64, is_coinbase: bool) -> Self {
+        Self {
+            tx_out,
+            height,
+            is_coinbase,
+        }
+    }
+
+    // Whether the output can be spent by a transaction included at `height`
+    pub fn is_mature(&self, height: u64) -> bool {
+        !self.is_coinbase || height >= self.height + COINBASE_MATURITY
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bitaekcoin::{encode::VarInt, script::Script};
+
+    use super::*;
+
+    #[test]
+    fn test_maturity() {
+        let tx_out = TxOut {
+            amount: 1,
+            script_size: VarInt(0),
+            script_pub_key: Script(vec![]),
+        };
+        let coinbase = Utxo::new(tx_out.clone(), 10, true);
+        assert!(!coinbase.is_mature(11));
+        assert!(!coinbase.is_mature(10 + COINBASE_MATURITY - 1));
+        assert!(coinbase.is_mature(10 + COINBASE_MATURITY));
+
+        let regular = Utxo::new(tx_out, 10, false);
+        assert!(regular.is_mature(11));
+    }
+}